@@ -0,0 +1,90 @@
+//! A path tracer that renders worlds described in JSON.
+//!
+//! A [`World`] is the scene, camera and [`Settings`] together, built from a world file like
+//! the ones under `worlds/` or from the same JSON in memory. [`render`] turns it into a
+//! float [`Image`], while [`generate`] is what the `rustytracer` binary runs and writes
+//! files instead.
+//!
+//! ```no_run
+//! use rustytracer::{render, Settings, World};
+//!
+//! let mut world = World::from_json(r#"{
+//!     "camera": { "lookfrom": [0.0, 1.0, 7.0], "lookat": [0.0, 0.0, 0.0], "fov": 60.0 },
+//!     "planes": [
+//!         { "origin": [0.0, -1.0, 0.0], "normal": [0.0, 1.0, 0.0], "color": [0.8, 0.8, 0.8], "mat": "flat" }
+//!     ],
+//!     "spheres": [
+//!         { "center": [0.0, 0.0, 0.0], "radius": 1.0, "color": [1.0, 1.0, 1.0], "mat": "glass" },
+//!         { "center": [0.0, 5.0, 0.0], "radius": 2.0, "color": [4.0, 4.0, 4.0], "mat": "diffuse_light" }
+//!     ]
+//! }"#, 320, 240).unwrap();
+//! world.set_camera([2.0, 1.0, 6.0], [0.0, 0.0, 0.0], 50.0, [0.0, 0.0]);
+//! let mut settings = Settings::default();
+//! settings.samples = 64;
+//! settings.threads = 4;
+//! world.set_settings(settings);
+//!
+//! let image = render(&world).unwrap();
+//! let [r, g, b] = image.pixel(160, 120);
+//! println!("{} {} {}", r, g, b);
+//! ```
+//!
+//! The same scene can be put together in code instead, starting from an empty world:
+//!
+//! ```no_run
+//! use rustytracer::World;
+//!
+//! let mut world = World::empty(320, 240);
+//! world.add_plane([0.0, -1.0, 0.0], [0.0, 1.0, 0.0], [0.8, 0.8, 0.8], "flat");
+//! world.add_sphere([0.0, 0.0, 0.0], 1.0, [1.0, 1.0, 1.0], "glass");
+//! world.add_sphere([0.0, 5.0, 0.0], 2.0, [4.0, 4.0, 4.0], "diffuse_light");
+//! world.set_camera([2.0, 1.0, 6.0], [0.0, 0.0, 0.0], 50.0, [0.0, 0.0]);
+//! ```
+//!
+//! The settings structs are `#[non_exhaustive]` so new settings can be added without
+//! breaking anyone: start from `Settings::default()` and change the fields you need.
+
+mod tracer;
+
+/// A float RGB image, `width*height` pixels row by row from the top left. Values are
+/// linear and aren't clamped, so anything brighter than white is kept.
+pub use crate::tracer::Image;
+
+/// Renders a world and hands back the image, denoised if its settings say so.
+///
+/// Nothing is written unless the settings ask for a progress stream or statistics, and
+/// nothing is printed apart from errors in the world or settings.
+pub use crate::tracer::render;
+
+/// Renders a world like [`render`], stopping early once `cancel` is stopped from another
+/// thread. Every pixel of the image then averages the samples it got by that point.
+pub use crate::tracer::render_cancellable;
+
+/// Renders the world file `world_file` to `path` the way the binary does: writing
+/// snapshots, checkpoints and AOVs as the settings ask, carrying on from the checkpoint
+/// at `resume` if there is one, and stopping cleanly once `cancel` is stopped.
+pub use crate::tracer::generate;
+
+/// Stops the render it was handed to, and only that one. Clones share the same flag, so
+/// one can be kept to call [`Cancel::stop`] on while the other goes to
+/// [`render_cancellable`].
+pub use crate::tracer::Cancel;
+
+/// The scene, camera and render settings.
+///
+/// [`World::new`] reads a world file and [`World::from_json`] takes the same JSON as
+/// text, both for an image of the given size, while [`World::empty`] starts with nothing.
+/// Spheres, planes, boxes, rectangles and point lights can be added to any of them with
+/// the `add_` methods. [`World::set_camera`] and [`World::set_settings`] change the view
+/// and how it's rendered.
+pub use crate::tracer::world::World;
+
+/// How a world gets rendered. Every field has a default, so a world file only needs what it
+/// changes, and code can change fields of `Settings::default()`.
+pub use crate::tracer::world::Settings;
+
+/// Parts of [`Settings`], each with its own defaults.
+pub use crate::tracer::world::{
+    AdaptiveSettings, AovSettings, CheckpointSettings, DenoiseSettings, FilterSettings, MltSettings,
+    ProgressSettings, ProgressiveSettings, WhittedSettings
+};
@@ -1,6 +1,42 @@
-mod tracer;
-use crate::tracer::*;
+use rustytracer::*;
+use std::path::Path;
 
+//rustytracer [world] [--resume [checkpoint]]
+//The world defaults to worlds/closed_room.json. Resuming without a checkpoint uses the one
+//saved next to the image.
 fn main() {
-    generate("output/image.png", 200,100).expect("Error, failure to write to file.");
+    let path = "output/image.png";
+    let mut world = None;
+    let default_checkpoint = Path::new(path).with_extension("checkpoint").to_string_lossy().into_owned();
+    let mut resume = None;
+    let mut args = std::env::args().skip(1).peekable();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--resume" => {
+                resume = match args.peek() {
+                    Some(next) if !next.starts_with("--") && !next.ends_with(".json") => args.next(),
+                    _ => Some(default_checkpoint.clone())
+                };
+            },
+            _ if !arg.starts_with("--") && world.is_none() => world = Some(arg),
+            _ => println!("Error, unknown option '{}'. Ignoring it", arg)
+        }
+    }
+    let world = world.unwrap_or_else(|| "worlds/closed_room.json".to_string());
+
+    //The first Ctrl-C stops the render cleanly, another one quits on the spot. It's armed
+    //before the world loads so a Ctrl-C then isn't lost.
+    let cancel = Cancel::new();
+    let stopper = cancel.clone();
+    let handler = ctrlc::set_handler(move || {
+        if stopper.stopped() {
+            std::process::exit(130);
+        }
+        stopper.stop();
+        println!("Stopping, writing out what's done so far. Press Ctrl-C again to quit now.");
+    });
+    if let Err(e) = handler {
+        println!("Error, couldn't catch Ctrl-C: {}", e);
+    }
+    generate(&world, path, 200, 100, resume.as_deref(), &cancel).expect("Error, failure to write to file.");
 }
@@ -0,0 +1,528 @@
+use super::camera::*;
+use super::checkpoint::{Reader, Writer};
+use super::debug::id_color;
+use super::film;
+use super::film::{Film, Filter};
+use super::random;
+use super::ray::*;
+use super::scene::*;
+use super::world::{FilterSettings, Settings};
+use super::Tile;
+use cgmath::*;
+use std::path::Path;
+use std::sync::Arc;
+
+//Extra image written alongside the beauty one.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Pass {
+    Albedo,
+    Normal,
+    Depth,
+    Position,
+    ObjectId,
+    MaterialId,
+    DirectDiffuse,
+    IndirectDiffuse,
+    Specular,
+    Emission,
+    Samples,
+}
+
+const PASSES: [(&str, Pass); 11] = [
+    ("albedo", Pass::Albedo),
+    ("normal", Pass::Normal),
+    ("depth", Pass::Depth),
+    ("position", Pass::Position),
+    ("object_id", Pass::ObjectId),
+    ("material_id", Pass::MaterialId),
+    ("direct_diffuse", Pass::DirectDiffuse),
+    ("indirect_diffuse", Pass::IndirectDiffuse),
+    ("specular", Pass::Specular),
+    ("emission", Pass::Emission),
+    ("samples", Pass::Samples),
+];
+
+impl Pass {
+    pub fn from_name(name: &str) -> Option<Pass> {
+        PASSES.iter().find(|(n, _)| *n == name).map(|(_, pass)| *pass)
+    }
+
+    fn name(&self) -> &'static str {
+        PASSES.iter().find(|(_, pass)| pass == self).map(|(n, _)| *n).unwrap_or("")
+    }
+
+    //Channels the pass gets in an EXR. Passes holding a single number only get one.
+    fn channels(&self) -> &'static [&'static str] {
+        match self {
+            Pass::Normal | Pass::Position => &["X", "Y", "Z"],
+            Pass::Depth => &["Z"],
+            Pass::ObjectId | Pass::MaterialId | Pass::Samples => &["Y"],
+            _ => &["R", "G", "B"]
+        }
+    }
+
+    pub fn is_lighting(&self) -> bool {
+        matches!(self, Pass::DirectDiffuse | Pass::IndirectDiffuse | Pass::Specular | Pass::Emission)
+    }
+
+    pub fn is_surface(&self) -> bool {
+        matches!(self, Pass::Albedo | Pass::Normal | Pass::Depth | Pass::Position | Pass::ObjectId | Pass::MaterialId)
+    }
+}
+
+//Passes the settings ask for, leaving out names that aren't passes.
+pub fn passes(names: &[String]) -> Vec<Pass> {
+    names.iter().filter_map(|name| match Pass::from_name(name) {
+        Some(pass) => Some(pass),
+        None => {
+            println!("Error, pass '{}' not found. Skipping it", name);
+            None
+        }
+    }).collect()
+}
+
+//One sample's color split by how its light got to the camera. Emission is lights seen
+//straight on, direct diffuse is light off one diffuse bounce, indirect diffuse off more
+//than one and specular anything first seen in a mirror or through glass.
+#[derive(Clone, Copy)]
+pub struct Lighting {
+    pub emission: Vector3<f64>,
+    pub direct_diffuse: Vector3<f64>,
+    pub indirect_diffuse: Vector3<f64>,
+    pub specular: Vector3<f64>,
+}
+
+impl Lighting {
+    pub fn zero() -> Lighting {
+        Lighting { emission: Vector3::zero(), direct_diffuse: Vector3::zero(), indirect_diffuse: Vector3::zero(), specular: Vector3::zero() }
+    }
+}
+
+//What the camera ray hits first. Ids start from one, zero is a miss.
+struct Surface {
+    albedo: Vector3<f64>,
+    normal: Vector3<f64>,
+    depth: f64,
+    position: Vector3<f64>,
+    object: usize,
+    material: usize,
+}
+
+//A film per pass. Lighting passes go through the image's filter so they add up to it, the
+//rest are plain averages over each pixel.
+pub struct Aovs {
+    width: u32,
+    height: u32,
+    films: Vec<(Pass, Film)>,
+}
+
+impl Aovs {
+    pub fn new(passes: &[Pass], width: u32, height: u32, filter: &Arc<dyn Filter>) -> Aovs {
+        let films = passes.iter().map(|&pass| (pass, Film::new(width, height, pass_filter(pass, filter)))).collect();
+        Aovs { width, height, films }
+    }
+
+    pub fn for_tile(passes: &[Pass], tile: &Tile, width: u32, height: u32, filter: &Arc<dyn Filter>) -> Aovs {
+        let films = passes.iter().map(|&pass| (pass, Film::for_tile(tile, width, height, pass_filter(pass, filter)))).collect();
+        Aovs { width, height, films }
+    }
+
+    //Adds one sample taken at (x, y) in pixels. Without lighting the lighting passes get black.
+    pub fn add_sample(&mut self, x: f64, y: f64, lighting: Option<&Lighting>) {
+        let lighting = lighting.copied().unwrap_or_else(Lighting::zero);
+        for (pass, film) in self.films.iter_mut() {
+            match pass {
+                Pass::Emission => film.add_sample(x, y, lighting.emission),
+                Pass::DirectDiffuse => film.add_sample(x, y, lighting.direct_diffuse),
+                Pass::IndirectDiffuse => film.add_sample(x, y, lighting.indirect_diffuse),
+                Pass::Specular => film.add_sample(x, y, lighting.specular),
+                //The box filter's weights count the samples.
+                Pass::Samples => film.add_sample(x, y, Vector3::zero()),
+                _ => {}
+            }
+        }
+    }
+
+    fn add_surface(&mut self, x: f64, y: f64, surface: &Surface) {
+        for (pass, film) in self.films.iter_mut() {
+            match pass {
+                Pass::Albedo => film.add_sample(x, y, surface.albedo),
+                Pass::Normal => film.add_sample(x, y, surface.normal),
+                Pass::Depth => film.add_sample(x, y, Vector3::new(surface.depth, surface.depth, surface.depth)),
+                Pass::Position => film.add_sample(x, y, surface.position),
+                _ => {}
+            }
+        }
+    }
+
+    //Ids would blend into made up ones if averaged, so each pixel takes them from its center.
+    fn set_ids(&mut self, i: u32, j: u32, surface: &Surface) {
+        let (x, y) = (i as f64 + 0.5, j as f64 + 0.5);
+        for (pass, film) in self.films.iter_mut() {
+            match pass {
+                Pass::ObjectId => film.add_sample(x, y, Vector3::new(1.0, 1.0, 1.0)*(surface.object as f64)),
+                Pass::MaterialId => film.add_sample(x, y, Vector3::new(1.0, 1.0, 1.0)*(surface.material as f64)),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &Aovs) {
+        for ((_, film), (_, other)) in self.films.iter_mut().zip(other.films.iter()) {
+            film.merge(other);
+        }
+    }
+
+    pub fn write(&self, out: &mut Writer) {
+        for (_, film) in &self.films {
+            film.write(out);
+        }
+    }
+
+    pub fn read(&mut self, input: &mut Reader) -> Option<()> {
+        for (_, film) in self.films.iter_mut() {
+            film.read(input)?;
+        }
+        Some(())
+    }
+
+    //Every pass's pixels, row by row, on aovs covering the whole image.
+    fn layers(&self) -> Vec<(Pass, Vec<Vector3<f64>>)> {
+        self.films.iter().map(|(pass, film)| {
+            let pixels = match pass {
+                Pass::Samples => {
+                    let mut counts = vec![];
+                    for j in 0..self.height {
+                        for i in 0..self.width {
+                            let n = film.weight(i, j);
+                            counts.push(Vector3::new(n, n, n));
+                        }
+                    }
+                    counts
+                },
+                Pass::Normal => film.pixels().iter()
+                    .map(|n| if n.magnitude2() > 0.0 { n.normalize() } else { *n })
+                    .collect(),
+                _ => film.pixels()
+            };
+            (*pass, pixels)
+        }).collect()
+    }
+}
+
+fn pass_filter(pass: Pass, filter: &Arc<dyn Filter>) -> Arc<dyn Filter> {
+    if pass.is_lighting() { filter.clone() } else { film::by_name(&FilterSettings::default()) }
+}
+
+//Finds what camera rays hit first for the surface passes. Media are looked through.
+pub struct SurfaceTracer {
+    scene: Arc<Scene>,
+    surfaces: Vec<bool>,
+    materials: Vec<String>,
+}
+
+impl SurfaceTracer {
+    pub fn new(scene: Arc<Scene>) -> SurfaceTracer {
+        let materials = scene.get_materials();
+        let surfaces = scene.get_hitables().iter()
+            .map(|h| !materials.get_material_by_key(h.get_material()).is_volume())
+            .collect();
+        let materials = materials.names();
+        SurfaceTracer { scene, surfaces, materials }
+    }
+
+    fn trace(&self, ray: &Ray) -> Surface {
+        let (t, hitable) = match self.scene.get_closest_intersection_among(ray, f64::MAX, &self.surfaces) {
+            Some(hit) => hit,
+            None => return Surface { albedo: Vector3::zero(), normal: Vector3::zero(), depth: 0.0, position: Vector3::zero(), object: 0, material: 0 }
+        };
+        let p = ray.point_at_parameter(t);
+        let n = hitable.get_norm_at_p(ray, &p);
+        let n = if n.dot(*ray.direction()) > 0.0 { -n } else { n };
+        let key = hitable.get_material();
+        let emitted = self.scene.get_materials().get_material_by_key(key.clone()).emitted();
+        let albedo = if emitted != Vector3::zero() { emitted.map(|c| c.min(1.0)) } else { *hitable.get_color() };
+        Surface {
+            albedo,
+            normal: n,
+            depth: t*ray.direction().magnitude(),
+            position: p,
+            object: self.scene.index_of(hitable) + 1,
+            material: self.materials.iter().position(|m| *m == key).map(|m| m + 1).unwrap_or(0),
+        }
+    }
+
+    //Adds samples jittered looks at every pixel of the tile, and one through its center
+    //for the ids. The numbers come from the seed and the pixel, like the denoiser's.
+    pub fn gather(&self, tile: &Tile, aovs: &mut Aovs, cam: &Camera, samples: u32, seed: u64) {
+        let (width, height) = (aovs.width as f64, aovs.height as f64);
+        for i in tile.x0..tile.x1 {
+            for j in tile.y0..tile.y1 {
+                let stream = random::Seeded::new(random::hash(&[seed, i as u64, j as u64, 1]));
+                random::with_stream(Box::new(stream), || {
+                    for _ in 0..samples {
+                        let x = (i as f64) + random::gen();
+                        let y = (j as f64) + random::gen();
+                        let surface = self.trace(&cam.get_ray(x/width, y/height));
+                        aovs.add_surface(x, y, &surface);
+                    }
+                    let center = self.trace(&cam.get_ray((i as f64 + 0.5)/width, (j as f64 + 0.5)/height));
+                    aovs.set_ids(i, j, &center);
+                });
+            }
+        }
+    }
+}
+
+//Writes the passes out next to the image at path, either as one EXR that also holds the
+//image or as a PNG per pass.
+pub fn save(path: &str, image: &[Vector3<f64>], aovs: &Aovs, settings: &Settings) -> std::io::Result<()> {
+    let path = Path::new(path);
+    let layers = aovs.layers();
+    if settings.aovs.format == "png" {
+        let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("image");
+        for (pass, pixels) in &layers {
+            let out = path.with_file_name(format!("{}_{}.png", stem, pass.name()));
+            let shown = viewable(*pass, pixels, settings.depth_range);
+            film::save(out.to_str().unwrap_or_default(), aovs.width, aovs.height, &shown)?;
+        }
+        return Ok(());
+    }
+    if settings.aovs.format != "exr" {
+        println!("Error, pass format '{}' not found. Using exr", settings.aovs.format);
+    }
+
+    let component = |v: &Vector3<f64>, c: usize| (match c { 0 => v.x, 1 => v.y, _ => v.z }) as f32;
+    let mut channels = vec![];
+    for (c, name) in ["R", "G", "B"].iter().enumerate() {
+        channels.push((name.to_string(), image.iter().map(|v| component(v, c)).collect()));
+    }
+    for (pass, pixels) in &layers {
+        for (c, name) in pass.channels().iter().enumerate() {
+            channels.push((format!("{}.{}", pass.name(), name), pixels.iter().map(|v| component(v, c)).collect()));
+        }
+    }
+    write_exr(&path.with_extension("exr"), aovs.width, aovs.height, channels)
+}
+
+//Maps a pass into 0 to 1 so it can be looked at as a PNG, the same way the debug views do.
+fn viewable(pass: Pass, pixels: &[Vector3<f64>], depth_range: f64) -> Vec<Vector3<f64>> {
+    let most = pixels.iter().fold(0.0f64, |most, v| most.max(v.x));
+    pixels.iter().map(|v| match pass {
+        Pass::Normal => v*0.5 + Vector3::new(0.5, 0.5, 0.5),
+        Pass::Depth => {
+            let depth = if v.x > 0.0 { 1.0 - (v.x/depth_range).min(1.0) } else { 0.0 };
+            Vector3::new(depth, depth, depth)
+        },
+        Pass::Position => v/(2.0*depth_range) + Vector3::new(0.5, 0.5, 0.5),
+        Pass::ObjectId | Pass::MaterialId => if v.x > 0.0 { id_color(v.x as usize - 1) } else { Vector3::zero() },
+        Pass::Samples => if most > 0.0 { v/most } else { *v },
+        _ => *v
+    }).collect()
+}
+
+//Uncompressed scanline OpenEXR with a 32 bit float per channel. Readers want the channels
+//sorted by name, and layers are just channels named layer.channel.
+fn write_exr(path: &Path, width: u32, height: u32, channels: Vec<(String, Vec<f32>)>) -> std::io::Result<()> {
+    std::fs::write(path, exr(width, height, channels))
+}
+
+fn exr(width: u32, height: u32, mut channels: Vec<(String, Vec<f32>)>) -> Vec<u8> {
+    channels.sort_by(|a, b| a.0.cmp(&b.0));
+    let attribute = |header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]| {
+        header.extend_from_slice(name.as_bytes());
+        header.push(0);
+        header.extend_from_slice(kind.as_bytes());
+        header.push(0);
+        header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+        header.extend_from_slice(value);
+    };
+
+    let mut list = vec![];
+    for (name, _) in &channels {
+        list.extend_from_slice(name.as_bytes());
+        list.push(0);
+        //Float pixels, not perceptually linear, three reserved bytes, then no subsampling.
+        list.extend_from_slice(&2i32.to_le_bytes());
+        list.extend_from_slice(&[0, 0, 0, 0]);
+        list.extend_from_slice(&1i32.to_le_bytes());
+        list.extend_from_slice(&1i32.to_le_bytes());
+    }
+    list.push(0);
+    let mut window = vec![];
+    for v in [0, 0, width as i32 - 1, height as i32 - 1].iter() {
+        window.extend_from_slice(&v.to_le_bytes());
+    }
+
+    //Magic number, then version 2 with no flags set.
+    let mut file = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+    attribute(&mut file, "channels", "chlist", &list);
+    attribute(&mut file, "compression", "compression", &[0]);
+    attribute(&mut file, "dataWindow", "box2i", &window);
+    attribute(&mut file, "displayWindow", "box2i", &window);
+    attribute(&mut file, "lineOrder", "lineOrder", &[0]);
+    attribute(&mut file, "pixelAspectRatio", "float", &1f32.to_le_bytes());
+    attribute(&mut file, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(&mut file, "screenWindowWidth", "float", &1f32.to_le_bytes());
+    file.push(0);
+
+    //Where each scanline starts, then the scanlines, each a channel at a time.
+    let line_size = 8 + 4*(width as usize)*channels.len();
+    let first_line = file.len() + 8*(height as usize);
+    for y in 0..height as usize {
+        file.extend_from_slice(&((first_line + y*line_size) as u64).to_le_bytes());
+    }
+    for y in 0..height {
+        file.extend_from_slice(&(y as i32).to_le_bytes());
+        file.extend_from_slice(&((line_size - 8) as i32).to_le_bytes());
+        for (_, values) in &channels {
+            for x in 0..width {
+                file.extend_from_slice(&values[(y*width + x) as usize].to_le_bytes());
+            }
+        }
+    }
+    file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::world::World;
+    use super::super::{integrator, render_section, sampler, Cancel};
+    use std::convert::TryInto;
+
+    fn le(v: i32) -> Vec<u8> {
+        v.to_le_bytes().to_vec()
+    }
+
+    #[test]
+    fn exr_header_matches_the_spec() {
+        let channels = vec![("R".to_string(), vec![1.0, 2.0]), ("B".to_string(), vec![3.0, 4.0])];
+        let file = exr(2, 1, channels);
+
+        let mut expected = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+        expected.extend_from_slice(b"channels\0chlist\0");
+        expected.extend(le(37));
+        for name in [b"B\0", b"R\0"].iter() {
+            expected.extend_from_slice(*name);
+            expected.extend(le(2));
+            expected.extend_from_slice(&[0, 0, 0, 0]);
+            expected.extend(le(1));
+            expected.extend(le(1));
+        }
+        expected.push(0);
+        expected.extend_from_slice(b"compression\0compression\0");
+        expected.extend(le(1));
+        expected.push(0);
+        for name in [&b"dataWindow\0"[..], &b"displayWindow\0"[..]].iter() {
+            expected.extend_from_slice(name);
+            expected.extend_from_slice(b"box2i\0");
+            expected.extend(le(16));
+            for v in [0, 0, 1, 0].iter() {
+                expected.extend(le(*v));
+            }
+        }
+        expected.extend_from_slice(b"lineOrder\0lineOrder\0");
+        expected.extend(le(1));
+        expected.push(0);
+        expected.extend_from_slice(b"pixelAspectRatio\0float\0");
+        expected.extend(le(4));
+        expected.extend_from_slice(&1f32.to_le_bytes());
+        expected.extend_from_slice(b"screenWindowCenter\0v2f\0");
+        expected.extend(le(8));
+        expected.extend_from_slice(&[0; 8]);
+        expected.extend_from_slice(b"screenWindowWidth\0float\0");
+        expected.extend(le(4));
+        expected.extend_from_slice(&1f32.to_le_bytes());
+        expected.push(0);
+
+        //One scanline: its offset, then y, its size and the channels in name order.
+        let header = expected.len();
+        expected.extend_from_slice(&((header + 8) as u64).to_le_bytes());
+        expected.extend(le(0));
+        expected.extend(le(16));
+        for v in [3.0f32, 4.0, 1.0, 2.0].iter() {
+            expected.extend_from_slice(&v.to_le_bytes());
+        }
+        assert_eq!(file, expected);
+    }
+
+    #[test]
+    fn exr_scanlines_are_where_the_offsets_say() {
+        let (width, height) = (3u32, 4u32);
+        let values: Vec<f32> = (0..width*height).map(|v| v as f32).collect();
+        let file = exr(width, height, vec![("Y".to_string(), values)]);
+        let line_size = 8 + 4*width as usize;
+        let table = file.len() - (height as usize)*line_size - 8*(height as usize);
+        for y in 0..height as usize {
+            let at = table + 8*y;
+            let offset = u64::from_le_bytes(file[at..at + 8].try_into().unwrap()) as usize;
+            assert_eq!(i32::from_le_bytes(file[offset..offset + 4].try_into().unwrap()), y as i32);
+            let first = f32::from_le_bytes(file[offset + 8..offset + 12].try_into().unwrap());
+            assert_eq!(first, (y as u32*width) as f32);
+        }
+    }
+
+    #[test]
+    fn lighting_passes_add_up_to_the_image() {
+        let mut world = World::empty(16, 16);
+        world.set_camera([0.0, 1.0, 2.0], [0.0, 1.0, -3.0], 60.0, [0.0, 0.0]);
+        world.add_plane([0.0, -1.0, 0.0], [0.0, 1.0, 0.0], [0.8, 0.8, 0.8], "flat");
+        world.add_sphere([0.0, 0.0, -3.0], 1.0, [1.0, 1.0, 1.0], "glass");
+        world.add_sphere([0.0, 5.0, -3.0], 2.0, [1.0, 1.0, 1.0], "diffuse_light");
+        world.add_box([1.0, -1.0, -4.0], [2.0, 0.0, -3.0], [0.5, 0.2, 0.2], "metal");
+        let mut settings = world.get_settings();
+        settings.seed = 3;
+        let scene = Arc::new(Scene::new(world.get_hitables(), world.get_materials(), world.get_lights()));
+        let integrator = integrator::by_name(scene, &settings);
+        let sampler = sampler::by_name(&settings.sampler, 8, settings.seed);
+        let filter = film::by_name(&settings.filter);
+        let passes = [Pass::DirectDiffuse, Pass::IndirectDiffuse, Pass::Specular, Pass::Emission];
+        let mut film = Film::new(16, 16, filter.clone());
+        let mut aovs = Aovs::new(&passes, 16, 16, &filter);
+        let tile = Tile { x0: 0, y0: 0, x1: 16, y1: 16 };
+        render_section(tile, &mut film, &integrator, &sampler, &world.get_camera(), 0..8, &settings.adaptive, Some(&mut aovs), &Cancel::new(), &mut |_| {});
+
+        let mut sum = vec![Vector3::zero(); 16*16];
+        for (_, pixels) in aovs.layers() {
+            for (total, p) in sum.iter_mut().zip(pixels) {
+                *total += p;
+            }
+        }
+        let image = film.pixels();
+        assert!(image.iter().any(|p| p.x > 0.0));
+        for (p, total) in image.iter().zip(sum) {
+            assert!((p - total).magnitude() < 1e-9*(1.0 + p.magnitude()));
+        }
+        //Each route shows up somewhere in this scene.
+        for (pass, pixels) in aovs.layers() {
+            assert!(pixels.iter().any(|p| p.magnitude() > 0.0), "{} is empty", pass.name());
+        }
+    }
+
+    #[test]
+    fn surface_passes_report_the_first_hit() {
+        let mut world = World::empty(9, 9);
+        world.set_camera([0.0, 0.0, 5.0], [0.0, 0.0, 0.0], 40.0, [0.0, 0.0]);
+        world.add_plane([0.0, 0.0, -10.0], [0.0, 0.0, 1.0], [0.9, 0.9, 0.9], "flat");
+        world.add_sphere([0.0, 0.0, 0.0], 1.0, [0.2, 0.4, 0.6], "flat");
+        let settings = world.get_settings();
+        let scene = Arc::new(Scene::new(world.get_hitables(), world.get_materials(), world.get_lights()));
+        let filter = film::by_name(&settings.filter);
+        let passes = [Pass::Albedo, Pass::Normal, Pass::ObjectId];
+        let mut aovs = Aovs::new(&passes, 9, 9, &filter);
+        let tile = Tile { x0: 0, y0: 0, x1: 9, y1: 9 };
+        SurfaceTracer::new(scene).gather(&tile, &mut aovs, &world.get_camera(), 4, settings.seed);
+
+        let layers = aovs.layers();
+        //The middle pixel looks at the sphere, the second object, and the corner past it
+        //at the plane.
+        let (middle, corner) = (4*9 + 4, 0);
+        assert!((layers[0].1[middle] - Vector3::new(0.2, 0.4, 0.6)).magnitude() < 1e-9);
+        assert!((layers[1].1[middle] - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 0.05);
+        assert_eq!(layers[2].1[middle], Vector3::new(2.0, 2.0, 2.0));
+        assert!((layers[0].1[corner] - Vector3::new(0.9, 0.9, 0.9)).magnitude() < 1e-9);
+        assert!((layers[1].1[corner] - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 1e-9);
+        assert_eq!(layers[2].1[corner], Vector3::new(1.0, 1.0, 1.0));
+    }
+}
@@ -0,0 +1,532 @@
+use super::camera::*;
+use super::geometry::*;
+use super::integrator::*;
+use super::material::*;
+use super::random;
+use super::ray::*;
+use super::scene::*;
+use cgmath::*;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Camera,
+    Light,
+    Surface,
+    Medium,
+}
+
+//One point on a camera or light subpath. Densities are per unit area (per unit volume in
+//media): pdf_fwd is how the subpath actually picked this vertex, pdf_rev how the other
+//direction would have picked it. Those are what the MIS weights are made from.
+#[derive(Clone)]
+struct Vertex {
+    kind: Kind,
+    p: Vector3<f64>,
+    n: Vector3<f64>,
+    //Unit direction back towards the previous vertex.
+    wo: Vector3<f64>,
+    beta: Vector3<f64>,
+    color: Vector3<f64>,
+    pdf_fwd: f64,
+    pdf_rev: f64,
+    delta: bool,
+    hitable: Option<Arc<dyn Hitable>>,
+    material: Option<Arc<dyn Material>>,
+}
+
+impl Vertex {
+    fn new(kind: Kind, p: Vector3<f64>, n: Vector3<f64>, beta: Vector3<f64>) -> Vertex {
+        Vertex {
+            kind,
+            p,
+            n,
+            wo: Vector3::zero(),
+            beta,
+            color: Vector3::new(1.0, 1.0, 1.0),
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            delta: false,
+            hitable: None,
+            material: None,
+        }
+    }
+
+    fn on_surface(&self) -> bool {
+        self.kind == Kind::Surface || self.kind == Kind::Light
+    }
+
+    fn connectible(&self) -> bool {
+        match self.kind {
+            Kind::Camera | Kind::Light => true,
+            _ => !self.delta
+        }
+    }
+
+    fn emitted(&self) -> Vector3<f64> {
+        self.material.as_ref().map(|m| m.emitted()).unwrap_or_else(Vector3::zero)
+    }
+
+    //Scattering towards next, for light that came in from the previous vertex.
+    fn f(&self, next: &Vertex) -> Vector3<f64> {
+        match &self.material {
+            Some(m) => {
+                let wn = (next.p - self.p).normalize();
+                self.color*m.eval(&self.wo, &wn, &self.n)
+            },
+            None => Vector3::zero()
+        }
+    }
+
+    //Turns a solid angle density at this vertex into an area density at next.
+    fn convert_density(&self, pdf: f64, next: &Vertex) -> f64 {
+        let w = next.p - self.p;
+        let d2 = w.magnitude2();
+        if d2 == 0.0 {
+            return 0.0;
+        }
+        let mut pdf = pdf/d2;
+        if next.on_surface() {
+            pdf *= next.n.dot(w/d2.sqrt()).abs();
+        }
+        pdf
+    }
+
+    //Density of an emitter at this vertex sending its light towards next.
+    fn pdf_light(&self, next: &Vertex) -> f64 {
+        let w = (next.p - self.p).normalize();
+        self.convert_density(self.n.dot(w).abs()/(2.0*PI), next)
+    }
+
+    //Density of this vertex being picked after prev, converted to an area density.
+    fn pdf(&self, cam: &Camera, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        match self.kind {
+            Kind::Light => self.pdf_light(next),
+            Kind::Camera => {
+                let w = (next.p - self.p).normalize();
+                self.convert_density(cam.pdf_dir(&w), next)
+            },
+            _ => {
+                let (prev, material) = match (prev, &self.material) {
+                    (Some(prev), Some(material)) => (prev, material),
+                    _ => return 0.0
+                };
+                let wp = (prev.p - self.p).normalize();
+                let wn = (next.p - self.p).normalize();
+                self.convert_density(material.pdf(&wp, &wn, &self.n), next)
+            }
+        }
+    }
+}
+
+//Bidirectional path tracer. Each sample traces a subpath from the camera and one from a
+//light, then joins every prefix of one to every prefix of the other, weighting each
+//join with the balance heuristic. Joins straight to the camera land on other pixels, so
+//they're handed back as splats.
+pub struct Bdpt {
+    scene: Arc<Scene>,
+    lights: Vec<Arc<dyn Hitable>>,
+    max_depth: usize,
+}
+
+impl Bdpt {
+    pub fn new(scene: Arc<Scene>, max_depth: u32) -> Bdpt {
+        let lights = scene.get_lights();
+        if lights.is_empty() {
+            println!("Error, no lights that can be sampled. Only paths that hit a light will count");
+        }
+        Bdpt { scene, lights, max_depth: max_depth as usize }
+    }
+
+    fn camera_subpath(&self, cam: &Camera, ray: Ray) -> Vec<Vertex> {
+        let dir = ray.direction().normalize();
+        let ray = Ray::new_at(*ray.origin(), dir, ray.time());
+        let mut path = vec![Vertex::new(Kind::Camera, *cam.origin(), *cam.forward(), Vector3::new(1.0, 1.0, 1.0))];
+        self.walk(&mut path, ray, Vector3::new(1.0, 1.0, 1.0), cam.pdf_dir(&dir), self.max_depth + 2);
+        path
+    }
+
+    fn light_subpath(&self, time: f64) -> Vec<Vertex> {
+        let vertex = match self.pick_light(time) {
+            Some(v) => v,
+            None => return vec![]
+        };
+        //Lights shine from both sides, like they do in the path tracer.
+        let mut n = vertex.n;
+        if random::gen() < 0.5 {
+            n = -n;
+        }
+        let dir = (n + rand_usphere()).normalize();
+        let cos = dir.dot(n);
+        let pdf_dir = cos/(2.0*PI);
+        if cos <= 0.0 {
+            return vec![];
+        }
+        let beta = vertex.emitted()*cos/(vertex.pdf_fwd*pdf_dir);
+        let ray = Ray::new_at(vertex.p, dir, time);
+        let mut path = vec![vertex];
+        self.walk(&mut path, ray, beta, pdf_dir, self.max_depth + 1);
+        path
+    }
+
+    //Picks a light evenly, then a point evenly over its surface. The vertex's pdf_fwd is
+    //the area density of having picked it.
+    fn pick_light(&self, time: f64) -> Option<Vertex> {
+        if self.lights.is_empty() {
+            return None;
+        }
+        let index = ((random::gen()*(self.lights.len() as f64)) as usize).min(self.lights.len() - 1);
+        let light = &self.lights[index];
+        let (p, n) = light.sample_surface(time)?;
+        let pdf_pos = 1.0/((self.lights.len() as f64)*light.area());
+        let material = self.scene.get_materials().get_material_by_key(light.get_material());
+        let mut vertex = Vertex::new(Kind::Light, p, n, material.emitted()/pdf_pos);
+        vertex.pdf_fwd = pdf_pos;
+        vertex.hitable = Some(light.clone());
+        vertex.material = Some(material);
+        Some(vertex)
+    }
+
+    //Density of a light subpath starting at v, which has to lie on an emitter.
+    fn pdf_light_origin(&self, v: &Vertex) -> f64 {
+        match &v.hitable {
+            Some(h) if h.area() > 0.0 && v.emitted() != Vector3::zero() => 1.0/((self.lights.len() as f64)*h.area()),
+            _ => 0.0
+        }
+    }
+
+    //Extends a subpath by scattering until it leaves the scene or reaches max_vertices.
+    fn walk(&self, path: &mut Vec<Vertex>, mut ray: Ray, mut beta: Vector3<f64>, mut pdf_fwd: f64, max_vertices: usize) {
+        while path.len() < max_vertices {
+            let (t, hitable) = match self.scene.get_closest_intersection(&ray, f64::MAX) {
+                Some(hit) => hit,
+                None => break
+            };
+            let p = ray.point_at_parameter(t);
+            let material = self.scene.get_materials().get_material_by_key(hitable.get_material());
+            let kind = if material.is_volume() { Kind::Medium } else { Kind::Surface };
+            let n = hitable.get_norm_at_p(&ray, &p);
+            let wo = -*ray.direction();
+
+            let mut vertex = Vertex::new(kind, p, n, beta);
+            vertex.wo = wo;
+            vertex.color = *hitable.get_color();
+            vertex.hitable = Some(hitable.clone());
+            vertex.material = Some(material.clone());
+            vertex.pdf_fwd = path.last().unwrap().convert_density(pdf_fwd, &vertex);
+            path.push(vertex);
+            if path.len() >= max_vertices {
+                break;
+            }
+
+            //Diffuse surfaces scatter back to the side the ray came from.
+            let facing = if material.is_specular() || n.dot(wo) >= 0.0 { n } else { -n };
+            let (scattered, _) = material.scatter(&ray, &facing, &p);
+            let dir = scattered.direction().normalize();
+            if !(dir.x.is_finite() && dir.y.is_finite() && dir.z.is_finite()) {
+                break;
+            }
+
+            let pdf_rev;
+            let last = path.len() - 1;
+            if material.is_specular() {
+                beta = beta.mul_element_wise(*hitable.get_color());
+                pdf_fwd = 0.0;
+                pdf_rev = 0.0;
+                path[last].delta = true;
+            } else {
+                pdf_fwd = material.pdf(&wo, &dir, &n);
+                if pdf_fwd <= 0.0 {
+                    break;
+                }
+                let cos = if material.is_volume() { 1.0 } else { dir.dot(n).abs() };
+                beta = beta.mul_element_wise(*hitable.get_color())*material.eval(&wo, &dir, &n)*cos/pdf_fwd;
+                pdf_rev = material.pdf(&dir, &wo, &n);
+            }
+            if beta == Vector3::zero() {
+                break;
+            }
+            let rev = path[last].convert_density(pdf_rev, &path[last - 1]);
+            path[last - 1].pdf_rev = rev;
+            ray = Ray::new_at(p, dir, ray.time());
+        }
+    }
+
+    fn visible(&self, a: &Vector3<f64>, b: &Vector3<f64>, time: f64) -> bool {
+        let d = b - a;
+        let dist = d.magnitude();
+        let ray = Ray::new_at(*a, d/dist, time);
+        !self.scene.blocked(&ray, dist - 0.001, &[])
+    }
+
+    fn geometry_term(&self, a: &Vertex, b: &Vertex, time: f64) -> f64 {
+        let d = b.p - a.p;
+        let d2 = d.magnitude2();
+        let w = d/d2.sqrt();
+        let mut g = 1.0/d2;
+        if a.on_surface() {
+            g *= a.n.dot(w).abs();
+        }
+        if b.on_surface() {
+            g *= b.n.dot(w).abs();
+        }
+        if g == 0.0 || !self.visible(&a.p, &b.p, time) {
+            return 0.0;
+        }
+        g
+    }
+
+    //Joins the first s light vertices to the first t camera vertices. The raster position
+    //is set for joins made straight to the camera.
+    fn connect(&self, cam: &Camera, light_path: &[Vertex], camera_path: &[Vertex], s: usize, t: usize, time: f64) -> (Vector3<f64>, Option<(f64, f64)>) {
+        let zero = (Vector3::zero(), None);
+        let mut sampled: Option<Vertex> = None;
+        let mut raster = None;
+        let col: Vector3<f64>;
+
+        if s == 0 {
+            //The camera subpath found a light by itself.
+            let pt = &camera_path[t - 1];
+            col = pt.beta.mul_element_wise(pt.emitted());
+        } else if t == 1 {
+            let qs = &light_path[s - 1];
+            if !qs.connectible() {
+                return zero;
+            }
+            raster = match cam.project(&qs.p) {
+                Some(r) => Some(r),
+                None => return zero
+            };
+            let d = qs.p - cam.origin();
+            let dist = d.magnitude();
+            let w = d/dist;
+            let v = Vertex::new(Kind::Camera, *cam.origin(), *cam.forward(), Vector3::new(1.0, 1.0, 1.0)*cam.importance(&w)*w.dot(*cam.forward())/(dist*dist));
+            let mut c = qs.beta.mul_element_wise(qs.f(&v)).mul_element_wise(v.beta);
+            if qs.on_surface() {
+                c *= qs.n.dot(w).abs();
+            }
+            if c == Vector3::zero() || !self.visible(cam.origin(), &qs.p, time) {
+                return zero;
+            }
+            col = c;
+            sampled = Some(v);
+        } else if s == 1 {
+            let pt = &camera_path[t - 1];
+            if !pt.connectible() {
+                return zero;
+            }
+            let v = match self.pick_light(time) {
+                Some(light) => light,
+                None => return zero
+            };
+            let c = pt.beta.mul_element_wise(pt.f(&v)).mul_element_wise(v.beta);
+            if c == Vector3::zero() {
+                return zero;
+            }
+            col = c*self.geometry_term(pt, &v, time);
+            sampled = Some(v);
+        } else {
+            let qs = &light_path[s - 1];
+            let pt = &camera_path[t - 1];
+            if !qs.connectible() || !pt.connectible() {
+                return zero;
+            }
+            let c = qs.beta.mul_element_wise(qs.f(pt)).mul_element_wise(pt.f(qs)).mul_element_wise(pt.beta);
+            if c == Vector3::zero() {
+                return zero;
+            }
+            col = c*self.geometry_term(qs, pt, time);
+        }
+
+        if col == Vector3::zero() {
+            return zero;
+        }
+        (col*self.mis_weight(cam, light_path, camera_path, sampled, s, t), raster)
+    }
+
+    //Balance heuristic over every other (s, t) split that could have made the same path,
+    //found by walking outwards from the join and swapping forward densities for reverse.
+    fn mis_weight(&self, cam: &Camera, light_path: &[Vertex], camera_path: &[Vertex], sampled: Option<Vertex>, s: usize, t: usize) -> f64 {
+        if s + t == 2 {
+            return 1.0;
+        }
+        let mut light = light_path[..s].to_vec();
+        let mut camera = camera_path[..t].to_vec();
+        if let Some(v) = sampled {
+            if s == 1 {
+                light[0] = v;
+            } else {
+                camera[0] = v;
+            }
+        }
+
+        if s == 0 && self.pdf_light_origin(&camera[t - 1]) == 0.0 {
+            //Emitters that can't be sampled are only ever found this way.
+            return 1.0;
+        }
+
+        //The vertices at the join are never treated as specular.
+        if s > 0 {
+            light[s - 1].delta = false;
+        }
+        camera[t - 1].delta = false;
+
+        let pt_rev = if s > 0 {
+            light[s - 1].pdf(cam, if s > 1 { Some(&light[s - 2]) } else { None }, &camera[t - 1])
+        } else {
+            self.pdf_light_origin(&camera[t - 1])
+        };
+        camera[t - 1].pdf_rev = pt_rev;
+        if t > 1 {
+            let pt_minus_rev = if s > 0 {
+                camera[t - 1].pdf(cam, Some(&light[s - 1]), &camera[t - 2])
+            } else {
+                camera[t - 1].pdf_light(&camera[t - 2])
+            };
+            camera[t - 2].pdf_rev = pt_minus_rev;
+        }
+        if s > 0 {
+            let qs_rev = camera[t - 1].pdf(cam, if t > 1 { Some(&camera[t - 2]) } else { None }, &light[s - 1]);
+            light[s - 1].pdf_rev = qs_rev;
+        }
+        if s > 1 {
+            let qs_minus_rev = light[s - 1].pdf(cam, Some(&camera[t - 1]), &light[s - 2]);
+            light[s - 2].pdf_rev = qs_minus_rev;
+        }
+
+        //Zero densities belong to specular vertices, which are skipped below anyway.
+        let remap = |x: f64| if x != 0.0 { x } else { 1.0 };
+        let mut sum = 0.0;
+        let mut ri = 1.0;
+        for i in (1..t).rev() {
+            ri *= remap(camera[i].pdf_rev)/remap(camera[i].pdf_fwd);
+            if !camera[i].delta && !camera[i - 1].delta {
+                sum += ri;
+            }
+        }
+        ri = 1.0;
+        for i in (0..s).rev() {
+            ri *= remap(light[i].pdf_rev)/remap(light[i].pdf_fwd);
+            let delta_before = i > 0 && light[i - 1].delta;
+            if !light[i].delta && !delta_before {
+                sum += ri;
+            }
+        }
+        1.0/(1.0 + sum)
+    }
+}
+
+impl Integrator for Bdpt {
+    fn render(&self, cam: &Camera, u: f64, v: f64, splat: &mut dyn FnMut(f64, f64, Vector3<f64>)) -> Vector3<f64> {
+        let ray = cam.get_ray(u, v);
+        let time = ray.time();
+        let camera_path = self.camera_subpath(cam, ray);
+        let light_path = self.light_subpath(time);
+
+        let mut col = Vector3::zero();
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                let depth = (s + t) as i64 - 2;
+                if (s == 1 && t == 1) || depth < 0 || depth > self.max_depth as i64 {
+                    continue;
+                }
+                let (c, raster) = self.connect(cam, &light_path, &camera_path, s, t, time);
+                if !(c.x.is_finite() && c.y.is_finite() && c.z.is_finite()) {
+                    continue;
+                }
+                match raster {
+                    Some((x, y)) => splat(x, y, c),
+                    None => col += c
+                }
+            }
+        }
+        col
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::world::World;
+
+    const ROOM: &str = r#"{
+        "camera": { "lookfrom": [0.0, 1.0, 6.0], "lookat": [0.0, -1.0, 0.0], "fov": 60.0 },
+        "planes": [{ "origin": [0.0, -1.0, 0.0], "normal": [0.0, 1.0, 0.0], "color": [0.8, 0.8, 0.8], "mat": "flat" }],
+        "spheres": [
+            { "center": [2.0, 0.0, 0.0], "radius": 1.0, "color": [0.7, 0.5, 0.3], "mat": "flat" },
+            { "center": [0.0, 4.0, 0.0], "radius": 1.0, "color": [1.0, 1.0, 1.0], "mat": "diffuse_light" }
+        ]
+    }"#;
+
+    fn bdpt(world: &World) -> Bdpt {
+        Bdpt::new(Arc::new(Scene::new(world.get_hitables(), world.get_materials(), world.get_lights())), 5)
+    }
+
+    //Whatever is first in the way going from towards to.
+    fn vertex_at(bdpt: &Bdpt, from: Vector3<f64>, to: Vector3<f64>) -> Vertex {
+        let dir = (to - from).normalize();
+        let ray = Ray::new_at(from + dir*1e-6, dir, 0.0);
+        let (t, hitable) = bdpt.scene.get_closest_intersection(&ray, f64::MAX).unwrap();
+        let p = ray.point_at_parameter(t);
+        let mut v = Vertex::new(Kind::Surface, p, hitable.get_norm_at_p(&ray, &p), Vector3::new(1.0, 1.0, 1.0));
+        v.hitable = Some(hitable.clone());
+        v.material = Some(bdpt.scene.get_materials().get_material_by_key(hitable.get_material()));
+        v
+    }
+
+    #[test]
+    fn weights_for_one_path_add_up_to_one() {
+        let world = World::from_json(ROOM, 32, 24).unwrap();
+        let (bdpt, cam) = (bdpt(&world), world.get_camera());
+        let c = Vertex::new(Kind::Camera, *cam.origin(), *cam.forward(), Vector3::new(1.0, 1.0, 1.0));
+        let a = vertex_at(&bdpt, c.p, Vector3::new(-2.0, -1.0, 0.0));
+        let b = vertex_at(&bdpt, a.p, Vector3::new(1.4, 0.8, 0.0));
+        let l = vertex_at(&bdpt, b.p, Vector3::new(0.0, 4.0, 0.0));
+        assert!(l.emitted() != Vector3::zero());
+
+        //Densities of each vertex coming from the camera's end and from the light's.
+        let mut path = [c, a, b, l];
+        let from_camera = [0.0, path[0].pdf(&cam, None, &path[1]), path[1].pdf(&cam, Some(&path[0]), &path[2]), path[2].pdf(&cam, Some(&path[1]), &path[3])];
+        let from_light = [0.0, path[2].pdf(&cam, Some(&path[3]), &path[1]), path[3].pdf_light(&path[2]), bdpt.pdf_light_origin(&path[3])];
+        for (k, v) in path.iter_mut().enumerate() {
+            v.pdf_fwd = from_camera[k];
+            v.pdf_rev = from_light[k];
+        }
+
+        let mut sum = 0.0;
+        for t in 1..=path.len() {
+            let camera_path = path[..t].to_vec();
+            let mut light_path: Vec<Vertex> = path[t..].iter().rev().cloned().collect();
+            for v in light_path.iter_mut() {
+                std::mem::swap(&mut v.pdf_fwd, &mut v.pdf_rev);
+            }
+            if let Some(v) = light_path.first_mut() {
+                v.kind = Kind::Light;
+            }
+            let w = bdpt.mis_weight(&cam, &light_path, &camera_path, None, path.len() - t, t);
+            assert!(w > 0.0 && w <= 1.0, "weight {} for t = {}", w, t);
+            sum += w;
+        }
+        assert!((sum - 1.0).abs() < 1e-9, "weights add up to {}", sum);
+    }
+
+    #[test]
+    fn agrees_with_the_path_tracer() {
+        let mean = |integrator: &str| {
+            let mut world = World::from_json(ROOM, 32, 24).unwrap();
+            let mut settings = world.get_settings();
+            settings.integrator = integrator.to_string();
+            settings.samples = 64;
+            settings.max_depth = 5;
+            settings.seed = 3;
+            settings.threads = 4;
+            world.set_settings(settings);
+            let image = super::super::render(&world).unwrap();
+            image.pixels.iter().map(|p| (p[0] + p[1] + p[2]) as f64).sum::<f64>()/(image.pixels.len() as f64)
+        };
+        let (path, bdpt) = (mean("path"), mean("bdpt"));
+        assert!(path > 0.0);
+        assert!((bdpt - path).abs() < 0.05*path, "bdpt {} against path {}", bdpt, path);
+    }
+}
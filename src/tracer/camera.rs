@@ -2,16 +2,21 @@ use cgmath::*;
 //use cgmath::prelude::*;
 use super::ray;
 use std::f64;
+use super::random;
+use super::stats;
 
 pub struct Camera {
     origin: Vector3<f64>,
     lower_left_corner: Vector3<f64>,
     horizontal: Vector3<f64>,
-    vertical: Vector3<f64>
+    vertical: Vector3<f64>,
+    forward: Vector3<f64>,
+    time0: f64,
+    time1: f64
 }
 
 impl Camera {
-    pub fn new(lookfrom: Vector3<f64>, lookat: Vector3<f64>, vup: Vector3<f64>, vfov: f64, aspect: f64) -> Camera {
+    pub fn new(lookfrom: Vector3<f64>, lookat: Vector3<f64>, vup: Vector3<f64>, vfov: f64, aspect: f64, time0: f64, time1: f64) -> Camera {
         let theta = (vfov*f64::consts::PI/180.0)/2.0;
         let half_height = theta.tan();
         let half_width = aspect * half_height;
@@ -26,11 +31,65 @@ impl Camera {
             lower_left_corner: lookfrom - half_width*u - half_height*v - w,
             horizontal: 2.0*half_width*u,
             vertical: 2.0*half_height*v,
-            origin: lookfrom
+            origin: lookfrom,
+            forward: -w,
+            time0,
+            time1
         }
     }
 
+    //Each ray is stamped with a random instant while the shutter is open, giving motion blur.
     pub fn get_ray(&self, u: f64, v: f64) -> ray::Ray {
-        ray::Ray::new_from(self.origin, self.lower_left_corner + u*self.horizontal + v*self.vertical - self.origin)
+        let time = self.time0 + random::gen()*(self.time1 - self.time0);
+        stats::count(|c| c.camera += 1);
+        ray::Ray::new_at(self.origin, self.lower_left_corner + u*self.horizontal + v*self.vertical - self.origin, time)
     }
-}
\ No newline at end of file
+
+    pub fn origin(&self) -> &Vector3<f64> {
+        &self.origin
+    }
+
+    pub fn forward(&self) -> &Vector3<f64> {
+        &self.forward
+    }
+
+    //Where the line from the camera to p crosses the image, as the u and v get_ray takes.
+    pub fn project(&self, p: &Vector3<f64>) -> Option<(f64, f64)> {
+        let d = p - self.origin;
+        let depth = d.dot(self.forward);
+        if depth <= 0.0 {
+            return None;
+        }
+        let on_plane = d/depth - (self.lower_left_corner - self.origin);
+        let u = on_plane.dot(self.horizontal)/self.horizontal.magnitude2();
+        let v = on_plane.dot(self.vertical)/self.vertical.magnitude2();
+        if (0.0..1.0).contains(&u) && (0.0..1.0).contains(&v) {
+            Some((u, v))
+        } else {
+            None
+        }
+    }
+
+    //Importance emitted along the unit direction w, normalized over the image plane one
+    //unit in front of the pinhole.
+    pub fn importance(&self, w: &Vector3<f64>) -> f64 {
+        let cos = w.dot(self.forward);
+        if self.project(&(self.origin + w)).is_none() {
+            return 0.0;
+        }
+        1.0/(self.plane_area()*cos*cos*cos*cos)
+    }
+
+    //Solid angle density of get_ray picking the unit direction w.
+    pub fn pdf_dir(&self, w: &Vector3<f64>) -> f64 {
+        let cos = w.dot(self.forward);
+        if self.project(&(self.origin + w)).is_none() {
+            return 0.0;
+        }
+        1.0/(self.plane_area()*cos*cos*cos)
+    }
+
+    fn plane_area(&self) -> f64 {
+        self.horizontal.magnitude()*self.vertical.magnitude()
+    }
+}
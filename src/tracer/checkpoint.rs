@@ -0,0 +1,170 @@
+use super::aov::Aovs;
+use super::film::Film;
+use cgmath::*;
+use std::path::Path;
+
+//Checkpoints hold the image's films after some number of tiles were added in. Every
+//sample's numbers come from the seed, the pixel and the sample's index, and tiles are added
+//in a fixed order, so that count is all the random state there is: carrying on from it
+//adds exactly what an uninterrupted render would have.
+const MAGIC: &[u8; 8] = b"RTCKPT02";
+
+pub struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn u64(&mut self, v: u64) {
+        self.bytes.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn floats(&mut self, values: &[f64]) {
+        self.u64(values.len() as u64);
+        for v in values {
+            self.bytes.extend_from_slice(&v.to_bits().to_le_bytes());
+        }
+    }
+
+    pub fn vectors(&mut self, values: &[Vector3<f64>]) {
+        self.floats(&values.iter().flat_map(|v| vec![v.x, v.y, v.z]).collect::<Vec<f64>>());
+    }
+}
+
+//Reads back what a Writer wrote, None once anything doesn't add up.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn u64(&mut self) -> Option<u64> {
+        if self.bytes.len() < 8 {
+            return None;
+        }
+        let (v, rest) = self.bytes.split_at(8);
+        self.bytes = rest;
+        let mut b = [0u8; 8];
+        b.copy_from_slice(v);
+        Some(u64::from_le_bytes(b))
+    }
+
+    pub fn floats(&mut self) -> Option<Vec<f64>> {
+        let n = self.u64()? as usize;
+        if self.bytes.len() < 8*n {
+            return None;
+        }
+        (0..n).map(|_| self.u64().map(f64::from_bits)).collect()
+    }
+
+    pub fn vectors(&mut self) -> Option<Vec<Vector3<f64>>> {
+        let floats = self.floats()?;
+        if floats.len() % 3 != 0 {
+            return None;
+        }
+        Some(floats.chunks(3).map(|c| Vector3::new(c[0], c[1], c[2])).collect())
+    }
+}
+
+//Writes the films and how many jobs went into them. The key sums up the world and image
+//size, so a checkpoint isn't picked up by some other render.
+pub fn save(path: &Path, key: u64, done: usize, film: &Film, aovs: Option<&Aovs>) -> std::io::Result<()> {
+    let mut out = Writer { bytes: MAGIC.to_vec() };
+    out.u64(key);
+    out.u64(done as u64);
+    film.write(&mut out);
+    if let Some(aovs) = aovs {
+        aovs.write(&mut out);
+    }
+    //Written beside it first so a crash partway through doesn't wreck the last good one.
+    let temp = path.with_extension("checkpoint.part");
+    std::fs::write(&temp, out.bytes)?;
+    std::fs::rename(&temp, path)
+}
+
+//Loads a checkpoint into empty films, giving back how many jobs it had done.
+pub fn load(path: &Path, key: u64, film: &mut Film, aovs: Option<&mut Aovs>) -> Option<usize> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            println!("Error, couldn't read checkpoint {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    if !bytes.starts_with(MAGIC) {
+        println!("Error, {} isn't a checkpoint", path.display());
+        return None;
+    }
+    let mut input = Reader { bytes: &bytes[MAGIC.len()..] };
+    if input.u64()? != key {
+        println!("Error, checkpoint {} is for a different world or image size", path.display());
+        return None;
+    }
+    let done = input.u64()? as usize;
+    let read = film.read(&mut input).and_then(|_| match aovs {
+        Some(aovs) => aovs.read(&mut input),
+        None => Some(())
+    });
+    if read.is_none() {
+        println!("Error, checkpoint {} is damaged", path.display());
+        return None;
+    }
+    Some(done)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::film::{self, Features};
+    use super::super::world::FilterSettings;
+
+    fn film() -> Film {
+        film::Film::new(5, 3, film::by_name(&FilterSettings { name: "mitchell".to_string(), radius: 0.0 }))
+    }
+
+    fn filled() -> Film {
+        let mut film = film();
+        for k in 0..40 {
+            let (x, y) = ((k as f64*0.37) % 5.0, (k as f64*0.61) % 3.0);
+            film.add_sample(x, y, Vector3::new(x, y, 0.5));
+            let features = Features { albedo: Vector3::new(0.5, 0.5, 0.5), normal: Vector3::new(0.0, 1.0, 0.0), depth: x };
+            film.add_features(x, y, &features);
+        }
+        film.add_splat(0.3, 0.6, Vector3::new(2.0, 1.0, 0.0));
+        film.add_samples(40);
+        film
+    }
+
+    fn path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rustytracer_{}_{}.checkpoint", name, std::process::id()))
+    }
+
+    #[test]
+    fn checkpoints_load_back_what_was_saved() {
+        let path = path("round_trip");
+        let saved = filled();
+        save(&path, 42, 7, &saved, None).unwrap();
+        let mut loaded = film();
+        assert_eq!(load(&path, 42, &mut loaded, None), Some(7));
+        for j in 0..3 {
+            for i in 0..5 {
+                assert_eq!(saved.pixel(i, j), loaded.pixel(i, j));
+                assert_eq!(saved.weight(i, j), loaded.weight(i, j));
+                assert_eq!(saved.features(i, j).depth, loaded.features(i, j).depth);
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn checkpoints_for_something_else_are_refused() {
+        let path = path("refused");
+        save(&path, 42, 7, &filled(), None).unwrap();
+        assert_eq!(load(&path, 43, &mut film(), None), None);
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 5]).unwrap();
+        assert_eq!(load(&path, 42, &mut film(), None), None);
+        std::fs::write(&path, b"not a checkpoint").unwrap();
+        assert_eq!(load(&path, 42, &mut film(), None), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+use super::geometry::*;
+use super::ray::*;
+use cgmath::*;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CsgOp {
+    Union,
+    Intersection,
+    Difference,
+}
+
+impl CsgOp {
+    fn inside(self, in_left: bool, in_right: bool) -> bool {
+        match self {
+            CsgOp::Union => in_left || in_right,
+            CsgOp::Intersection => in_left && in_right,
+            CsgOp::Difference => in_left && !in_right,
+        }
+    }
+}
+
+//Combines two solids by walking the spans where the ray is inside each of them.
+//Color and material come from the left child.
+pub struct Csg {
+    op: CsgOp,
+    left: Arc<dyn Hitable>,
+    right: Arc<dyn Hitable>,
+}
+
+impl Csg {
+    pub fn new(op: CsgOp, left: Arc<dyn Hitable>, right: Arc<dyn Hitable>) -> Csg {
+        Csg { op, left, right }
+    }
+
+    //Finds the child whose boundary the ray crosses at t. The flag is set when that
+    //surface faces into the result, which happens for the subtracted shape.
+    fn surface_at(&self, r: &Ray, t: f64) -> (&Arc<dyn Hitable>, bool) {
+        let gap = |child: &Arc<dyn Hitable>| {
+            child.hit_intervals(r)
+                .iter()
+                .flat_map(|&(t0, t1)| vec![t0, t1])
+                .map(|end| (end - t).abs())
+                .fold(f64::MAX, f64::min)
+        };
+        if gap(&self.left) <= gap(&self.right) {
+            (&self.left, false)
+        } else {
+            (&self.right, self.op == CsgOp::Difference)
+        }
+    }
+
+    fn param_at(r: &Ray, p: &Vector3<f64>) -> f64 {
+        (p - r.origin()).dot(*r.direction())/r.direction().dot(*r.direction())
+    }
+}
+
+impl Hitable for Csg {
+    fn hit(&self, r: &Ray, t_max: f64) -> f64 {
+        for (t0, t1) in self.hit_intervals(r) {
+            if t0 > 0.001 && t0 < t_max {
+                return t0;
+            }
+            if t1 > 0.001 && t1 < t_max {
+                return t1;
+            }
+        }
+        0.0
+    }
+
+    fn get_center(&self) -> &Vector3<f64> {
+        self.left.get_center()
+    }
+
+    fn get_radius(&self) -> f64 {
+        self.left.get_radius()
+    }
+
+    fn get_color(&self) -> &Vector3<f64> {
+        self.left.get_color()
+    }
+
+    fn get_material(&self) -> String {
+        self.left.get_material()
+    }
+
+    fn get_norm_at_p(&self, r: &Ray, p: &Vector3<f64>) -> Vector3<f64> {
+        let (child, flipped) = self.surface_at(r, Csg::param_at(r, p));
+        let n = child.get_norm_at_p(r, p);
+        if flipped { -n } else { n }
+    }
+
+    fn get_uv_at_p(&self, r: &Ray, p: &Vector3<f64>) -> (f64, f64) {
+        let (child, _) = self.surface_at(r, Csg::param_at(r, p));
+        child.get_uv_at_p(r, p)
+    }
+
+    //Sweeps the boundaries of both children in order and keeps the stretches where
+    //the operation says the ray is inside.
+    fn hit_intervals(&self, r: &Ray) -> Vec<(f64, f64)> {
+        let mut events: Vec<(f64, bool)> = vec![];
+        for (t0, t1) in self.left.hit_intervals(r) {
+            events.push((t0, true));
+            events.push((t1, true));
+        }
+        for (t0, t1) in self.right.hit_intervals(r) {
+            events.push((t0, false));
+            events.push((t1, false));
+        }
+        //Degenerate rays, like the one a light returns, give NaN spans.
+        if events.iter().any(|e| e.0.is_nan()) {
+            return vec![];
+        }
+        events.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+        let mut spans = vec![];
+        let mut in_left = false;
+        let mut in_right = false;
+        let mut start = 0.0;
+        for (t, is_left) in events {
+            let was_inside = self.op.inside(in_left, in_right);
+            if is_left {
+                in_left = !in_left;
+            } else {
+                in_right = !in_right;
+            }
+            let inside = self.op.inside(in_left, in_right);
+            if inside && !was_inside {
+                start = t;
+            } else if was_inside && !inside && t > start {
+                spans.push((start, t));
+            }
+        }
+        spans
+    }
+
+    fn is_closed(&self) -> bool {
+        self.left.is_closed() && self.right.is_closed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Two unit spheres overlapping between x = -0.5 and x = 0.5.
+    fn pair(op: CsgOp) -> Csg {
+        let white = Vector3::new(1.0, 1.0, 1.0);
+        let left: Arc<dyn Hitable> = Arc::new(Sphere::new(white, 1.0, Vector3::new(-0.5, 0.0, 0.0), "flat".to_string()));
+        let right: Arc<dyn Hitable> = Arc::new(Sphere::new(white, 1.0, Vector3::new(0.5, 0.0, 0.0), "flat".to_string()));
+        Csg::new(op, left, right)
+    }
+
+    //Along the x axis from x = 5, so t is 5 - x.
+    fn along_x() -> Ray {
+        Ray::new_at(Vector3::new(5.0, 0.0, 0.0), Vector3::new(-1.0, 0.0, 0.0), 0.0)
+    }
+
+    fn close_spans(a: &[(f64, f64)], b: &[(f64, f64)]) -> bool {
+        a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| (x.0 - y.0).abs() < 1e-9 && (x.1 - y.1).abs() < 1e-9)
+    }
+
+    #[test]
+    fn spans_follow_the_operation() {
+        assert!(close_spans(&pair(CsgOp::Union).hit_intervals(&along_x()), &[(3.5, 6.5)]));
+        assert!(close_spans(&pair(CsgOp::Intersection).hit_intervals(&along_x()), &[(4.5, 5.5)]));
+        assert!(close_spans(&pair(CsgOp::Difference).hit_intervals(&along_x()), &[(5.5, 6.5)]));
+    }
+
+    #[test]
+    fn difference_surface_faces_into_the_result() {
+        let diff = pair(CsgOp::Difference);
+        let r = along_x();
+        let t = diff.hit(&r, f64::MAX);
+        assert!((t - 5.5).abs() < 1e-9);
+        //The hole's wall belongs to the right sphere, flipped to point out of what's left.
+        let n = diff.get_norm_at_p(&r, &r.point_at_parameter(t));
+        assert!((n - Vector3::new(1.0, 0.0, 0.0)).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn nan_spans_are_ignored() {
+        let degenerate = Ray::new_at(Vector3::new(5.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 0.0), 0.0);
+        assert!(pair(CsgOp::Union).hit_intervals(&degenerate).is_empty());
+        assert_eq!(pair(CsgOp::Union).hit(&degenerate, f64::MAX), 0.0);
+    }
+}
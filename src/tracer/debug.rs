@@ -0,0 +1,185 @@
+use super::camera::*;
+use super::geometry::*;
+use super::integrator::*;
+use super::random;
+use super::ray::*;
+use super::scene::*;
+use super::world::Settings;
+use cgmath::*;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum View {
+    Normals,
+    Depth,
+    Albedo,
+    Uv,
+    ObjectId,
+    AmbientOcclusion,
+    Bounces,
+}
+
+impl View {
+    pub fn from_name(name: &str) -> Option<View> {
+        match name {
+            "normals" => Some(View::Normals),
+            "depth" => Some(View::Depth),
+            "albedo" => Some(View::Albedo),
+            "uv" => Some(View::Uv),
+            "object_id" => Some(View::ObjectId),
+            "ao" => Some(View::AmbientOcclusion),
+            "bounces" => Some(View::Bounces),
+            _ => None
+        }
+    }
+}
+
+//Shows one property of whatever the camera ray hits first, for tracking down problems
+//with a scene. Misses are black.
+pub struct Debug {
+    scene: Arc<Scene>,
+    view: View,
+    //Distance where the depth view fades to black.
+    depth_range: f64,
+    //How far ambient occlusion rays look for something blocking them.
+    ao_distance: f64,
+    min_depth: u32,
+    //Bounce count shown as red in the heatmap.
+    max_depth: u32,
+}
+
+impl Debug {
+    pub fn new(scene: Arc<Scene>, view: View, settings: &Settings) -> Debug {
+        Debug {
+            scene,
+            view,
+            depth_range: settings.depth_range,
+            ao_distance: settings.ao_distance,
+            min_depth: settings.min_depth,
+            max_depth: settings.max_depth.max(1),
+        }
+    }
+
+    //Fraction of a cosine weighted hemisphere above p that's open, one ray at a time.
+    fn ambient_occlusion(&self, r: &Ray, p: &Vector3<f64>, n: &Vector3<f64>) -> f64 {
+        let n = if n.dot(*r.direction()) > 0.0 { -*n } else { *n };
+        let dir = (n + rand_usphere()).normalize();
+        let shadow = Ray::new_at(*p, dir, r.time());
+        if self.scene.blocked(&shadow, self.ao_distance, &[]) { 0.0 } else { 1.0 }
+    }
+
+    //How many times the path tracer would scatter the ray before it ends.
+    fn bounces(&self, r: Ray) -> u32 {
+        let mut ray = r;
+        let mut beta = Vector3::new(1.0, 1.0, 1.0);
+        let mut depth = 0;
+        loop {
+            let q = survival(depth, &beta, self.min_depth, self.max_depth);
+            if q < 1.0 && random::gen() >= q {
+                return depth;
+            }
+            beta /= q;
+            let (t, hitable) = match self.scene.get_closest_intersection(&ray, f64::MAX) {
+                Some(hit) => hit,
+                None => return depth
+            };
+            let material = self.scene.get_materials().get_material_by_key(hitable.get_material());
+            if material.emitted() != Vector3::zero() {
+                return depth;
+            }
+            let p = ray.point_at_parameter(t);
+            let n = hitable.get_norm_at_p(&ray, &p);
+            ray = material.scatter(&ray, &n, &p).0;
+            beta = beta.mul_element_wise(*hitable.get_color());
+            depth += 1;
+        }
+    }
+}
+
+//Blue through green to red as x goes from 0 to 1.
+fn heat(x: f64) -> Vector3<f64> {
+    let x = x.clamp(0.0, 1.0);
+    if x < 0.5 {
+        Vector3::new(0.0, 2.0*x, 1.0 - 2.0*x)
+    } else {
+        Vector3::new(2.0*x - 1.0, 2.0 - 2.0*x, 0.0)
+    }
+}
+
+//Spreads an index over hues so neighbouring objects are easy to tell apart.
+pub fn id_color(id: usize) -> Vector3<f64> {
+    let hue = ((id as f64)*0.618_033_988_75).fract()*6.0;
+    let x = 1.0 - (hue % 2.0 - 1.0).abs();
+    match hue as u32 {
+        0 => Vector3::new(1.0, x, 0.0),
+        1 => Vector3::new(x, 1.0, 0.0),
+        2 => Vector3::new(0.0, 1.0, x),
+        3 => Vector3::new(0.0, x, 1.0),
+        4 => Vector3::new(x, 0.0, 1.0),
+        _ => Vector3::new(1.0, 0.0, x)
+    }
+}
+
+impl Integrator for Debug {
+    fn render(&self, cam: &Camera, u: f64, v: f64, _: &mut dyn FnMut(f64, f64, Vector3<f64>)) -> Vector3<f64> {
+        let ray = cam.get_ray(u, v);
+        if self.view == View::Bounces {
+            return heat((self.bounces(ray) as f64)/(self.max_depth as f64));
+        }
+
+        let (t, hitable) = match self.scene.get_closest_intersection(&ray, f64::MAX) {
+            Some(hit) => hit,
+            None => return Vector3::zero()
+        };
+        let p = ray.point_at_parameter(t);
+        match self.view {
+            View::Normals => hitable.get_norm_at_p(&ray, &p)*0.5 + Vector3::new(0.5, 0.5, 0.5),
+            View::Depth => {
+                let depth = 1.0 - (t*ray.direction().magnitude()/self.depth_range).min(1.0);
+                Vector3::new(depth, depth, depth)
+            },
+            View::Albedo => *hitable.get_color(),
+            View::Uv => {
+                let (u, v) = hitable.get_uv_at_p(&ray, &p);
+                Vector3::new(u, v, 0.0)
+            },
+            View::ObjectId => id_color(self.scene.index_of(hitable)),
+            View::AmbientOcclusion => {
+                let ao = self.ambient_occlusion(&ray, &p, &hitable.get_norm_at_p(&ray, &p));
+                Vector3::new(ao, ao, ao)
+            },
+            View::Bounces => Vector3::zero()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::world::World;
+
+    //What the ray through the middle of the image sees, with the camera 5 in front of a
+    //unit sphere.
+    fn look(view: View, depth_range: f64) -> Vector3<f64> {
+        let mut world = World::empty(8, 8);
+        world.set_camera([0.0, 0.0, 5.0], [0.0, 0.0, 0.0], 40.0, [0.0, 0.0]);
+        world.add_sphere([0.0, 0.0, 0.0], 1.0, [0.2, 0.4, 0.6], "flat");
+        let mut settings = world.get_settings();
+        settings.depth_range = depth_range;
+        let scene = Arc::new(Scene::new(world.get_hitables(), world.get_materials(), world.get_lights()));
+        Debug::new(scene, view, &settings).render(&world.get_camera(), 0.5, 0.5, &mut |_, _, _| {})
+    }
+
+    #[test]
+    fn normals_facing_the_camera_are_blue() {
+        assert!((look(View::Normals, 20.0) - Vector3::new(0.5, 0.5, 1.0)).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn depth_fades_out_at_the_range() {
+        //The sphere is 4 away.
+        assert!((look(View::Depth, 8.0) - Vector3::new(0.5, 0.5, 0.5)).magnitude() < 1e-9);
+        assert!(look(View::Depth, 4.0).magnitude() < 1e-9);
+        assert_eq!(look(View::Depth, 2.0), Vector3::zero());
+    }
+}
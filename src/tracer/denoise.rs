@@ -0,0 +1,200 @@
+use super::camera::*;
+use super::film::{Features, Film};
+use super::random;
+use super::ray::*;
+use super::scene::*;
+use super::world::DenoiseSettings;
+use super::Tile;
+use cgmath::*;
+use std::sync::Arc;
+
+//Finds what each pixel sees for the denoiser to go by. Mirrors and glass are looked
+//through, taking the stronger ray at glass, so what's behind them keeps its edges.
+pub struct FeatureTracer {
+    scene: Arc<Scene>,
+    //Hitables a feature ray can stop at, which leaves out media.
+    surfaces: Vec<bool>,
+    max_depth: u32,
+}
+
+impl FeatureTracer {
+    pub fn new(scene: Arc<Scene>, max_depth: u32) -> FeatureTracer {
+        let materials = scene.get_materials();
+        let surfaces = scene.get_hitables().iter()
+            .map(|h| !materials.get_material_by_key(h.get_material()).is_volume())
+            .collect();
+        FeatureTracer { scene, surfaces, max_depth }
+    }
+
+    fn trace(&self, mut ray: Ray) -> Features {
+        let mut tint = Vector3::new(1.0, 1.0, 1.0);
+        let mut depth = 0.0;
+        for _ in 0..=self.max_depth {
+            let (t, hitable) = match self.scene.get_closest_intersection_among(&ray, f64::MAX, &self.surfaces) {
+                Some(hit) => hit,
+                None => break
+            };
+            let p = ray.point_at_parameter(t);
+            let n = hitable.get_norm_at_p(&ray, &p);
+            let n = if n.dot(*ray.direction()) > 0.0 { -n } else { n };
+            depth += t*ray.direction().magnitude();
+            let material = self.scene.get_materials().get_material_by_key(hitable.get_material());
+            let emitted = material.emitted();
+            if emitted != Vector3::zero() {
+                let albedo = tint.mul_element_wise(emitted.map(|c| c.min(1.0)));
+                return Features { albedo, normal: n, depth };
+            }
+
+            let color = *hitable.get_color();
+            let rays = material.specular_rays(&ray, &n, &p);
+            match rays.into_iter().max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal)) {
+                Some((next, _)) => {
+                    tint = tint.mul_element_wise(color);
+                    ray = next;
+                },
+                None => return Features { albedo: tint.mul_element_wise(color), normal: n, depth }
+            }
+        }
+        Features::zero()
+    }
+
+    //Adds samples jittered looks at every pixel of the tile to its film. The numbers come
+    //from the seed and the pixel, so they don't move the integrator's samples around.
+    pub fn gather(&self, tile: &Tile, film: &mut Film, cam: &Camera, samples: u32, seed: u64) {
+        let (width, height) = (film.image_width() as f64, film.image_height() as f64);
+        for i in tile.x0..tile.x1 {
+            for j in tile.y0..tile.y1 {
+                let stream = random::Seeded::new(random::hash(&[seed, i as u64, j as u64]));
+                random::with_stream(Box::new(stream), || {
+                    for _ in 0..samples {
+                        let x = (i as f64) + random::gen();
+                        let y = (j as f64) + random::gen();
+                        let features = self.trace(cam.get_ray(x/width, y/height));
+                        film.add_features(x, y, &features);
+                    }
+                });
+            }
+        }
+    }
+}
+
+//B3 spline the a-trous passes spread out.
+const KERNEL: [f64; 5] = [1.0/16.0, 1.0/4.0, 3.0/8.0, 1.0/4.0, 1.0/16.0];
+
+//Edge-avoiding a-trous wavelet filter (Dammertz et al.) over a film covering the whole
+//image. Colors are divided by albedo first so only the lighting gets blurred and texture
+//comes back sharp afterwards. Each pass spaces the kernel's taps twice as far apart and
+//halves how different a color can be, so big flat areas smooth out without edges bleeding.
+pub fn denoise(film: &Film, settings: &DenoiseSettings) -> Vec<Vector3<f64>> {
+    let (width, height) = (film.image_width() as i64, film.image_height() as i64);
+    let mut features = Vec::with_capacity((width*height) as usize);
+    for j in 0..height {
+        for i in 0..width {
+            features.push(film.features(i as u32, j as u32));
+        }
+    }
+    //Dark albedo channels would blow up, those are left as they are.
+    let divisor = |f: &Features| f.albedo.map(|a| if a > 0.01 { a } else { 1.0 });
+    let mut colors: Vec<Vector3<f64>> = film.pixels().iter().zip(features.iter())
+        .map(|(col, f)| col.div_element_wise(divisor(f)))
+        .collect();
+
+    let sigma_albedo = settings.sigma_albedo.max(1e-6);
+    let sigma_normal = settings.sigma_normal.max(1e-6);
+    let sigma_depth = settings.sigma_depth.max(1e-6);
+    let mut sigma_color = settings.sigma_color.max(1e-6);
+    for pass in 0..settings.iterations {
+        let step = 1i64 << pass;
+        let mut filtered = Vec::with_capacity(colors.len());
+        for j in 0..height {
+            for i in 0..width {
+                let k = (j*width + i) as usize;
+                let (c, f) = (colors[k], &features[k]);
+                let mut sum = Vector3::zero();
+                let mut total = 0.0;
+                for (dy, ky) in KERNEL.iter().enumerate() {
+                    for (dx, kx) in KERNEL.iter().enumerate() {
+                        let x = i + (dx as i64 - 2)*step;
+                        let y = j + (dy as i64 - 2)*step;
+                        if x < 0 || y < 0 || x >= width || y >= height {
+                            continue;
+                        }
+                        let q = (y*width + x) as usize;
+                        let (cq, fq) = (colors[q], &features[q]);
+                        let color = (c - cq).magnitude2()/(sigma_color*sigma_color);
+                        let albedo = (f.albedo - fq.albedo).magnitude2()/(sigma_albedo*sigma_albedo);
+                        let normal = (f.normal - fq.normal).magnitude2()/(sigma_normal*sigma_normal);
+                        let depth = (f.depth - fq.depth).abs()/(sigma_depth*f.depth.max(fq.depth).max(1e-6));
+                        let w = kx*ky*(-color - albedo - normal - depth).exp();
+                        sum += cq*w;
+                        total += w;
+                    }
+                }
+                filtered.push(if total > 0.0 { sum/total } else { c });
+            }
+        }
+        colors = filtered;
+        sigma_color *= 0.5;
+    }
+
+    colors.iter().zip(features.iter())
+        .map(|(col, f)| col.mul_element_wise(divisor(f)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::film;
+    use super::super::world::FilterSettings;
+
+    //A film with one sample in the middle of each pixel. The left half faces up and the
+    //right half faces sideways, and each half is lit by its own level plus noise.
+    fn film(noise: f64) -> Film {
+        let filter = film::by_name(&FilterSettings { name: "box".to_string(), radius: 0.0 });
+        let mut film = Film::new(32, 16, filter);
+        random::with_stream(Box::new(random::Seeded::new(8)), || {
+            for j in 0..16 {
+                for i in 0..32 {
+                    let (x, y) = (i as f64 + 0.5, j as f64 + 0.5);
+                    let (level, normal) = if i < 16 { (0.2, Vector3::new(0.0, 1.0, 0.0)) } else { (0.8, Vector3::new(1.0, 0.0, 0.0)) };
+                    let l = level + noise*(random::gen() - 0.5);
+                    film.add_sample(x, y, Vector3::new(l, l, l));
+                    film.add_features(x, y, &Features { albedo: Vector3::new(1.0, 1.0, 1.0), normal, depth: 5.0 });
+                }
+            }
+        });
+        film
+    }
+
+    fn spread(pixels: &[Vector3<f64>], level: f64, columns: std::ops::Range<usize>) -> f64 {
+        let mut worst: f64 = 0.0;
+        for j in 0..16 {
+            for i in columns.clone() {
+                worst = worst.max((pixels[j*32 + i].x - level).abs());
+            }
+        }
+        worst
+    }
+
+    #[test]
+    fn noise_is_smoothed_without_crossing_edges() {
+        let settings = DenoiseSettings::default();
+        let noisy = film(0.2);
+        let before = noisy.pixels();
+        let after = denoise(&noisy, &settings);
+        assert!(spread(&after, 0.2, 0..16) < 0.5*spread(&before, 0.2, 0..16));
+        assert!(spread(&after, 0.8, 16..32) < 0.5*spread(&before, 0.8, 16..32));
+        //The columns either side of the edge stay on their own side.
+        assert!(spread(&after, 0.2, 15..16) < 0.1);
+        assert!(spread(&after, 0.8, 16..17) < 0.1);
+    }
+
+    #[test]
+    fn clean_images_come_back_unchanged() {
+        let clean = film(0.0);
+        for (a, b) in clean.pixels().iter().zip(denoise(&clean, &DenoiseSettings::default()).iter()) {
+            assert!((a - b).magnitude() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,511 @@
+use super::checkpoint::{Reader, Writer};
+use super::world::FilterSettings;
+use super::Tile;
+use crate::tracer::image::GenericImage;
+use cgmath::*;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+//Reconstruction filter, weighing a sample by its offset in pixels from a pixel's center.
+pub trait Filter: Send + Sync {
+    //Furthest offset on either axis that still gets any weight.
+    fn radius(&self) -> f64;
+    fn eval(&self, x: f64, y: f64) -> f64;
+}
+
+//Samples on the edge between two pixels only count towards the one on the right or below.
+pub struct BoxFilter {
+    radius: f64,
+}
+
+impl Filter for BoxFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+    fn eval(&self, x: f64, y: f64) -> f64 {
+        let r = self.radius;
+        if x >= -r && x < r && y >= -r && y < r { 1.0 } else { 0.0 }
+    }
+}
+
+pub struct Tent {
+    radius: f64,
+}
+
+impl Filter for Tent {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+    fn eval(&self, x: f64, y: f64) -> f64 {
+        (1.0 - x.abs()/self.radius).max(0.0)*(1.0 - y.abs()/self.radius).max(0.0)
+    }
+}
+
+//Shifted down so it reaches zero at the radius instead of being cut off.
+pub struct Gaussian {
+    radius: f64,
+    sigma: f64,
+}
+
+impl Gaussian {
+    fn gaussian(&self, x: f64) -> f64 {
+        let g = |x: f64| (-x*x/(2.0*self.sigma*self.sigma)).exp();
+        (g(x) - g(self.radius)).max(0.0)
+    }
+}
+
+impl Filter for Gaussian {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+    fn eval(&self, x: f64, y: f64) -> f64 {
+        self.gaussian(x)*self.gaussian(y)
+    }
+}
+
+//Mitchell-Netravali cubic with B = C = 1/3. The small negative lobes keep edges sharp.
+pub struct Mitchell {
+    radius: f64,
+}
+
+impl Mitchell {
+    fn mitchell(&self, x: f64) -> f64 {
+        let (b, c) = (1.0/3.0, 1.0/3.0);
+        let x = (2.0*x/self.radius).abs();
+        if x > 2.0 {
+            0.0
+        } else if x > 1.0 {
+            ((-b - 6.0*c)*x*x*x + (6.0*b + 30.0*c)*x*x + (-12.0*b - 48.0*c)*x + (8.0*b + 24.0*c))/6.0
+        } else {
+            ((12.0 - 9.0*b - 6.0*c)*x*x*x + (-18.0 + 12.0*b + 6.0*c)*x*x + (6.0 - 2.0*b))/6.0
+        }
+    }
+}
+
+impl Filter for Mitchell {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+    fn eval(&self, x: f64, y: f64) -> f64 {
+        self.mitchell(x)*self.mitchell(y)
+    }
+}
+
+//Sinc windowed by a wider sinc that ends at the radius.
+pub struct Lanczos {
+    radius: f64,
+}
+
+impl Lanczos {
+    fn lanczos(&self, x: f64) -> f64 {
+        let sinc = |x: f64| if x.abs() < 1e-5 { 1.0 } else { (PI*x).sin()/(PI*x) };
+        if x.abs() > self.radius { 0.0 } else { sinc(x)*sinc(x/self.radius) }
+    }
+}
+
+impl Filter for Lanczos {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+    fn eval(&self, x: f64, y: f64) -> f64 {
+        self.lanczos(x)*self.lanczos(y)
+    }
+}
+
+//Builds the filter the settings name, with its usual radius unless one is given.
+pub fn by_name(settings: &FilterSettings) -> Arc<dyn Filter> {
+    let radius = |usual: f64| if settings.radius > 0.0 { settings.radius } else { usual };
+    match settings.name.as_str() {
+        "box" => Arc::new(BoxFilter { radius: radius(0.5) }),
+        "tent" => Arc::new(Tent { radius: radius(1.0) }),
+        "gaussian" => Arc::new(Gaussian { radius: radius(1.5), sigma: radius(1.5)/3.0 }),
+        "mitchell" => Arc::new(Mitchell { radius: radius(2.0) }),
+        "lanczos" => Arc::new(Lanczos { radius: radius(3.0) }),
+        name => {
+            println!("Error, filter '{}' not found. Using box", name);
+            Arc::new(BoxFilter { radius: radius(0.5) })
+        }
+    }
+}
+
+//What a pixel sees, for guiding the denoiser. Misses have zero everything.
+#[derive(Clone, Copy)]
+pub struct Features {
+    pub albedo: Vector3<f64>,
+    pub normal: Vector3<f64>,
+    //Distance along the camera ray.
+    pub depth: f64,
+}
+
+impl Features {
+    pub fn zero() -> Features {
+        Features { albedo: Vector3::zero(), normal: Vector3::zero(), depth: 0.0 }
+    }
+
+    fn add(&mut self, other: &Features) {
+        self.albedo += other.albedo;
+        self.normal += other.normal;
+        self.depth += other.depth;
+    }
+}
+
+//Float framebuffer that samples are filtered into. A film covers the whole image, or one
+//tile plus the pixels around it its samples reach, and tile films get merged into the
+//image's film. Splats, from light that lands wherever it likes, are kept for the whole
+//image and shared out over every sample the image took.
+pub struct Film {
+    //Pixel rectangle this film stores, which can stick out past the image.
+    x0: i64,
+    y0: i64,
+    width: u32,
+    height: u32,
+    image_width: u32,
+    image_height: u32,
+    filter: Arc<dyn Filter>,
+    //Filter weighted sum of samples and of the weights, per pixel.
+    sums: Vec<Vector3<f64>>,
+    weights: Vec<f64>,
+    //Splatted light for every pixel of the image. Stays empty until something is splatted,
+    //and tile films only use it once their list of splats would be bigger.
+    splats: Vec<Vector3<f64>>,
+    //Splats as (pixel, color) pairs, so a tile film doesn't carry the whole image around.
+    scattered: Vec<(u32, Vector3<f64>)>,
+    samples: u64,
+    //Sums of the features seen in each pixel and how many there were, empty unless the
+    //denoiser wants them.
+    features: Vec<Features>,
+    feature_counts: Vec<u32>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32, filter: Arc<dyn Filter>) -> Film {
+        Film::covering(0, 0, width, height, width, height, filter)
+    }
+
+    fn covering(x0: i64, y0: i64, width: u32, height: u32, image_width: u32, image_height: u32, filter: Arc<dyn Filter>) -> Film {
+        Film {
+            x0,
+            y0,
+            width,
+            height,
+            image_width,
+            image_height,
+            filter,
+            sums: vec![Vector3::zero(); (width*height) as usize],
+            weights: vec![0.0; (width*height) as usize],
+            splats: vec![],
+            scattered: vec![],
+            samples: 0,
+            features: vec![],
+            feature_counts: vec![],
+        }
+    }
+
+    //Empty film for rendering one tile of a width by height image into.
+    pub fn for_tile(tile: &Tile, width: u32, height: u32, filter: Arc<dyn Filter>) -> Film {
+        let reach = (filter.radius() - 0.5).max(0.0).ceil() as u32;
+        Film::covering(
+            tile.x0 as i64 - reach as i64,
+            tile.y0 as i64 - reach as i64,
+            tile.x1 - tile.x0 + 2*reach,
+            tile.y1 - tile.y0 + 2*reach,
+            width,
+            height,
+            filter
+        )
+    }
+
+    pub fn image_width(&self) -> u32 {
+        self.image_width
+    }
+
+    pub fn image_height(&self) -> u32 {
+        self.image_height
+    }
+
+    //Adds a sample taken at (x, y) in pixels to every pixel whose filter reaches it.
+    pub fn add_sample(&mut self, x: f64, y: f64, col: Vector3<f64>) {
+        let r = self.filter.radius();
+        let x_min = ((x - 0.5 - r).ceil() as i64).max(self.x0).max(0);
+        let x_max = ((x - 0.5 + r).floor() as i64).min(self.x0 + self.width as i64 - 1).min(self.image_width as i64 - 1);
+        let y_min = ((y - 0.5 - r).ceil() as i64).max(self.y0).max(0);
+        let y_max = ((y - 0.5 + r).floor() as i64).min(self.y0 + self.height as i64 - 1).min(self.image_height as i64 - 1);
+        for i in x_min..=x_max {
+            for j in y_min..=y_max {
+                let w = self.filter.eval(x - (i as f64 + 0.5), y - (j as f64 + 0.5));
+                if w != 0.0 {
+                    let k = ((j - self.y0)*(self.width as i64) + (i - self.x0)) as usize;
+                    self.sums[k] += col*w;
+                    self.weights[k] += w;
+                }
+            }
+        }
+    }
+
+    //Adds light to the pixel holding (u, v), which run from 0 to 1 across the image.
+    pub fn add_splat(&mut self, u: f64, v: f64, col: Vector3<f64>) {
+        let x = ((u*(self.image_width as f64)) as u32).min(self.image_width - 1);
+        let y = ((v*(self.image_height as f64)) as u32).min(self.image_height - 1);
+        self.splat(y*self.image_width + x, col);
+    }
+
+    fn splat(&mut self, k: u32, col: Vector3<f64>) {
+        if self.splats.is_empty() {
+            let image_pixels = (self.image_width*self.image_height) as usize;
+            let whole = self.x0 == 0 && self.y0 == 0 && self.width == self.image_width && self.height == self.image_height;
+            if !whole && self.scattered.len() < image_pixels {
+                self.scattered.push((k, col));
+                return;
+            }
+            //The list would outgrow the image, or this film is the image anyway.
+            self.splats = vec![Vector3::zero(); image_pixels];
+            for (k, col) in std::mem::take(&mut self.scattered) {
+                self.splats[k as usize] += col;
+            }
+        }
+        self.splats[k as usize] += col;
+    }
+
+    //Adds what was seen at (x, y) in pixels to the pixel it's in.
+    pub fn add_features(&mut self, x: f64, y: f64, features: &Features) {
+        let (i, j) = (x.floor() as i64 - self.x0, y.floor() as i64 - self.y0);
+        if i < 0 || j < 0 || i >= self.width as i64 || j >= self.height as i64 {
+            return;
+        }
+        if self.features.is_empty() {
+            self.features = vec![Features::zero(); (self.width*self.height) as usize];
+            self.feature_counts = vec![0; (self.width*self.height) as usize];
+        }
+        let k = (j*(self.width as i64) + i) as usize;
+        self.features[k].add(features);
+        self.feature_counts[k] += 1;
+    }
+
+    //Counts samples towards how finely splats get shared out.
+    pub fn add_samples(&mut self, samples: u64) {
+        self.samples += samples;
+    }
+
+    //Adds in everything a tile film gathered.
+    pub fn merge(&mut self, other: &Film) {
+        for j in 0..other.height as i64 {
+            for i in 0..other.width as i64 {
+                let (x, y) = (other.x0 + i - self.x0, other.y0 + j - self.y0);
+                if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+                    continue;
+                }
+                let from = (j*(other.width as i64) + i) as usize;
+                let to = (y*(self.width as i64) + x) as usize;
+                self.sums[to] += other.sums[from];
+                self.weights[to] += other.weights[from];
+                if !other.features.is_empty() {
+                    if self.features.is_empty() {
+                        self.features = vec![Features::zero(); (self.width*self.height) as usize];
+                        self.feature_counts = vec![0; (self.width*self.height) as usize];
+                    }
+                    self.features[to].add(&other.features[from]);
+                    self.feature_counts[to] += other.feature_counts[from];
+                }
+            }
+        }
+        for (k, splat) in other.splats.iter().enumerate() {
+            if *splat != Vector3::zero() {
+                self.splat(k as u32, *splat);
+            }
+        }
+        for &(k, splat) in other.scattered.iter() {
+            self.splat(k, splat);
+        }
+        self.samples += other.samples;
+    }
+
+    //Linear color of a pixel of the image, on a film covering the whole image.
+    pub fn pixel(&self, i: u32, j: u32) -> Vector3<f64> {
+        let k = (j*self.width + i) as usize;
+        let mut col = if self.weights[k] != 0.0 { self.sums[k]/self.weights[k] } else { Vector3::zero() };
+        if !self.splats.is_empty() {
+            let samples_per_pixel = (self.samples as f64/((self.image_width*self.image_height) as f64)).max(1.0);
+            col += self.splats[(j*self.image_width + i) as usize]/samples_per_pixel;
+        }
+        col
+    }
+
+    //Filter weight that landed on a pixel, which for a box filter is how many samples it took.
+    pub fn weight(&self, i: u32, j: u32) -> f64 {
+        self.weights[(j*self.width + i) as usize]
+    }
+
+    //Average features seen in a pixel, on a film covering the whole image.
+    pub fn features(&self, i: u32, j: u32) -> Features {
+        let k = (j*self.width + i) as usize;
+        if self.features.is_empty() || self.feature_counts[k] == 0 {
+            return Features::zero();
+        }
+        let n = self.feature_counts[k] as f64;
+        let sum = &self.features[k];
+        let normal = if sum.normal.magnitude2() > 0.0 { sum.normal.normalize() } else { Vector3::zero() };
+        Features { albedo: sum.albedo/n, normal, depth: sum.depth/n }
+    }
+
+    //Everything gathered so far, for a checkpoint.
+    pub fn write(&self, out: &mut Writer) {
+        out.vectors(&self.sums);
+        out.floats(&self.weights);
+        out.vectors(&self.splats);
+        out.floats(&self.scattered.iter().map(|&(k, _)| k as f64).collect::<Vec<_>>());
+        out.vectors(&self.scattered.iter().map(|&(_, col)| col).collect::<Vec<_>>());
+        out.u64(self.samples);
+        out.vectors(&self.features.iter().map(|f| f.albedo).collect::<Vec<_>>());
+        out.vectors(&self.features.iter().map(|f| f.normal).collect::<Vec<_>>());
+        out.floats(&self.features.iter().map(|f| f.depth).collect::<Vec<_>>());
+        out.floats(&self.feature_counts.iter().map(|&n| n as f64).collect::<Vec<_>>());
+    }
+
+    //Takes back what write() saved, on a film the same size.
+    pub fn read(&mut self, input: &mut Reader) -> Option<()> {
+        let pixels = (self.width*self.height) as usize;
+        let image_pixels = (self.image_width*self.image_height) as usize;
+        let (sums, weights, splats) = (input.vectors()?, input.floats()?, input.vectors()?);
+        let (scattered, scattered_colors) = (input.floats()?, input.vectors()?);
+        let samples = input.u64()?;
+        let (albedo, normal, depth) = (input.vectors()?, input.vectors()?, input.floats()?);
+        let counts = input.floats()?;
+        let has_features = !counts.is_empty();
+        if sums.len() != pixels || weights.len() != pixels
+            || (!splats.is_empty() && splats.len() != image_pixels)
+            || scattered.len() != scattered_colors.len() || scattered.iter().any(|&k| k < 0.0 || k as usize >= image_pixels)
+            || (has_features && (counts.len() != pixels || albedo.len() != pixels || normal.len() != pixels || depth.len() != pixels)) {
+            return None;
+        }
+        self.sums = sums;
+        self.weights = weights;
+        self.splats = splats;
+        self.scattered = scattered.iter().map(|&k| k as u32).zip(scattered_colors).collect();
+        self.samples = samples;
+        self.features = (0..albedo.len()).map(|k| Features { albedo: albedo[k], normal: normal[k], depth: depth[k] }).collect();
+        self.feature_counts = counts.iter().map(|&n| n as u32).collect();
+        Some(())
+    }
+
+    //Every pixel of the image, row by row.
+    pub fn pixels(&self) -> Vec<Vector3<f64>> {
+        let mut pixels = Vec::with_capacity((self.image_width*self.image_height) as usize);
+        for j in 0..self.image_height {
+            for i in 0..self.image_width {
+                pixels.push(self.pixel(i, j));
+            }
+        }
+        pixels
+    }
+}
+
+//Writes linear pixels, row by row, out as an 8 bit image.
+pub fn save(path: &str, width: u32, height: u32, pixels: &[Vector3<f64>]) -> std::io::Result<()> {
+    let mut img = image::DynamicImage::new_rgb8(width, height);
+    for j in 0..height {
+        for i in 0..width {
+            let mut col = pixels[(j*width + i) as usize];
+            //col = Vector3::new(col.x.sqrt(), col.y.sqrt(), col.z.sqrt());
+            col.x = col.x.clamp(0.0, 1.0);
+            col.y = col.y.clamp(0.0, 1.0);
+            col.z = col.z.clamp(0.0, 1.0);
+
+            let mut rgba = [0u8;4];
+            rgba[0] = (255.99*col.x) as u8;
+            rgba[1] = (255.99*col.y) as u8;
+            rgba[2] = (255.99*col.z) as u8;
+            rgba[3] = 1;
+            img.put_pixel(i, j, image::Rgba(rgba));
+        }
+    }
+    img.save(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::random;
+
+    const NAMES: [&str; 5] = ["box", "tent", "gaussian", "mitchell", "lanczos"];
+
+    fn filter(name: &str) -> Arc<dyn Filter> {
+        by_name(&FilterSettings { name: name.to_string(), radius: 0.0 })
+    }
+
+    //Samples spread evenly over an image, the same ones whichever film they go to.
+    fn samples(width: u32, height: u32) -> Vec<(f64, f64, Vector3<f64>)> {
+        random::with_stream(Box::new(random::Seeded::new(4)), || {
+            (0..width*height*16).map(|_| {
+                let (x, y) = (random::gen()*(width as f64), random::gen()*(height as f64));
+                (x, y, Vector3::new(x, y, 1.0))
+            }).collect()
+        })
+    }
+
+    #[test]
+    fn filters_peak_in_the_middle_and_end_at_their_radius() {
+        for name in NAMES.iter() {
+            let f = filter(name);
+            let r = f.radius();
+            assert!(f.eval(0.0, 0.0) > 0.0, "{}", name);
+            assert_eq!(f.eval(r + 1e-6, 0.0), 0.0, "{}", name);
+            assert_eq!(f.eval(0.0, -r - 1e-6), 0.0, "{}", name);
+            assert!((f.eval(0.3, -0.2) - f.eval(-0.3, 0.2)).abs() < 1e-12, "{}", name);
+        }
+    }
+
+    #[test]
+    fn flat_color_comes_out_flat() {
+        //Weights are divided back out, so however a filter is shaped a constant stays put.
+        for name in NAMES.iter() {
+            let mut film = Film::new(6, 4, filter(name));
+            for (x, y, _) in samples(6, 4) {
+                film.add_sample(x, y, Vector3::new(0.25, 0.5, 1.0));
+            }
+            for p in film.pixels() {
+                assert!((p - Vector3::new(0.25, 0.5, 1.0)).magnitude() < 1e-9, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn tile_films_add_up_to_the_whole_image() {
+        for name in NAMES.iter() {
+            let mut whole = Film::new(8, 6, filter(name));
+            let mut merged = Film::new(8, 6, filter(name));
+            let tiles = [Tile { x0: 0, y0: 0, x1: 5, y1: 6 }, Tile { x0: 5, y0: 0, x1: 8, y1: 6 }];
+            let mut films: Vec<Film> = tiles.iter().map(|t| Film::for_tile(t, 8, 6, filter(name))).collect();
+            for (x, y, col) in samples(8, 6) {
+                whole.add_sample(x, y, col);
+                let k = if x < 5.0 { 0 } else { 1 };
+                films[k].add_sample(x, y, col);
+            }
+            for film in &films {
+                merged.merge(film);
+            }
+            for (a, b) in whole.pixels().iter().zip(merged.pixels().iter()) {
+                assert!((a - b).magnitude() < 1e-9, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn tile_splats_stay_small_and_add_up() {
+        let tile = Tile { x0: 0, y0: 0, x1: 2, y1: 2 };
+        let mut whole = Film::new(8, 6, filter("box"));
+        let (mut few, mut many) = (Film::for_tile(&tile, 8, 6, filter("box")), Film::for_tile(&tile, 8, 6, filter("box")));
+        let splats = samples(8, 6);
+        for (n, &(x, y, col)) in splats.iter().enumerate() {
+            whole.add_splat(x/8.0, y/6.0, col);
+            if n < 10 { few.add_splat(x/8.0, y/6.0, col) } else { many.add_splat(x/8.0, y/6.0, col) }
+        }
+        assert!(few.splats.is_empty() && few.scattered.len() == 10);
+        assert!(many.scattered.is_empty() && many.splats.len() == 48);
+        let mut merged = Film::new(8, 6, filter("box"));
+        merged.merge(&few);
+        merged.merge(&many);
+        for (a, b) in whole.splats.iter().zip(merged.splats.iter()) {
+            assert!((a - b).magnitude() < 1e-9);
+        }
+    }
+}
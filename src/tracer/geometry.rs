@@ -1,16 +1,38 @@
 use super::ray::*;
 use cgmath::*;
-use rand::*;
+use super::random;
 use std::sync::Arc;
 use std::collections::HashMap as Map;
 
 pub trait Hitable: Send + Sync {
     fn hit(&self, r: &Ray, t_max: f64) -> f64;
     fn get_center(&self) -> &Vector3<f64>;
+    #[allow(dead_code)]
     fn get_radius(&self) -> f64;
     fn get_color(&self) -> &Vector3<f64>;
     fn get_material(&self) -> String;
-    fn get_norm_at_p(&self, p: &Vector3<f64>) -> Vector3<f64>;
+    //The ray is passed along so moving geometry can find where it was at the ray's time.
+    fn get_norm_at_p(&self, r: &Ray, p: &Vector3<f64>) -> Vector3<f64>;
+    //Surface coordinates in [0, 1] for the point p.
+    fn get_uv_at_p(&self, r: &Ray, p: &Vector3<f64>) -> (f64, f64);
+    //Sorted spans of t where the ray is inside the solid. Shapes that don't enclose a
+    //volume return nothing, so they can't take part in CSG.
+    fn hit_intervals(&self, _: &Ray) -> Vec<(f64, f64)> {
+        vec![]
+    }
+    //Whether the shape has an inside for hit_intervals to report, so it can hold a medium.
+    //Planes count, with everything behind them inside.
+    fn is_closed(&self) -> bool {
+        false
+    }
+    //Surface area, zero for shapes that can't be sampled as area lights.
+    fn area(&self) -> f64 {
+        0.0
+    }
+    //Point and normal picked evenly over the surface where it is at the given time.
+    fn sample_surface(&self, _: f64) -> Option<(Vector3<f64>, Vector3<f64>)> {
+        None
+    }
 }
 
 pub struct Plane {
@@ -54,28 +76,60 @@ impl Hitable for Plane {
         self.mat.clone()
     }
 
-    fn get_norm_at_p(&self, _: &Vector3<f64>) -> Vector3<f64> {
+    fn get_norm_at_p(&self, _: &Ray, _: &Vector3<f64>) -> Vector3<f64> {
         self.normal
     }
+
+    //Tiles once per unit along the plane.
+    fn get_uv_at_p(&self, _: &Ray, p: &Vector3<f64>) -> (f64, f64) {
+        let (u, v) = tangents(&self.normal);
+        let d = p - self.origin;
+        (d.dot(u).rem_euclid(1.0), d.dot(v).rem_euclid(1.0))
+    }
+
+    //Everything behind the plane counts as inside.
+    fn hit_intervals(&self, r: &Ray) -> Vec<(f64, f64)> {
+        let denom = r.direction().dot(self.normal);
+        let height = (r.origin() - self.origin).dot(self.normal);
+        if denom.abs() < 1e-12 {
+            return if height < 0.0 { vec![(f64::MIN, f64::MAX)] } else { vec![] };
+        }
+        let t = -height/denom;
+        if denom > 0.0 { vec![(f64::MIN, t)] } else { vec![(t, f64::MAX)] }
+    }
+
+    fn is_closed(&self) -> bool {
+        true
+    }
 }
 
 pub struct Sphere {
     color: Vector3<f64>,
     radius: f64,
     center: Vector3<f64>,
+    motion: Vector3<f64>,
     mat: String,
 }
 
 impl Sphere {
     pub fn new(col: Vector3<f64>, r: f64, c: Vector3<f64>, m: String) -> Sphere {
-        Sphere { color: col, radius: r, center: c, mat: m }
+        Sphere { color: col, radius: r, center: c, motion: Vector3::new(0.0, 0.0, 0.0), mat: m }
+    }
+
+    //Sphere travelling in a straight line from c0 at time 0 to c1 at time 1.
+    pub fn new_moving(col: Vector3<f64>, r: f64, c0: Vector3<f64>, c1: Vector3<f64>, m: String) -> Sphere {
+        Sphere { color: col, radius: r, center: c0, motion: c1 - c0, mat: m }
+    }
+
+    fn center_at(&self, time: f64) -> Vector3<f64> {
+        self.center + time*self.motion
     }
 }
 
 //https://en.wikipedia.org/wiki/Line%E2%80%93sphere_intersection
 impl Hitable for Sphere {
     fn hit(&self, r: &Ray, t_max: f64) -> f64 {
-        let oc = r.origin() - self.center;
+        let oc = r.origin() - self.center_at(r.time());
         let a = r.direction().dot(*r.direction());
         let b = oc.dot(*r.direction());
         let c = oc.dot(oc) - (self.radius*self.radius);
@@ -107,29 +161,842 @@ impl Hitable for Sphere {
         self.mat.clone()
     }
 
-    fn get_norm_at_p(&self, p: &Vector3<f64>) -> Vector3<f64> {
-        let n = p - self.center;
+    fn get_norm_at_p(&self, r: &Ray, p: &Vector3<f64>) -> Vector3<f64> {
+        let n = p - self.center_at(r.time());
         n/n.magnitude()
     }
+
+    fn get_uv_at_p(&self, r: &Ray, p: &Vector3<f64>) -> (f64, f64) {
+        let n = self.get_norm_at_p(r, p);
+        let phi = (-n.z).atan2(n.x) + std::f64::consts::PI;
+        let theta = (-n.y).clamp(-1.0, 1.0).acos();
+        (phi/(2.0*std::f64::consts::PI), theta/std::f64::consts::PI)
+    }
+
+    fn hit_intervals(&self, r: &Ray) -> Vec<(f64, f64)> {
+        let oc = r.origin() - self.center_at(r.time());
+        let a = r.direction().dot(*r.direction());
+        let b = oc.dot(*r.direction());
+        let c = oc.dot(oc) - (self.radius*self.radius);
+        let discriminant = b*b - a*c;
+        if discriminant < 0.0 {
+            return vec![];
+        }
+        vec![((-b - discriminant.sqrt())/a, (-b + discriminant.sqrt())/a)]
+    }
+
+    fn is_closed(&self) -> bool {
+        true
+    }
+
+    fn area(&self) -> f64 {
+        4.0*std::f64::consts::PI*self.radius*self.radius
+    }
+
+    fn sample_surface(&self, time: f64) -> Option<(Vector3<f64>, Vector3<f64>)> {
+        let n = rand_usphere();
+        Some((self.center_at(time) + self.radius*n, n))
+    }
 }
 
-pub fn rand_usphere() -> Vector3<f64> {
-    let mut rng = rand::thread_rng();
-    let mut p: Vector3<f64> = 2.0 * Vector3::new(rng.gen(), rng.gen(), rng.gen()) - Vector3::new(1.0, 1.0, 1.0);
-    
-    while p.dot(p) >= 1.0 {
-        p = 2.0 * Vector3::new(rng.gen(), rng.gen(), rng.gen()) - Vector3::new(1.0, 1.0, 1.0);
+//Wraps any hitable and slides it along a velocity over the shutter interval.
+pub struct Moving {
+    inner: Arc<dyn Hitable>,
+    velocity: Vector3<f64>,
+}
+
+impl Moving {
+    pub fn new(inner: Arc<dyn Hitable>, velocity: Vector3<f64>) -> Moving {
+        Moving { inner, velocity }
+    }
+
+    //Moving the object by +offset is the same as moving the ray by -offset.
+    fn local_ray(&self, r: &Ray) -> Ray {
+        Ray::new_at(r.origin() - r.time()*self.velocity, *r.direction(), r.time())
+    }
+}
+
+impl Hitable for Moving {
+    fn hit(&self, r: &Ray, t_max: f64) -> f64 {
+        self.inner.hit(&self.local_ray(r), t_max)
+    }
+
+    fn get_center(&self) -> &Vector3<f64> {
+        self.inner.get_center()
+    }
+
+    fn get_radius(&self) -> f64 {
+        self.inner.get_radius()
+    }
+
+    fn get_color(&self) -> &Vector3<f64> {
+        self.inner.get_color()
+    }
+
+    fn get_material(&self) -> String {
+        self.inner.get_material()
+    }
+
+    fn get_norm_at_p(&self, r: &Ray, p: &Vector3<f64>) -> Vector3<f64> {
+        self.inner.get_norm_at_p(&self.local_ray(r), &(p - r.time()*self.velocity))
+    }
+
+    fn get_uv_at_p(&self, r: &Ray, p: &Vector3<f64>) -> (f64, f64) {
+        self.inner.get_uv_at_p(&self.local_ray(r), &(p - r.time()*self.velocity))
+    }
+
+    fn hit_intervals(&self, r: &Ray) -> Vec<(f64, f64)> {
+        self.inner.hit_intervals(&self.local_ray(r))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    fn area(&self) -> f64 {
+        self.inner.area()
+    }
+
+    fn sample_surface(&self, time: f64) -> Option<(Vector3<f64>, Vector3<f64>)> {
+        self.inner.sample_surface(time).map(|(p, n)| (p + time*self.velocity, n))
+    }
+}
+
+//Orthonormal frame used to intersect shapes in a canonical position, with the axis along +y.
+struct Frame {
+    origin: Vector3<f64>,
+    u: Vector3<f64>,
+    axis: Vector3<f64>,
+    v: Vector3<f64>,
+}
+
+impl Frame {
+    fn new(origin: Vector3<f64>, axis: Vector3<f64>) -> Frame {
+        let axis = axis.normalize();
+        let (u, v) = tangents(&axis);
+        Frame { origin, u, axis, v }
+    }
+
+    fn to_local(&self, p: &Vector3<f64>) -> Vector3<f64> {
+        let d = p - self.origin;
+        Vector3::new(d.dot(self.u), d.dot(self.axis), d.dot(self.v))
+    }
+
+    fn dir_to_local(&self, d: &Vector3<f64>) -> Vector3<f64> {
+        Vector3::new(d.dot(self.u), d.dot(self.axis), d.dot(self.v))
+    }
+
+    fn dir_to_world(&self, d: &Vector3<f64>) -> Vector3<f64> {
+        d.x*self.u + d.y*self.axis + d.z*self.v
+    }
+}
+
+//Two unit vectors perpendicular to n and to each other.
+pub fn tangents(n: &Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+    let helper = if n.x.abs() > 0.9 { Vector3::new(0.0, 1.0, 0.0) } else { Vector3::new(1.0, 0.0, 0.0) };
+    let u = helper.cross(*n).normalize();
+    let v = n.normalize().cross(u);
+    (u, v)
+}
+
+//Convex shapes are inside between their first and last crossing.
+fn convex_span(ts: &[f64]) -> Vec<(f64, f64)> {
+    if ts.len() < 2 {
+        return vec![];
+    }
+    let t0 = ts.iter().cloned().fold(f64::MAX, f64::min);
+    let t1 = ts.iter().cloned().fold(f64::MIN, f64::max);
+    vec![(t0, t1)]
+}
+
+fn nearest_valid(ts: &[f64], t_max: f64) -> f64 {
+    let mut best = 0.0;
+    for &t in ts {
+        if t > 0.001 && t < t_max && (best == 0.0 || t < best) {
+            best = t;
+        }
+    }
+    best
+}
+
+//Box with faces aligned to the axes. Rotate it with a transform to get an oriented box.
+pub struct AaBox {
+    min: Vector3<f64>,
+    max: Vector3<f64>,
+    center: Vector3<f64>,
+    color: Vector3<f64>,
+    mat: String,
+}
+
+impl AaBox {
+    pub fn new(min: Vector3<f64>, max: Vector3<f64>, col: Vector3<f64>, m: String) -> AaBox {
+        AaBox { min, max, center: (min + max)/2.0, color: col, mat: m }
+    }
+
+    //Entry and exit distances through the slabs, if the ray crosses the box at all.
+    fn slabs(&self, r: &Ray) -> Option<(f64, f64)> {
+        let mut t0 = f64::MIN;
+        let mut t1 = f64::MAX;
+        for i in 0..3 {
+            let inv = 1.0/r.direction()[i];
+            let mut near = (self.min[i] - r.origin()[i])*inv;
+            let mut far = (self.max[i] - r.origin()[i])*inv;
+            if inv < 0.0 {
+                std::mem::swap(&mut near, &mut far);
+            }
+            t0 = t0.max(near);
+            t1 = t1.min(far);
+            if t1 < t0 {
+                return None;
+            }
+        }
+        Some((t0, t1))
+    }
+
+    //Index of the axis whose face p lies on, and which side of it.
+    fn face(&self, p: &Vector3<f64>) -> (usize, f64) {
+        let half = (self.max - self.min)/2.0;
+        let d = p - self.center;
+        let mut axis = 0;
+        let mut best = f64::MAX;
+        for i in 0..3 {
+            let gap = (half[i] - d[i].abs()).abs();
+            if gap < best {
+                best = gap;
+                axis = i;
+            }
+        }
+        (axis, d[axis].signum())
+    }
+}
+
+impl Hitable for AaBox {
+    fn hit(&self, r: &Ray, t_max: f64) -> f64 {
+        match self.slabs(r) {
+            Some((t0, t1)) => nearest_valid(&[t0, t1], t_max),
+            None => 0.0
+        }
+    }
+
+    fn get_center(&self) -> &Vector3<f64> {
+        &self.center
+    }
+
+    fn get_radius(&self) -> f64 {
+        (self.max - self.center).magnitude()
+    }
+
+    fn get_color(&self) -> &Vector3<f64> {
+        &self.color
+    }
+
+    fn get_material(&self) -> String {
+        self.mat.clone()
+    }
+
+    fn get_norm_at_p(&self, _: &Ray, p: &Vector3<f64>) -> Vector3<f64> {
+        let (axis, side) = self.face(p);
+        let mut n = Vector3::new(0.0, 0.0, 0.0);
+        n[axis] = side;
+        n
+    }
+
+    fn get_uv_at_p(&self, _: &Ray, p: &Vector3<f64>) -> (f64, f64) {
+        let (axis, _) = self.face(p);
+        let size = self.max - self.min;
+        let local = p - self.min;
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        (local[a]/size[a], local[b]/size[b])
+    }
+
+    fn hit_intervals(&self, r: &Ray) -> Vec<(f64, f64)> {
+        self.slabs(r).into_iter().collect()
+    }
+
+    fn is_closed(&self) -> bool {
+        true
+    }
+}
+
+//Cylinder capped at both ends, running from base to base + axis.
+pub struct Cylinder {
+    frame: Frame,
+    radius: f64,
+    height: f64,
+    center: Vector3<f64>,
+    color: Vector3<f64>,
+    mat: String,
+}
+
+impl Cylinder {
+    pub fn new(base: Vector3<f64>, axis: Vector3<f64>, radius: f64, col: Vector3<f64>, m: String) -> Cylinder {
+        Cylinder {
+            frame: Frame::new(base, axis),
+            radius,
+            height: axis.magnitude(),
+            center: base + axis/2.0,
+            color: col,
+            mat: m
+        }
+    }
+
+    //Every parameter where the ray crosses the side wall or a cap, in local space.
+    fn crossings(&self, r: &Ray) -> Vec<f64> {
+        let o = self.frame.to_local(r.origin());
+        let d = self.frame.dir_to_local(r.direction());
+        let mut ts = vec![];
+
+        let a = d.x*d.x + d.z*d.z;
+        let b = o.x*d.x + o.z*d.z;
+        let c = o.x*o.x + o.z*o.z - self.radius*self.radius;
+        let discriminant = b*b - a*c;
+        if a > 0.0 && discriminant >= 0.0 {
+            for t in [(-b - discriminant.sqrt())/a, (-b + discriminant.sqrt())/a].iter() {
+                let y = o.y + t*d.y;
+                if y >= 0.0 && y <= self.height {
+                    ts.push(*t);
+                }
+            }
+        }
+
+        if d.y != 0.0 {
+            for cap in [0.0, self.height].iter() {
+                let t = (cap - o.y)/d.y;
+                let x = o.x + t*d.x;
+                let z = o.z + t*d.z;
+                if x*x + z*z <= self.radius*self.radius {
+                    ts.push(t);
+                }
+            }
+        }
+        ts
     }
-    p
 }
 
+impl Hitable for Cylinder {
+    fn hit(&self, r: &Ray, t_max: f64) -> f64 {
+        nearest_valid(&self.crossings(r), t_max)
+    }
+
+    fn get_center(&self) -> &Vector3<f64> {
+        &self.center
+    }
+
+    fn get_radius(&self) -> f64 {
+        (self.radius*self.radius + self.height*self.height/4.0).sqrt()
+    }
+
+    fn get_color(&self) -> &Vector3<f64> {
+        &self.color
+    }
+
+    fn get_material(&self) -> String {
+        self.mat.clone()
+    }
+
+    fn get_norm_at_p(&self, _: &Ray, p: &Vector3<f64>) -> Vector3<f64> {
+        let l = self.frame.to_local(p);
+        let side = (l.x*l.x + l.z*l.z).sqrt();
+        if (side - self.radius).abs() < l.y.abs().min((l.y - self.height).abs()) {
+            return self.frame.dir_to_world(&Vector3::new(l.x, 0.0, l.z)).normalize();
+        }
+        if l.y < self.height/2.0 { -self.frame.axis } else { self.frame.axis }
+    }
+
+    fn get_uv_at_p(&self, _: &Ray, p: &Vector3<f64>) -> (f64, f64) {
+        let l = self.frame.to_local(p);
+        let phi = l.z.atan2(l.x) + std::f64::consts::PI;
+        (phi/(2.0*std::f64::consts::PI), (l.y/self.height).clamp(0.0, 1.0))
+    }
+
+    fn hit_intervals(&self, r: &Ray) -> Vec<(f64, f64)> {
+        convex_span(&self.crossings(r))
+    }
+
+    fn is_closed(&self) -> bool {
+        true
+    }
+}
+
+//Cone with base_radius at base narrowing to top_radius at base + axis. A top radius of
+//zero gives a pointed cone, anything else a frustum. Both ends are capped.
+pub struct Cone {
+    frame: Frame,
+    base_radius: f64,
+    top_radius: f64,
+    height: f64,
+    center: Vector3<f64>,
+    color: Vector3<f64>,
+    mat: String,
+}
+
+impl Cone {
+    pub fn new(base: Vector3<f64>, axis: Vector3<f64>, base_radius: f64, top_radius: f64, col: Vector3<f64>, m: String) -> Cone {
+        Cone {
+            frame: Frame::new(base, axis),
+            base_radius,
+            top_radius,
+            height: axis.magnitude(),
+            center: base + axis/2.0,
+            color: col,
+            mat: m
+        }
+    }
+
+    fn slope(&self) -> f64 {
+        (self.top_radius - self.base_radius)/self.height
+    }
+
+    fn crossings(&self, r: &Ray) -> Vec<f64> {
+        let o = self.frame.to_local(r.origin());
+        let d = self.frame.dir_to_local(r.direction());
+        let k = self.slope();
+        let mut ts = vec![];
+
+        //x^2 + z^2 = (base_radius + k*y)^2 along the ray.
+        let ro = self.base_radius + k*o.y;
+        let a = d.x*d.x + d.z*d.z - k*k*d.y*d.y;
+        let b = o.x*d.x + o.z*d.z - k*d.y*ro;
+        let c = o.x*o.x + o.z*o.z - ro*ro;
+        let discriminant = b*b - a*c;
+        if a.abs() > 1e-12 && discriminant >= 0.0 {
+            for t in [(-b - discriminant.sqrt())/a, (-b + discriminant.sqrt())/a].iter() {
+                let y = o.y + t*d.y;
+                if y >= 0.0 && y <= self.height {
+                    ts.push(*t);
+                }
+            }
+        } else if a.abs() <= 1e-12 && b != 0.0 {
+            let t = -c/(2.0*b);
+            let y = o.y + t*d.y;
+            if y >= 0.0 && y <= self.height {
+                ts.push(t);
+            }
+        }
+
+        if d.y != 0.0 {
+            for (cap, radius) in [(0.0, self.base_radius), (self.height, self.top_radius)].iter() {
+                let t = (cap - o.y)/d.y;
+                let x = o.x + t*d.x;
+                let z = o.z + t*d.z;
+                if x*x + z*z <= radius*radius {
+                    ts.push(t);
+                }
+            }
+        }
+        ts
+    }
+}
+
+impl Hitable for Cone {
+    fn hit(&self, r: &Ray, t_max: f64) -> f64 {
+        nearest_valid(&self.crossings(r), t_max)
+    }
+
+    fn get_center(&self) -> &Vector3<f64> {
+        &self.center
+    }
+
+    fn get_radius(&self) -> f64 {
+        let r = self.base_radius.max(self.top_radius);
+        (r*r + self.height*self.height/4.0).sqrt()
+    }
+
+    fn get_color(&self) -> &Vector3<f64> {
+        &self.color
+    }
+
+    fn get_material(&self) -> String {
+        self.mat.clone()
+    }
+
+    fn get_norm_at_p(&self, _: &Ray, p: &Vector3<f64>) -> Vector3<f64> {
+        let l = self.frame.to_local(p);
+        let side = (l.x*l.x + l.z*l.z).sqrt();
+        let wall = self.base_radius + self.slope()*l.y;
+        let to_cap = l.y.abs().min((l.y - self.height).abs());
+        if (side - wall).abs() < to_cap && side > 0.0 {
+            //Tilt the radial direction by the slope of the wall.
+            let radial = Vector3::new(l.x/side, 0.0, l.z/side);
+            let n = radial + Vector3::new(0.0, -self.slope(), 0.0);
+            return self.frame.dir_to_world(&n).normalize();
+        }
+        if l.y < self.height/2.0 { -self.frame.axis } else { self.frame.axis }
+    }
+
+    fn get_uv_at_p(&self, _: &Ray, p: &Vector3<f64>) -> (f64, f64) {
+        let l = self.frame.to_local(p);
+        let phi = l.z.atan2(l.x) + std::f64::consts::PI;
+        (phi/(2.0*std::f64::consts::PI), (l.y/self.height).clamp(0.0, 1.0))
+    }
+
+    fn hit_intervals(&self, r: &Ray) -> Vec<(f64, f64)> {
+        convex_span(&self.crossings(r))
+    }
+
+    fn is_closed(&self) -> bool {
+        true
+    }
+}
+
+//Flat round disk facing along its normal.
+pub struct Disk {
+    frame: Frame,
+    radius: f64,
+    color: Vector3<f64>,
+    mat: String,
+}
+
+impl Disk {
+    pub fn new(center: Vector3<f64>, normal: Vector3<f64>, radius: f64, col: Vector3<f64>, m: String) -> Disk {
+        Disk { frame: Frame::new(center, normal), radius, color: col, mat: m }
+    }
+}
+
+impl Hitable for Disk {
+    fn hit(&self, r: &Ray, t_max: f64) -> f64 {
+        let o = self.frame.to_local(r.origin());
+        let d = self.frame.dir_to_local(r.direction());
+        if d.y.abs() < 1e-9 {
+            return 0.0;
+        }
+        let t = -o.y/d.y;
+        let x = o.x + t*d.x;
+        let z = o.z + t*d.z;
+        if x*x + z*z > self.radius*self.radius {
+            return 0.0;
+        }
+        nearest_valid(&[t], t_max)
+    }
+
+    fn get_center(&self) -> &Vector3<f64> {
+        &self.frame.origin
+    }
+
+    fn get_radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn get_color(&self) -> &Vector3<f64> {
+        &self.color
+    }
+
+    fn get_material(&self) -> String {
+        self.mat.clone()
+    }
+
+    fn get_norm_at_p(&self, _: &Ray, _: &Vector3<f64>) -> Vector3<f64> {
+        self.frame.axis
+    }
+
+    //Angle around the disk and distance from its center.
+    fn get_uv_at_p(&self, _: &Ray, p: &Vector3<f64>) -> (f64, f64) {
+        let l = self.frame.to_local(p);
+        let phi = l.z.atan2(l.x) + std::f64::consts::PI;
+        (phi/(2.0*std::f64::consts::PI), ((l.x*l.x + l.z*l.z).sqrt()/self.radius).min(1.0))
+    }
+
+    fn area(&self) -> f64 {
+        std::f64::consts::PI*self.radius*self.radius
+    }
+
+    fn sample_surface(&self, _: f64) -> Option<(Vector3<f64>, Vector3<f64>)> {
+        let r = self.radius*random::gen().sqrt();
+        let phi = 2.0*std::f64::consts::PI*random::gen();
+        let p = self.frame.origin + self.frame.dir_to_world(&Vector3::new(r*phi.cos(), 0.0, r*phi.sin()));
+        Some((p, self.frame.axis))
+    }
+}
+
+//Parallelogram spanned by two edges from a corner, facing along edge_u x edge_v.
+pub struct Rectangle {
+    corner: Vector3<f64>,
+    edge_u: Vector3<f64>,
+    edge_v: Vector3<f64>,
+    normal: Vector3<f64>,
+    center: Vector3<f64>,
+    color: Vector3<f64>,
+    mat: String,
+}
+
+impl Rectangle {
+    pub fn new(corner: Vector3<f64>, edge_u: Vector3<f64>, edge_v: Vector3<f64>, col: Vector3<f64>, m: String) -> Rectangle {
+        Rectangle {
+            corner,
+            edge_u,
+            edge_v,
+            normal: edge_u.cross(edge_v).normalize(),
+            center: corner + (edge_u + edge_v)/2.0,
+            color: col,
+            mat: m
+        }
+    }
+
+    //Position of p along each edge, as a fraction of the edge.
+    fn params(&self, p: &Vector3<f64>) -> (f64, f64) {
+        let d = p - self.corner;
+        let uu = self.edge_u.dot(self.edge_u);
+        let uv = self.edge_u.dot(self.edge_v);
+        let vv = self.edge_v.dot(self.edge_v);
+        let du = d.dot(self.edge_u);
+        let dv = d.dot(self.edge_v);
+        let det = uu*vv - uv*uv;
+        ((vv*du - uv*dv)/det, (uu*dv - uv*du)/det)
+    }
+}
+
+impl Hitable for Rectangle {
+    fn hit(&self, r: &Ray, t_max: f64) -> f64 {
+        let denom = r.direction().dot(self.normal);
+        if denom.abs() < 1e-9 {
+            return 0.0;
+        }
+        let t = (self.corner - r.origin()).dot(self.normal)/denom;
+        let (a, b) = self.params(&r.point_at_parameter(t));
+        if !(0.0..=1.0).contains(&a) || !(0.0..=1.0).contains(&b) {
+            return 0.0;
+        }
+        nearest_valid(&[t], t_max)
+    }
+
+    fn get_center(&self) -> &Vector3<f64> {
+        &self.center
+    }
+
+    fn get_radius(&self) -> f64 {
+        (self.edge_u + self.edge_v).magnitude()/2.0
+    }
+
+    fn get_color(&self) -> &Vector3<f64> {
+        &self.color
+    }
+
+    fn get_material(&self) -> String {
+        self.mat.clone()
+    }
+
+    fn get_norm_at_p(&self, _: &Ray, _: &Vector3<f64>) -> Vector3<f64> {
+        self.normal
+    }
+
+    fn get_uv_at_p(&self, _: &Ray, p: &Vector3<f64>) -> (f64, f64) {
+        let (a, b) = self.params(p);
+        (a.clamp(0.0, 1.0), b.clamp(0.0, 1.0))
+    }
+
+    fn area(&self) -> f64 {
+        self.edge_u.cross(self.edge_v).magnitude()
+    }
+
+    fn sample_surface(&self, _: f64) -> Option<(Vector3<f64>, Vector3<f64>)> {
+        Some((self.corner + random::gen()*self.edge_u + random::gen()*self.edge_v, self.normal))
+    }
+}
+
+//Ring torus around the axis through center, tube of minor_radius swept at major_radius.
+pub struct Torus {
+    frame: Frame,
+    major_radius: f64,
+    minor_radius: f64,
+    color: Vector3<f64>,
+    mat: String,
+}
+
+impl Torus {
+    pub fn new(center: Vector3<f64>, axis: Vector3<f64>, major_radius: f64, minor_radius: f64, col: Vector3<f64>, m: String) -> Torus {
+        Torus { frame: Frame::new(center, axis), major_radius, minor_radius, color: col, mat: m }
+    }
+
+    fn crossings(&self, r: &Ray) -> Vec<f64> {
+        //Start the ray from its closest approach to the center so the quartic stays well conditioned.
+        let o = self.frame.to_local(r.origin());
+        let d = self.frame.dir_to_local(r.direction());
+        let dd = d.dot(d);
+        let shift = -o.dot(d)/dd;
+        let o = o + shift*d;
+
+        let rr = self.major_radius*self.major_radius;
+        let alpha = dd;
+        let beta = 2.0*o.dot(d);
+        let gamma = o.dot(o) + rr - self.minor_radius*self.minor_radius;
+
+        //(|p|^2 + R^2 - r^2)^2 = 4R^2(x^2 + z^2)
+        let coefficients = [
+            gamma*gamma - 4.0*rr*(o.x*o.x + o.z*o.z),
+            2.0*beta*gamma - 8.0*rr*(o.x*d.x + o.z*d.z),
+            beta*beta + 2.0*alpha*gamma - 4.0*rr*(d.x*d.x + d.z*d.z),
+            2.0*alpha*beta,
+            alpha*alpha
+        ];
+        solve_quartic(&coefficients).into_iter().map(|t| t + shift).collect()
+    }
+}
+
+impl Hitable for Torus {
+    fn hit(&self, r: &Ray, t_max: f64) -> f64 {
+        nearest_valid(&self.crossings(r), t_max)
+    }
+
+    fn get_center(&self) -> &Vector3<f64> {
+        &self.frame.origin
+    }
+
+    fn get_radius(&self) -> f64 {
+        self.major_radius + self.minor_radius
+    }
+
+    fn get_color(&self) -> &Vector3<f64> {
+        &self.color
+    }
+
+    fn get_material(&self) -> String {
+        self.mat.clone()
+    }
+
+    fn get_norm_at_p(&self, _: &Ray, p: &Vector3<f64>) -> Vector3<f64> {
+        let l = self.frame.to_local(p);
+        let ring = Vector3::new(l.x, 0.0, l.z);
+        let ring = if ring.magnitude() > 0.0 { ring.normalize()*self.major_radius } else { ring };
+        self.frame.dir_to_world(&(l - ring)).normalize()
+    }
+
+    //Angle around the axis and angle around the tube.
+    fn get_uv_at_p(&self, _: &Ray, p: &Vector3<f64>) -> (f64, f64) {
+        let l = self.frame.to_local(p);
+        let two_pi = 2.0*std::f64::consts::PI;
+        let phi = l.z.atan2(l.x) + std::f64::consts::PI;
+        let theta = l.y.atan2((l.x*l.x + l.z*l.z).sqrt() - self.major_radius) + std::f64::consts::PI;
+        (phi/two_pi, theta/two_pi)
+    }
+
+    //A ray can pass through the tube twice, so pair the crossings up in order.
+    fn hit_intervals(&self, r: &Ray) -> Vec<(f64, f64)> {
+        let mut ts: Vec<f64> = self.crossings(r).into_iter().filter(|t| !t.is_nan()).collect();
+        ts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        ts.dedup_by(|a, b| (*a - *b).abs() < 1e-9);
+        ts.chunks_exact(2).map(|pair| (pair[0], pair[1])).collect()
+    }
+
+    fn is_closed(&self) -> bool {
+        true
+    }
+}
+
+//Polynomial root finding after Jochen Schwarze's "Cubic and Quartic Roots" in Graphics Gems.
+//Coefficients are given lowest power first.
+fn solve_quadric(c: &[f64; 3]) -> Vec<f64> {
+    let p = c[1]/(2.0*c[2]);
+    let q = c[0]/c[2];
+    let d = p*p - q;
+    if d.abs() < 1e-9 {
+        vec![-p]
+    } else if d < 0.0 {
+        vec![]
+    } else {
+        let sqrt_d = d.sqrt();
+        vec![sqrt_d - p, -sqrt_d - p]
+    }
+}
+
+fn solve_cubic(c: &[f64; 4]) -> Vec<f64> {
+    let a = c[2]/c[3];
+    let b = c[1]/c[3];
+    let cc = c[0]/c[3];
+
+    //Substitute x = y - a/3 to remove the quadratic term.
+    let sq_a = a*a;
+    let p = (-sq_a/3.0 + b)/3.0;
+    let q = (2.0/27.0*a*sq_a - a*b/3.0 + cc)/2.0;
+    let cb_p = p*p*p;
+    let d = q*q + cb_p;
+
+    let mut s = if d.abs() < 1e-9 {
+        if q.abs() < 1e-9 {
+            vec![0.0]
+        } else {
+            let u = (-q).cbrt();
+            vec![2.0*u, -u]
+        }
+    } else if d < 0.0 {
+        let phi = (-q/(-cb_p).sqrt()).clamp(-1.0, 1.0).acos()/3.0;
+        let t = 2.0*(-p).sqrt();
+        let third = std::f64::consts::PI/3.0;
+        vec![t*phi.cos(), -t*(phi + third).cos(), -t*(phi - third).cos()]
+    } else {
+        let sqrt_d = d.sqrt();
+        vec![(sqrt_d - q).cbrt() - (sqrt_d + q).cbrt()]
+    };
+
+    for root in s.iter_mut() {
+        *root -= a/3.0;
+    }
+    s
+}
+
+fn solve_quartic(c: &[f64; 5]) -> Vec<f64> {
+    let a = c[3]/c[4];
+    let b = c[2]/c[4];
+    let cc = c[1]/c[4];
+    let d = c[0]/c[4];
+
+    //Substitute x = y - a/4 to remove the cubic term.
+    let sq_a = a*a;
+    let p = -3.0/8.0*sq_a + b;
+    let q = sq_a*a/8.0 - a*b/2.0 + cc;
+    let r = -3.0/256.0*sq_a*sq_a + sq_a*b/16.0 - a*cc/4.0 + d;
+
+    let mut s = if r.abs() < 1e-9 {
+        let mut s = solve_cubic(&[q, p, 0.0, 1.0]);
+        s.push(0.0);
+        s
+    } else {
+        //Solve the resolvent cubic, then split into two quadratics.
+        let z = solve_cubic(&[r*p/2.0 - q*q/8.0, -r, -p/2.0, 1.0])[0];
+        let u = z*z - r;
+        let v = 2.0*z - p;
+        if u < -1e-9 || v < -1e-9 {
+            return vec![];
+        }
+        let u = u.max(0.0).sqrt();
+        let v = v.max(0.0).sqrt();
+        let mut s = solve_quadric(&[z - u, if q < 0.0 { -v } else { v }, 1.0]);
+        s.extend(solve_quadric(&[z + u, if q < 0.0 { v } else { -v }, 1.0]));
+        s
+    };
+
+    //Polish each root with a couple of Newton steps on the original polynomial.
+    for root in s.iter_mut() {
+        *root -= a/4.0;
+        for _ in 0..2 {
+            let x = *root;
+            let f = (((c[4]*x + c[3])*x + c[2])*x + c[1])*x + c[0];
+            let df = ((4.0*c[4]*x + 3.0*c[3])*x + 2.0*c[2])*x + c[1];
+            if df.abs() > 1e-12 {
+                *root = x - f/df;
+            }
+        }
+    }
+    s
+}
+
+//Uniform direction on the unit sphere. Always takes two numbers, so samplers can line
+//their dimensions up with the bounces.
+pub fn rand_usphere() -> Vector3<f64> {
+    let z = 1.0 - 2.0*random::gen();
+    let phi = 2.0*std::f64::consts::PI*random::gen();
+    let r = (1.0 - z*z).max(0.0).sqrt();
+    Vector3::new(r*phi.cos(), r*phi.sin(), z)
+}
+
+#[allow(dead_code)]
 pub struct GeometryFactory {
     geometry_list: Map<String, Arc<dyn Hitable>>,
 }
 
+#[allow(dead_code)]
 impl GeometryFactory {
     pub fn new() -> GeometryFactory {
-        let mut geometry_map: Map<String, Arc<dyn Hitable>> = Map::new();
+        let geometry_map: Map<String, Arc<dyn Hitable>> = Map::new();
         //geometry_map.insert("plane", Arc::new(Plane{}));
 
         GeometryFactory {geometry_list: geometry_map}
@@ -147,4 +1014,139 @@ impl GeometryFactory {
             }
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn white() -> Vector3<f64> {
+        Vector3::new(1.0, 1.0, 1.0)
+    }
+
+    //Ray from (x, y, 5) straight down the z axis.
+    fn down_z(x: f64, y: f64) -> Ray {
+        Ray::new_at(Vector3::new(x, y, 5.0), Vector3::new(0.0, 0.0, -1.0), 0.0)
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    fn close_spans(a: &[(f64, f64)], b: &[(f64, f64)]) -> bool {
+        a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| close(x.0, y.0) && close(x.1, y.1))
+    }
+
+    #[test]
+    fn sphere_hits_front_and_reports_its_span() {
+        let sphere = Sphere::new(white(), 1.0, Vector3::new(0.0, 0.0, 0.0), "flat".to_string());
+        assert!(close(sphere.hit(&down_z(0.0, 0.0), f64::MAX), 4.0));
+        assert!(close(sphere.hit(&down_z(0.6, 0.0), f64::MAX), 5.0 - 0.8));
+        assert_eq!(sphere.hit(&down_z(1.5, 0.0), f64::MAX), 0.0);
+        assert_eq!(sphere.hit(&down_z(0.0, 0.0), 3.0), 0.0);
+        assert!(close_spans(&sphere.hit_intervals(&down_z(0.0, 0.0)), &[(4.0, 6.0)]));
+        let n = sphere.get_norm_at_p(&down_z(0.0, 0.0), &Vector3::new(0.0, 0.0, 1.0));
+        assert!((n - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn moving_sphere_follows_its_center() {
+        let sphere = Sphere::new_moving(white(), 1.0, Vector3::new(0.0, 0.0, 0.0), Vector3::new(2.0, 0.0, 0.0), "flat".to_string());
+        let r = Ray::new_at(Vector3::new(1.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0), 0.5);
+        assert!(close(sphere.hit(&r, f64::MAX), 4.0));
+    }
+
+    #[test]
+    fn plane_hits_in_front_only() {
+        let plane = Plane::new(Vector3::new(0.0, 0.0, -1.0), Vector3::new(0.0, 0.0, 1.0), white(), "flat".to_string());
+        assert!(close(plane.hit(&down_z(3.0, -2.0), f64::MAX), 6.0));
+        let away = Ray::new_at(Vector3::new(0.0, 0.0, 5.0), Vector3::new(0.0, 0.0, 1.0), 0.0);
+        assert_eq!(plane.hit(&away, f64::MAX), 0.0);
+    }
+
+    #[test]
+    fn box_slabs() {
+        let b = AaBox::new(Vector3::new(-1.0, -1.0, -1.0), Vector3::new(1.0, 1.0, 1.0), white(), "flat".to_string());
+        assert!(close(b.hit(&down_z(0.5, -0.5), f64::MAX), 4.0));
+        assert_eq!(b.hit(&down_z(1.5, 0.0), f64::MAX), 0.0);
+        assert!(close_spans(&b.hit_intervals(&down_z(0.0, 0.0)), &[(4.0, 6.0)]));
+        let n = b.get_norm_at_p(&down_z(0.0, 0.0), &Vector3::new(0.2, 0.3, 1.0));
+        assert!((n - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn cylinder_side_and_caps() {
+        let c = Cylinder::new(Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.0, 2.0, 0.0), 1.0, white(), "flat".to_string());
+        assert!(close(c.hit(&down_z(0.0, 0.0), f64::MAX), 4.0));
+        assert_eq!(c.hit(&down_z(0.0, 1.5), f64::MAX), 0.0);
+        let from_above = Ray::new_at(Vector3::new(0.5, 5.0, 0.0), Vector3::new(0.0, -1.0, 0.0), 0.0);
+        assert!(close(c.hit(&from_above, f64::MAX), 4.0));
+        assert!(close_spans(&c.hit_intervals(&from_above), &[(4.0, 6.0)]));
+    }
+
+    #[test]
+    fn cone_narrows_towards_the_top() {
+        //Radius 1 at y = -1 down to a point at y = 1, so 0.5 at y = 0.
+        let c = Cone::new(Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.0, 2.0, 0.0), 1.0, 0.0, white(), "flat".to_string());
+        assert!(close(c.hit(&down_z(0.0, 0.0), f64::MAX), 4.5));
+        assert_eq!(c.hit(&down_z(0.6, 0.0), f64::MAX), 0.0);
+        assert!(close_spans(&c.hit_intervals(&down_z(0.0, 0.0)), &[(4.5, 5.5)]));
+    }
+
+    #[test]
+    fn disk_and_rectangle_are_bounded() {
+        let disk = Disk::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0), 1.0, white(), "flat".to_string());
+        assert!(close(disk.hit(&down_z(0.5, 0.5), f64::MAX), 5.0));
+        assert_eq!(disk.hit(&down_z(0.8, 0.8), f64::MAX), 0.0);
+        assert!(close(disk.area(), std::f64::consts::PI));
+
+        let rect = Rectangle::new(Vector3::new(-1.0, -1.0, 0.0), Vector3::new(2.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0), white(), "flat".to_string());
+        assert!(close(rect.hit(&down_z(0.9, -0.5), f64::MAX), 5.0));
+        assert_eq!(rect.hit(&down_z(0.0, 0.5), f64::MAX), 0.0);
+        assert!(close(rect.area(), 2.0));
+        let (u, v) = rect.get_uv_at_p(&down_z(0.0, -0.5), &Vector3::new(0.0, -0.5, 0.0));
+        assert!(close(u, 0.5) && close(v, 0.5));
+    }
+
+    #[test]
+    fn torus_crosses_the_tube_twice() {
+        let t = Torus::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0), 2.0, 0.5, white(), "flat".to_string());
+        assert!(close(t.hit(&down_z(0.0, 0.0), f64::MAX), 2.5));
+        assert!(close_spans(&t.hit_intervals(&down_z(0.0, 0.0)), &[(2.5, 3.5), (6.5, 7.5)]));
+        //Straight through the hole along the axis.
+        let through = Ray::new_at(Vector3::new(0.0, 5.0, 0.0), Vector3::new(0.0, -1.0, 0.0), 0.0);
+        assert_eq!(t.hit(&through, f64::MAX), 0.0);
+        let n = t.get_norm_at_p(&down_z(0.0, 0.0), &Vector3::new(0.0, 0.0, 2.5));
+        assert!((n - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn sampled_points_lie_on_the_surface() {
+        let sphere = Sphere::new(white(), 2.0, Vector3::new(1.0, 0.0, 0.0), "flat".to_string());
+        let disk = Disk::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0), 1.0, white(), "flat".to_string());
+        random::with_stream(Box::new(random::Seeded::new(3)), || {
+            for _ in 0..100 {
+                let (p, n) = sphere.sample_surface(0.0).unwrap();
+                assert!(close((p - Vector3::new(1.0, 0.0, 0.0)).magnitude(), 2.0));
+                assert!(close(n.magnitude(), 1.0));
+                let (p, _) = disk.sample_surface(0.0).unwrap();
+                assert!(close(p.z, 0.0) && p.x*p.x + p.y*p.y <= 1.0 + 1e-9);
+            }
+        });
+    }
+
+    #[test]
+    fn moving_emitters_are_sampled_where_they_are() {
+        let sphere = Sphere::new_moving(white(), 1.0, Vector3::new(0.0, 0.0, 0.0), Vector3::new(4.0, 0.0, 0.0), "flat".to_string());
+        let inner: Arc<dyn Hitable> = Arc::new(Sphere::new(white(), 1.0, Vector3::new(0.0, 0.0, 0.0), "flat".to_string()));
+        let sliding = Moving::new(inner, Vector3::new(0.0, 2.0, 0.0));
+        assert!(close(sliding.area(), 4.0*std::f64::consts::PI));
+        random::with_stream(Box::new(random::Seeded::new(5)), || {
+            for _ in 0..100 {
+                let (p, _) = sphere.sample_surface(0.5).unwrap();
+                assert!(close((p - Vector3::new(2.0, 0.0, 0.0)).magnitude(), 1.0));
+                let (p, _) = sliding.sample_surface(1.0).unwrap();
+                assert!(close((p - Vector3::new(0.0, 2.0, 0.0)).magnitude(), 1.0));
+            }
+        });
+    }
+}
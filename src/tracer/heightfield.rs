@@ -0,0 +1,320 @@
+use super::geometry::*;
+use super::ray::*;
+use cgmath::*;
+
+//Terrain made from a grid of heights. Rays are walked down a min/max quadtree over the
+//grid so only the cells near the ray get their two triangles tested.
+pub struct Heightfield {
+    heights: Vec<f64>,
+    normals: Vec<Vector3<f64>>,
+    nx: usize,
+    nz: usize,
+    origin: Vector3<f64>,
+    size: Vector3<f64>,
+    cell: Vector2<f64>,
+    //levels[0] has one (min, max) entry per cell, each level above halves both sides.
+    levels: Vec<Level>,
+    center: Vector3<f64>,
+    color: Vector3<f64>,
+    mat: String,
+}
+
+struct Level {
+    width: usize,
+    depth: usize,
+    bounds: Vec<(f64, f64)>,
+}
+
+impl Heightfield {
+    //Loads a grayscale image where black is the origin's height and white is origin + size.y.
+    pub fn from_image(path: &str, origin: Vector3<f64>, size: Vector3<f64>, col: Vector3<f64>, m: String) -> image::ImageResult<Heightfield> {
+        let img = image::open(path)?.to_luma();
+        let (nx, nz) = (img.width() as usize, img.height() as usize);
+        let heights = img.pixels().map(|p| (p.data[0] as f64)/255.0).collect();
+        Ok(Heightfield::new(heights, nx, nz, origin, size, col, m))
+    }
+
+    //Heights are row-major along x, in [0, 1] and scaled by size.y.
+    pub fn new(heights: Vec<f64>, nx: usize, nz: usize, origin: Vector3<f64>, size: Vector3<f64>, col: Vector3<f64>, m: String) -> Heightfield {
+        let nx = nx.max(2);
+        let nz = nz.max(2);
+        let mut heights: Vec<f64> = heights.iter().map(|h| h*size.y).collect();
+        heights.resize(nx*nz, 0.0);
+        let cell = Vector2::new(size.x/((nx - 1) as f64), size.z/((nz - 1) as f64));
+
+        let mut field = Heightfield {
+            heights,
+            normals: vec![],
+            nx,
+            nz,
+            origin,
+            size,
+            cell,
+            levels: vec![],
+            center: origin + size/2.0,
+            color: col,
+            mat: m
+        };
+        field.normals = field.vertex_normals();
+        field.levels = field.build_levels();
+        field
+    }
+
+    fn height(&self, x: usize, z: usize) -> f64 {
+        self.heights[z*self.nx + x]
+    }
+
+    fn vertex(&self, x: usize, z: usize) -> Vector3<f64> {
+        self.origin + Vector3::new((x as f64)*self.cell.x, self.height(x, z), (z as f64)*self.cell.y)
+    }
+
+    //Normals from the central difference of the heights around each vertex.
+    fn vertex_normals(&self) -> Vec<Vector3<f64>> {
+        let mut normals = Vec::with_capacity(self.nx*self.nz);
+        for z in 0..self.nz {
+            for x in 0..self.nx {
+                let (x0, x1) = (x.saturating_sub(1), (x + 1).min(self.nx - 1));
+                let (z0, z1) = (z.saturating_sub(1), (z + 1).min(self.nz - 1));
+                let dhdx = (self.height(x1, z) - self.height(x0, z))/(((x1 - x0) as f64)*self.cell.x);
+                let dhdz = (self.height(x, z1) - self.height(x, z0))/(((z1 - z0) as f64)*self.cell.y);
+                normals.push(Vector3::new(-dhdx, 1.0, -dhdz).normalize());
+            }
+        }
+        normals
+    }
+
+    fn build_levels(&self) -> Vec<Level> {
+        let mut cells = Vec::with_capacity((self.nx - 1)*(self.nz - 1));
+        for z in 0..self.nz - 1 {
+            for x in 0..self.nx - 1 {
+                let corners = [self.height(x, z), self.height(x + 1, z), self.height(x, z + 1), self.height(x + 1, z + 1)];
+                let low = corners.iter().cloned().fold(f64::MAX, f64::min);
+                let high = corners.iter().cloned().fold(f64::MIN, f64::max);
+                cells.push((low, high));
+            }
+        }
+
+        let mut levels = vec![Level { width: self.nx - 1, depth: self.nz - 1, bounds: cells }];
+        while levels.last().map(|l| l.width > 1 || l.depth > 1).unwrap_or(false) {
+            let below = levels.last().unwrap();
+            let width = below.width.div_ceil(2);
+            let depth = below.depth.div_ceil(2);
+            let mut bounds = Vec::with_capacity(width*depth);
+            for z in 0..depth {
+                for x in 0..width {
+                    let mut low = f64::MAX;
+                    let mut high = f64::MIN;
+                    for (cx, cz) in [(2*x, 2*z), (2*x + 1, 2*z), (2*x, 2*z + 1), (2*x + 1, 2*z + 1)].iter() {
+                        if *cx < below.width && *cz < below.depth {
+                            let (l, h) = below.bounds[cz*below.width + cx];
+                            low = low.min(l);
+                            high = high.max(h);
+                        }
+                    }
+                    bounds.push((low, high));
+                }
+            }
+            levels.push(Level { width, depth, bounds });
+        }
+        levels
+    }
+
+    //Slab test against the box a node covers, returning the entry distance.
+    fn node_entry(&self, r: &Ray, level: usize, x: usize, z: usize, t_max: f64) -> Option<f64> {
+        let span = (1usize << level) as f64;
+        let (low, high) = self.levels[level].bounds[z*self.levels[level].width + x];
+        let min = Vector3::new(
+            self.origin.x + (x as f64)*span*self.cell.x,
+            self.origin.y + low,
+            self.origin.z + (z as f64)*span*self.cell.y
+        );
+        let max = Vector3::new(
+            (min.x + span*self.cell.x).min(self.origin.x + self.size.x),
+            self.origin.y + high,
+            (min.z + span*self.cell.y).min(self.origin.z + self.size.z)
+        );
+
+        let mut t0: f64 = 0.001;
+        let mut t1 = t_max;
+        for i in 0..3 {
+            let inv = 1.0/r.direction()[i];
+            let mut near = (min[i] - r.origin()[i])*inv;
+            let mut far = (max[i] - r.origin()[i])*inv;
+            if inv < 0.0 {
+                std::mem::swap(&mut near, &mut far);
+            }
+            //Flat nodes have zero thickness, so compare with a little slack.
+            t0 = t0.max(near - 1e-9);
+            t1 = t1.min(far + 1e-9);
+            if t1 < t0 {
+                return None;
+            }
+        }
+        Some(t0)
+    }
+
+    //Visits children nearest first so the first hit found is the closest one.
+    fn traverse(&self, r: &Ray, level: usize, x: usize, z: usize, t_max: f64) -> f64 {
+        if self.node_entry(r, level, x, z, t_max).is_none() {
+            return 0.0;
+        }
+        if level == 0 {
+            return self.hit_cell(r, x, z, t_max);
+        }
+
+        let below = &self.levels[level - 1];
+        let mut children = vec![];
+        for (cx, cz) in [(2*x, 2*z), (2*x + 1, 2*z), (2*x, 2*z + 1), (2*x + 1, 2*z + 1)].iter() {
+            if *cx < below.width && *cz < below.depth {
+                if let Some(t) = self.node_entry(r, level - 1, *cx, *cz, t_max) {
+                    children.push((t, *cx, *cz));
+                }
+            }
+        }
+        children.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+        let mut best = 0.0;
+        for (entry, cx, cz) in children {
+            if best != 0.0 && entry > best {
+                break;
+            }
+            let t = self.traverse(r, level - 1, cx, cz, if best != 0.0 { best } else { t_max });
+            if t != 0.0 && (best == 0.0 || t < best) {
+                best = t;
+            }
+        }
+        best
+    }
+
+    fn hit_cell(&self, r: &Ray, x: usize, z: usize, t_max: f64) -> f64 {
+        let v00 = self.vertex(x, z);
+        let v10 = self.vertex(x + 1, z);
+        let v01 = self.vertex(x, z + 1);
+        let v11 = self.vertex(x + 1, z + 1);
+        let mut best = 0.0;
+        for t in [hit_triangle(r, &v00, &v01, &v10), hit_triangle(r, &v10, &v01, &v11)].iter() {
+            if *t > 0.001 && *t < t_max && (best == 0.0 || *t < best) {
+                best = *t;
+            }
+        }
+        best
+    }
+}
+
+//Moller-Trumbore, returning 0 on a miss like the other hitables.
+fn hit_triangle(r: &Ray, a: &Vector3<f64>, b: &Vector3<f64>, c: &Vector3<f64>) -> f64 {
+    let e1 = b - a;
+    let e2 = c - a;
+    let pvec = r.direction().cross(e2);
+    let det = e1.dot(pvec);
+    if det.abs() < 1e-12 {
+        return 0.0;
+    }
+    let inv = 1.0/det;
+    let tvec = r.origin() - a;
+    let u = tvec.dot(pvec)*inv;
+    if !(0.0..=1.0).contains(&u) {
+        return 0.0;
+    }
+    let qvec = tvec.cross(e1);
+    let v = r.direction().dot(qvec)*inv;
+    if v < 0.0 || u + v > 1.0 {
+        return 0.0;
+    }
+    e2.dot(qvec)*inv
+}
+
+impl Hitable for Heightfield {
+    fn hit(&self, r: &Ray, t_max: f64) -> f64 {
+        let top = self.levels.len() - 1;
+        self.traverse(r, top, 0, 0, t_max)
+    }
+
+    fn get_center(&self) -> &Vector3<f64> {
+        &self.center
+    }
+
+    fn get_radius(&self) -> f64 {
+        self.size.magnitude()/2.0
+    }
+
+    fn get_color(&self) -> &Vector3<f64> {
+        &self.color
+    }
+
+    fn get_material(&self) -> String {
+        self.mat.clone()
+    }
+
+    //Bilinear blend of the four vertex normals around p, so the terrain shades smoothly.
+    fn get_norm_at_p(&self, _: &Ray, p: &Vector3<f64>) -> Vector3<f64> {
+        let gx = ((p.x - self.origin.x)/self.cell.x).clamp(0.0, (self.nx - 1) as f64);
+        let gz = ((p.z - self.origin.z)/self.cell.y).clamp(0.0, (self.nz - 1) as f64);
+        let x = (gx.floor() as usize).min(self.nx - 2);
+        let z = (gz.floor() as usize).min(self.nz - 2);
+        let fx = gx - x as f64;
+        let fz = gz - z as f64;
+        let n = |x: usize, z: usize| self.normals[z*self.nx + x];
+        let n = (1.0 - fx)*(1.0 - fz)*n(x, z) + fx*(1.0 - fz)*n(x + 1, z) + (1.0 - fx)*fz*n(x, z + 1) + fx*fz*n(x + 1, z + 1);
+        n.normalize()
+    }
+
+    fn get_uv_at_p(&self, _: &Ray, p: &Vector3<f64>) -> (f64, f64) {
+        (((p.x - self.origin.x)/self.size.x).clamp(0.0, 1.0), ((p.z - self.origin.z)/self.size.z).clamp(0.0, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::random;
+
+    fn field(heights: Vec<f64>, nx: usize, nz: usize) -> Heightfield {
+        let white = Vector3::new(1.0, 1.0, 1.0);
+        Heightfield::new(heights, nx, nz, Vector3::new(0.0, 0.0, 0.0), Vector3::new(4.0, 2.0, 4.0), white, "flat".to_string())
+    }
+
+    #[test]
+    fn flat_field_is_hit_at_its_height() {
+        let flat = field(vec![0.5; 25], 5, 5);
+        let down = Ray::new_at(Vector3::new(1.3, 5.0, 2.7), Vector3::new(0.0, -1.0, 0.0), 0.0);
+        assert!((flat.hit(&down, f64::MAX) - 4.0).abs() < 1e-9);
+        let n = flat.get_norm_at_p(&down, &Vector3::new(1.3, 1.0, 2.7));
+        assert!((n - Vector3::new(0.0, 1.0, 0.0)).magnitude() < 1e-9);
+        let outside = Ray::new_at(Vector3::new(5.0, 5.0, 2.0), Vector3::new(0.0, -1.0, 0.0), 0.0);
+        assert_eq!(flat.hit(&outside, f64::MAX), 0.0);
+    }
+
+    #[test]
+    fn ramp_is_hit_on_its_slope() {
+        //Height goes from 0 to 2 across x, so the surface is y = x/2.
+        let heights: Vec<f64> = (0..9).flat_map(|_| (0..9).map(|x| x as f64/8.0)).collect();
+        let ramp = field(heights, 9, 9);
+        let down = Ray::new_at(Vector3::new(3.0, 5.0, 1.0), Vector3::new(0.0, -1.0, 0.0), 0.0);
+        assert!((ramp.hit(&down, f64::MAX) - 3.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn quadtree_finds_the_same_hits_as_every_cell() {
+        let (nx, nz) = (13, 9);
+        let heights: Vec<f64> = (0..nx*nz).map(|i| ((i*7919) % 97) as f64/97.0).collect();
+        let bumpy = field(heights, nx, nz);
+        random::with_stream(Box::new(random::Seeded::new(11)), || {
+            for _ in 0..500 {
+                let origin = Vector3::new(random::gen()*6.0 - 1.0, 3.0, random::gen()*6.0 - 1.0);
+                let target = Vector3::new(random::gen()*4.0, random::gen()*2.0, random::gen()*4.0);
+                let r = Ray::new_at(origin, target - origin, 0.0);
+                let mut brute = 0.0;
+                for z in 0..nz - 1 {
+                    for x in 0..nx - 1 {
+                        let t = bumpy.hit_cell(&r, x, z, f64::MAX);
+                        if t != 0.0 && (brute == 0.0 || t < brute) {
+                            brute = t;
+                        }
+                    }
+                }
+                assert!((bumpy.hit(&r, f64::MAX) - brute).abs() < 1e-9);
+            }
+        });
+    }
+}
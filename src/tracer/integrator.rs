@@ -0,0 +1,158 @@
+use super::aov::Lighting;
+use super::bdpt::Bdpt;
+use super::camera::*;
+use super::debug;
+use super::random;
+use super::ray::Ray;
+use super::scene::*;
+use super::whitted::Whitted;
+use super::world::Settings;
+use cgmath::*;
+use std::sync::Arc;
+
+//Works out one sample of the color seen through (u, v), which run from 0 to 1 across the
+//image. Light that lands on some other part of the image is handed to splat instead.
+pub trait Integrator: Send + Sync {
+    fn render(&self, cam: &Camera, u: f64, v: f64, splat: &mut dyn FnMut(f64, f64, Vector3<f64>)) -> Vector3<f64>;
+    //The same sample, also split up by how its light reached the camera for integrators
+    //that keep track.
+    fn render_passes(&self, cam: &Camera, u: f64, v: f64, splat: &mut dyn FnMut(f64, f64, Vector3<f64>)) -> (Vector3<f64>, Option<Lighting>) {
+        (self.render(cam, u, v, splat), None)
+    }
+}
+
+//What the camera ray met first, which decides the pass a path's light goes in.
+enum Route {
+    Emission,
+    Diffuse,
+    Specular,
+}
+
+//Unidirectional path tracing, following one scattered ray per bounce.
+pub struct PathTracer {
+    scene: Arc<Scene>,
+    min_depth: u32,
+    max_depth: u32,
+}
+
+impl PathTracer {
+    pub fn new(scene: Arc<Scene>, settings: &Settings) -> PathTracer {
+        PathTracer { scene, min_depth: settings.min_depth, max_depth: settings.max_depth }
+    }
+}
+
+impl PathTracer {
+    //Color a path brings back, how it started and how many bounces it took.
+    fn trace(&self, mut ray: Ray) -> (Vector3<f64>, Route, u32) {
+        let mut beta = Vector3::new(1.0, 1.0, 1.0);
+        let mut route = Route::Emission;
+        let mut depth = 0;
+        loop {
+            let q = survival(depth, &beta, self.min_depth, self.max_depth);
+            if q < 1.0 && random::gen() >= q {
+                return (Vector3::zero(), route, depth);
+            }
+            beta /= q;
+
+            let (t, hitable) = match self.scene.get_closest_intersection(&ray, f64::MAX) {
+                Some(hit) => hit,
+                None => return (Vector3::zero(), route, depth)
+            };
+            let p = ray.point_at_parameter(t);
+            let n = hitable.get_norm_at_p(&ray, &p);
+            let material = self.scene.get_materials().get_material_by_key(hitable.get_material());
+            let emitted = material.emitted();
+            //Lights end the path.
+            if emitted != Vector3::zero() {
+                return (beta.mul_element_wise(emitted), route, depth);
+            }
+
+            if depth == 0 {
+                route = if material.is_specular() { Route::Specular } else { Route::Diffuse };
+            }
+            ray = material.scatter(&ray, &n, &p).0;
+            beta = beta.mul_element_wise(*hitable.get_color());
+            depth += 1;
+        }
+    }
+}
+
+impl Integrator for PathTracer {
+    fn render(&self, cam: &Camera, u: f64, v: f64, _: &mut dyn FnMut(f64, f64, Vector3<f64>)) -> Vector3<f64> {
+        self.trace(cam.get_ray(u, v)).0
+    }
+
+    fn render_passes(&self, cam: &Camera, u: f64, v: f64, _: &mut dyn FnMut(f64, f64, Vector3<f64>)) -> (Vector3<f64>, Option<Lighting>) {
+        let (col, route, depth) = self.trace(cam.get_ray(u, v));
+        let mut lighting = Lighting::zero();
+        match route {
+            Route::Emission => lighting.emission = col,
+            Route::Specular => lighting.specular = col,
+            Route::Diffuse if depth == 1 => lighting.direct_diffuse = col,
+            Route::Diffuse => lighting.indirect_diffuse = col
+        }
+        (col, Some(lighting))
+    }
+}
+
+pub fn luminance(c: &Vector3<f64>) -> f64 {
+    0.2126*c.x + 0.7152*c.y + 0.0722*c.z
+}
+
+//Russian roulette: the chance a path carrying throughput beta goes on after depth bounces.
+//Paths always make it through min_depth bounces and never past max_depth. In between the
+//chance follows the brightest channel, and survivors divide their throughput by it so the
+//image stays unbiased.
+pub fn survival(depth: u32, beta: &Vector3<f64>, min_depth: u32, max_depth: u32) -> f64 {
+    if depth > max_depth {
+        0.0
+    } else if depth < min_depth {
+        1.0
+    } else {
+        beta.x.max(beta.y).max(beta.z).min(1.0)
+    }
+}
+
+//Builds the integrator the settings name. Metropolis runs chains over the path tracer
+//instead of taking samples, so it gets the path tracer here.
+pub fn by_name(scene: Arc<Scene>, settings: &Settings) -> Arc<dyn Integrator> {
+    match settings.integrator.as_str() {
+        "path" | "mlt" => Arc::new(PathTracer::new(scene, settings)),
+        "bdpt" => Arc::new(Bdpt::new(scene, settings.max_depth)),
+        "whitted" => Arc::new(Whitted::new(scene, settings.max_depth, &settings.whitted)),
+        name => match debug::View::from_name(name) {
+            Some(view) => Arc::new(debug::Debug::new(scene, view, settings)),
+            None => {
+                println!("Error, integrator '{}' not found. Using path", name);
+                Arc::new(PathTracer::new(scene, settings))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::random;
+
+    #[test]
+    fn survival_respects_the_depth_limits() {
+        let dim = Vector3::new(0.01, 0.02, 0.03);
+        assert_eq!(survival(2, &dim, 3, 10), 1.0);
+        assert_eq!(survival(11, &Vector3::new(5.0, 5.0, 5.0), 3, 10), 0.0);
+        assert!((survival(5, &dim, 3, 10) - 0.03).abs() < 1e-12);
+        assert_eq!(survival(5, &Vector3::new(0.2, 3.0, 0.1), 3, 10), 1.0);
+    }
+
+    #[test]
+    fn roulette_keeps_the_mean() {
+        //Survivors carry beta/q, so on average a path is worth what it carried in.
+        let beta = Vector3::new(0.25, 0.1, 0.05);
+        let q = survival(4, &beta, 3, 10);
+        let runs = 200_000;
+        let total = random::with_stream(Box::new(random::Seeded::new(11)), || {
+            (0..runs).fold(0.0, |sum, _| if random::gen() < q { sum + beta.x/q } else { sum })
+        });
+        assert!((total/(runs as f64) - beta.x).abs() < 0.005);
+    }
+}
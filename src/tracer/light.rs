@@ -1,20 +1,26 @@
 use cgmath::*;
 
+//Point light for the Whitted preview. Brightness falls off with the square of the distance.
 pub struct Light {
     origin: Vector3<f64>,
-    intensity: f64
+    intensity: f64,
+    color: Vector3<f64>
 }
 
 impl Light {
-    pub fn new(org: Vector3<f64>, int: f64) -> Light {
-        Light { origin: org, intensity: int }
+    pub fn new(org: Vector3<f64>, int: f64, col: Vector3<f64>) -> Light {
+        Light { origin: org, intensity: int, color: col }
     }
 
     pub fn origin(&self) -> &Vector3<f64> {
         &self.origin
     }
-    
+
     pub fn intensity(&self) -> f64 {
         self.intensity
     }
+
+    pub fn color(&self) -> &Vector3<f64> {
+        &self.color
+    }
 }
\ No newline at end of file
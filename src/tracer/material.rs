@@ -1,19 +1,41 @@
 use cgmath::*;
 use super::ray;
 use super::geometry;
-use rand::*;
+use super::random;
 
 use std::sync::Arc;
 use std::collections::HashMap as Map;
-use std::error::Error;
 
 pub trait Material: Send + Sync {
     fn scatter(&self, r: &ray::Ray, n: &Vector3<f64>, p: &Vector3<f64>) -> (ray::Ray, f64);
     fn emitted(&self) -> Vector3<f64> {
         Vector3::new(0.0,0.0,0.0)
     }
-    fn importance_scatter(&self, r_in: &ray::Ray, r_scatter: &ray::Ray) -> f64 {
-        return 0.0;
+    #[allow(dead_code)]
+    fn importance_scatter(&self, _r_in: &ray::Ray, _r_scatter: &ray::Ray) -> f64 {
+        0.0
+    }
+    //Scattering function for light passing between wi and wo, both pointing away from
+    //the surface. The color is left to the hitable.
+    fn eval(&self, _wi: &Vector3<f64>, _wo: &Vector3<f64>, _n: &Vector3<f64>) -> f64 {
+        0.0
+    }
+    //Solid angle density of scatter() picking wo when the ray arrived from wi.
+    fn pdf(&self, _wi: &Vector3<f64>, _wo: &Vector3<f64>, _n: &Vector3<f64>) -> f64 {
+        0.0
+    }
+    //Mirrors and glass only scatter into a single direction, so paths can't be joined there.
+    fn is_specular(&self) -> bool {
+        false
+    }
+    //Every ray a perfect mirror or clear glass splits the incoming ray into, with the
+    //fraction of light each one carries. Empty for everything else.
+    fn specular_rays(&self, _r: &ray::Ray, _n: &Vector3<f64>, _p: &Vector3<f64>) -> Vec<(ray::Ray, f64)> {
+        vec![]
+    }
+    //Phase functions scatter inside media, where there's no surface to take a cosine with.
+    fn is_volume(&self) -> bool {
+        false
     }
 }
 
@@ -21,13 +43,20 @@ pub struct Flat {}
 
 impl Material for Flat {
     fn scatter(&self, r: &ray::Ray, n: &Vector3<f64>, p: &Vector3<f64>) -> (ray::Ray, f64) {
+        //A point on the sphere's surface rather than inside it gives an exact cosine lobe.
         let direction = n + geometry::rand_usphere();
         let direction = direction/direction.dot(direction).sqrt();
-        let pdf = n.dot(*r.direction())/3.14159;
-        (ray::Ray::new_from(*p, direction), pdf)
+        let pdf = n.dot(*r.direction())/std::f64::consts::PI;
+        (ray::Ray::new_at(*p, direction, r.time()), pdf)
+    }
+    fn importance_scatter(&self, _r_in: &ray::Ray, _r_scatter: &ray::Ray) -> f64 {
+        0.0
     }
-    fn importance_scatter(&self, r_in: &ray::Ray, r_scatter: &ray::Ray) -> f64 {
-        return 0.0;
+    fn eval(&self, wi: &Vector3<f64>, wo: &Vector3<f64>, n: &Vector3<f64>) -> f64 {
+        if wi.dot(*n)*wo.dot(*n) > 0.0 { 1.0/std::f64::consts::PI } else { 0.0 }
+    }
+    fn pdf(&self, wi: &Vector3<f64>, wo: &Vector3<f64>, n: &Vector3<f64>) -> f64 {
+        if wi.dot(*n)*wo.dot(*n) > 0.0 { wo.dot(*n).abs()/std::f64::consts::PI } else { 0.0 }
     }
 }
 
@@ -36,7 +65,13 @@ pub struct Metal {}
 impl Material for Metal {
     fn scatter(&self, r: &ray::Ray, n: &Vector3<f64>, p: &Vector3<f64>) -> (ray::Ray, f64) {
         let reflected = reflect(&(r.direction() / r.direction().magnitude()), n);
-        (ray::Ray::new_from(*p, reflected), 0.0)
+        (ray::Ray::new_at(*p, reflected, r.time()), 0.0)
+    }
+    fn specular_rays(&self, r: &ray::Ray, n: &Vector3<f64>, p: &Vector3<f64>) -> Vec<(ray::Ray, f64)> {
+        vec![(self.scatter(r, n, p).0, 1.0)]
+    }
+    fn is_specular(&self) -> bool {
+        true
     }
 }
 
@@ -60,7 +95,7 @@ impl Material for Dielectric {
         if r.direction().dot(*n) < 0.0 {
             outward_normal = *n;
             ni_over_nt = 1.0 / self.ref_index;
-            cos = -1.0 * r.direction().dot(*n)/r.direction().magnitude();
+            cos = -r.direction().dot(*n)/r.direction().magnitude();
         } else { //out
             outward_normal = -1.0 * n;
             ni_over_nt = self.ref_index;
@@ -68,14 +103,72 @@ impl Material for Dielectric {
         }
         let refraction = refract(r.direction(), &outward_normal, ni_over_nt);
         let reflect_prob: f64 = schlick(cos, self.ref_index);
-        let mut rng = rand::thread_rng();
-        let rng: f64 = rng.gen();
+        let rng: f64 = random::gen();
 
         if rng < reflect_prob {
-            return (ray::Ray::new_from(*p, reflection), 0.0);
+            return (ray::Ray::new_at(*p, reflection, r.time()), 0.0);
         }
         
-        (ray::Ray::new_from(*p, refraction), 0.0)
+        (ray::Ray::new_at(*p, refraction, r.time()), 0.0)
+    }
+    fn specular_rays(&self, r: &ray::Ray, n: &Vector3<f64>, p: &Vector3<f64>) -> Vec<(ray::Ray, f64)> {
+        let d = r.direction().normalize();
+        let entering = d.dot(*n) < 0.0;
+        let (facing, eta) = if entering { (*n, 1.0/self.ref_index) } else { (-*n, self.ref_index) };
+        let cos = -d.dot(facing);
+        let sin2 = eta*eta*(1.0 - cos*cos);
+        let reflection = ray::Ray::new_at(*p, reflect(&d, &facing), r.time());
+        if sin2 > 1.0 {
+            return vec![(reflection, 1.0)];
+        }
+
+        let reflect_prob = if entering { schlick(cos, self.ref_index) } else { schlick((1.0 - sin2).sqrt(), self.ref_index) };
+        vec![
+            (reflection, reflect_prob),
+            (ray::Ray::new_at(*p, refract(&d, &facing, eta), r.time()), 1.0 - reflect_prob)
+        ]
+    }
+    fn is_specular(&self) -> bool {
+        true
+    }
+}
+
+//Smooth boundary of a translucent object. Light refracts in, random walks through the
+//medium the world places inside, then refracts back out or reflects internally.
+pub struct Subsurface {
+    ref_index: f64
+}
+
+impl Subsurface {
+    pub fn new(r: f64) -> Subsurface {
+        Subsurface { ref_index: r }
+    }
+}
+
+impl Material for Subsurface {
+    fn scatter(&self, r: &ray::Ray, n: &Vector3<f64>, p: &Vector3<f64>) -> (ray::Ray, f64) {
+        let d = r.direction().normalize();
+        let entering = d.dot(*n) < 0.0;
+        let (facing, eta) = if entering { (*n, 1.0/self.ref_index) } else { (-*n, self.ref_index) };
+        let cos = -d.dot(facing);
+        let sin2 = eta*eta*(1.0 - cos*cos);
+
+        //Schlick needs the angle on the less dense side.
+        let reflect_prob = if sin2 > 1.0 {
+            1.0
+        } else if entering {
+            schlick(cos, self.ref_index)
+        } else {
+            schlick((1.0 - sin2).sqrt(), self.ref_index)
+        };
+
+        if random::gen() < reflect_prob {
+            return (ray::Ray::new_at(*p, reflect(&d, &facing), r.time()), 0.0);
+        }
+        (ray::Ray::new_at(*p, refract(&d, &facing, eta), r.time()), 0.0)
+    }
+    fn is_specular(&self) -> bool {
+        true
     }
 }
 
@@ -84,7 +177,7 @@ fn reflect(v: &Vector3<f64>, n: &Vector3<f64>) -> Vector3<f64> {
 }
 
 fn refract(v: &Vector3<f64>, n: &Vector3<f64>, ni_over_nt: f64) -> Vector3<f64> {
-    let c1 = -1.0 * v.dot(*n);
+    let c1 = -v.dot(*n);
     let c2 = (1.0 - ni_over_nt*ni_over_nt * (1.0 - c1*c1)).sqrt();
     (ni_over_nt * v) + (ni_over_nt * c1 - c2) * n
 }
@@ -115,21 +208,84 @@ impl Material for DiffuseLight {
     }
 }
 
+//Henyey-Greenstein phase function for scattering events inside media. Positive g
+//scatters forward along the ray, negative g back towards where it came from.
+pub struct HenyeyGreenstein {
+    g: f64
+}
+
+impl HenyeyGreenstein {
+    pub fn new(g: f64) -> HenyeyGreenstein {
+        HenyeyGreenstein { g: g.clamp(-0.99, 0.99) }
+    }
+
+    fn phase(&self, cos: f64) -> f64 {
+        let g = self.g;
+        let denom = 1.0 + g*g - 2.0*g*cos;
+        (1.0 - g*g)/(4.0*std::f64::consts::PI*denom*denom.sqrt())
+    }
+}
+
+impl Material for HenyeyGreenstein {
+    fn scatter(&self, r: &ray::Ray, _: &Vector3<f64>, p: &Vector3<f64>) -> (ray::Ray, f64) {
+        let (u1, u2) = (random::gen(), random::gen());
+        let g = self.g;
+        let cos = if g.abs() < 1e-3 {
+            1.0 - 2.0*u1
+        } else {
+            let s = (1.0 - g*g)/(1.0 - g + 2.0*g*u1);
+            ((1.0 + g*g - s*s)/(2.0*g)).clamp(-1.0, 1.0)
+        };
+        let sin = (1.0 - cos*cos).max(0.0).sqrt();
+        let phi = 2.0*std::f64::consts::PI*u2;
+
+        let w = r.direction().normalize();
+        let (u, v) = geometry::tangents(&w);
+        let direction = sin*phi.cos()*u + sin*phi.sin()*v + cos*w;
+        (ray::Ray::new_at(*p, direction, r.time()), self.phase(cos))
+    }
+    //The phase function is sampled exactly, so it's also its own density.
+    fn eval(&self, wi: &Vector3<f64>, wo: &Vector3<f64>, _: &Vector3<f64>) -> f64 {
+        self.phase(-wi.dot(*wo))
+    }
+    fn pdf(&self, wi: &Vector3<f64>, wo: &Vector3<f64>, _: &Vector3<f64>) -> f64 {
+        self.phase(-wi.dot(*wo))
+    }
+    fn is_volume(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone)]
 pub struct MaterialsFactory {
-    materials_list: Map<&'static str, Arc<dyn Material>>,
+    materials_list: Map<String, Arc<dyn Material>>,
 }
 
 impl MaterialsFactory {
     pub fn new() -> MaterialsFactory {
-        let mut all_materials: Map<&'static str, Arc<dyn Material>> = Map::new();
-        all_materials.insert("flat", Arc::new(Flat{}));
-        all_materials.insert("metal", Arc::new(Metal{}));
-        all_materials.insert("glass", Arc::new(Dielectric::new(1.5))); //Default to standard glass
-        all_materials.insert("diffuse_light", Arc::new(DiffuseLight::new(1.0*2.5, 1.0*2.5, 0.98431372549*2.5))); //Sunlight at 5400K
+        let mut all_materials: Map<String, Arc<dyn Material>> = Map::new();
+        all_materials.insert("flat".to_string(), Arc::new(Flat{}));
+        all_materials.insert("metal".to_string(), Arc::new(Metal{}));
+        all_materials.insert("glass".to_string(), Arc::new(Dielectric::new(1.5))); //Default to standard glass
+        all_materials.insert("diffuse_light".to_string(), Arc::new(DiffuseLight::new(1.0*2.5, 1.0*2.5, 0.98431372549*2.5))); //Sunlight at 5400K
+        all_materials.insert("isotropic".to_string(), Arc::new(HenyeyGreenstein::new(0.0)));
+        all_materials.insert("subsurface".to_string(), Arc::new(Subsurface::new(1.3))); //Close to skin and wax
 
         MaterialsFactory {materials_list: all_materials}
     }
 
+    //Adds a material built from the world file, such as the phase function of a medium.
+    pub fn insert(&mut self, key: String, material: Arc<dyn Material>) {
+        self.materials_list.insert(key, material);
+    }
+
+    //Every material's name, sorted so a material's place in the list doesn't change from run to run.
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.materials_list.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
     pub fn get_material_by_key(&self, material_type: String) -> Arc<dyn Material> {
         let res = self.materials_list.get(material_type.as_str());
         match res {
@@ -0,0 +1,352 @@
+use super::geometry::*;
+use super::random;
+use super::ray::*;
+use cgmath::*;
+use std::fs;
+use std::sync::Arc;
+
+//How much of the medium there is at each point, relative to its extinction coefficient.
+pub trait Density: Send + Sync {
+    fn density(&self, p: &Vector3<f64>) -> f64;
+    //Upper bound of density() anywhere, used as the majorant for delta tracking.
+    fn max_density(&self) -> f64;
+    //Corners of the box density() is zero outside of, None when it reaches everywhere.
+    fn bounds(&self) -> Option<(Vector3<f64>, Vector3<f64>)> {
+        None
+    }
+}
+
+pub struct ConstantDensity {}
+
+impl Density for ConstantDensity {
+    fn density(&self, _: &Vector3<f64>) -> f64 {
+        1.0
+    }
+
+    fn max_density(&self) -> f64 {
+        1.0
+    }
+}
+
+//Fractal value noise, for smoke and patchy fog.
+pub struct NoiseDensity {
+    scale: f64,
+    octaves: u32,
+}
+
+impl NoiseDensity {
+    pub fn new(scale: f64, octaves: u32) -> NoiseDensity {
+        NoiseDensity { scale, octaves: octaves.max(1) }
+    }
+}
+
+impl Density for NoiseDensity {
+    fn density(&self, p: &Vector3<f64>) -> f64 {
+        let mut sum = 0.0;
+        let mut amplitude = 0.5;
+        let mut frequency = 1.0/self.scale;
+        for _ in 0..self.octaves {
+            sum += amplitude*value_noise(p*frequency);
+            amplitude *= 0.5;
+            frequency *= 2.0;
+        }
+        sum.clamp(0.0, 1.0)
+    }
+
+    fn max_density(&self) -> f64 {
+        1.0
+    }
+}
+
+fn lattice(x: i64, y: i64, z: i64) -> f64 {
+    let mut h = (x.wrapping_mul(73856093) ^ y.wrapping_mul(19349663) ^ z.wrapping_mul(83492791)) as u64;
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    (h & 0xffffff) as f64/(0xffffff as f64)
+}
+
+fn value_noise(p: Vector3<f64>) -> f64 {
+    let (x0, y0, z0) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let smooth = |t: f64| t*t*(3.0 - 2.0*t);
+    let (fx, fy, fz) = (smooth(p.x - x0), smooth(p.y - y0), smooth(p.z - z0));
+    let (x0, y0, z0) = (x0 as i64, y0 as i64, z0 as i64);
+    let lerp = |a: f64, b: f64, t: f64| a + (b - a)*t;
+    let corner = |dx: i64, dy: i64, dz: i64| lattice(x0 + dx, y0 + dy, z0 + dz);
+    lerp(
+        lerp(lerp(corner(0, 0, 0), corner(1, 0, 0), fx), lerp(corner(0, 1, 0), corner(1, 1, 0), fx), fy),
+        lerp(lerp(corner(0, 0, 1), corner(1, 0, 1), fx), lerp(corner(0, 1, 1), corner(1, 1, 1), fx), fy),
+        fz
+    )
+}
+
+//Voxel densities spread over the box from min to max, read from a plain text file:
+//"nx ny nz" followed by nx*ny*nz values with x changing fastest.
+pub struct GridDensity {
+    dims: [usize; 3],
+    values: Vec<f64>,
+    min: Vector3<f64>,
+    max: Vector3<f64>,
+    peak: f64,
+}
+
+impl GridDensity {
+    pub fn load(path: &str, min: Vector3<f64>, max: Vector3<f64>) -> Result<GridDensity, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut numbers = text.split_whitespace();
+        let mut dims = [0usize; 3];
+        for d in dims.iter_mut() {
+            *d = numbers.next()
+                .and_then(|n| n.parse().ok())
+                .ok_or("missing grid dimensions")?;
+        }
+        let values: Vec<f64> = numbers.map(|n| n.parse::<f64>()).collect::<Result<_, _>>().map_err(|e| e.to_string())?;
+        if values.len() != dims[0]*dims[1]*dims[2] || values.is_empty() {
+            return Err(format!("expected {} values, found {}", dims[0]*dims[1]*dims[2], values.len()));
+        }
+        let peak = values.iter().cloned().fold(0.0, f64::max);
+        Ok(GridDensity { dims, values, min, max, peak })
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize) -> f64 {
+        self.values[(z*self.dims[1] + y)*self.dims[0] + x]
+    }
+}
+
+impl Density for GridDensity {
+    //Trilinear interpolation between voxel centers, zero outside the grid.
+    fn density(&self, p: &Vector3<f64>) -> f64 {
+        let mut cell = [0usize; 3];
+        let mut frac = [0.0; 3];
+        for i in 0..3 {
+            let t = (p[i] - self.min[i])/(self.max[i] - self.min[i]);
+            if !(0.0..=1.0).contains(&t) {
+                return 0.0;
+            }
+            let g = (t*(self.dims[i] as f64) - 0.5).clamp(0.0, (self.dims[i] - 1) as f64);
+            cell[i] = (g.floor() as usize).min(self.dims[i].saturating_sub(2));
+            frac[i] = if self.dims[i] > 1 { g - cell[i] as f64 } else { 0.0 };
+        }
+        let next = |i: usize| (cell[i] + 1).min(self.dims[i] - 1);
+        let mut sum = 0.0;
+        for corner in 0..8 {
+            let pick = |i: usize| (corner >> i) & 1 == 1;
+            let x = if pick(0) { next(0) } else { cell[0] };
+            let y = if pick(1) { next(1) } else { cell[1] };
+            let z = if pick(2) { next(2) } else { cell[2] };
+            let mut weight = 1.0;
+            for (i, f) in frac.iter().enumerate() {
+                weight *= if pick(i) { *f } else { 1.0 - f };
+            }
+            sum += weight*self.voxel(x, y, z);
+        }
+        sum
+    }
+
+    fn max_density(&self) -> f64 {
+        self.peak
+    }
+
+    fn bounds(&self) -> Option<(Vector3<f64>, Vector3<f64>)> {
+        Some((self.min, self.max))
+    }
+}
+
+//Participating medium filling the inside of a boundary shape, or all of space when there
+//is no boundary. Hitting it means a scattering event, sampled by delta tracking; the
+//albedo is handed out as the color so absorption darkens each bounce.
+pub struct Volume {
+    boundary: Option<Arc<dyn Hitable>>,
+    density: Arc<dyn Density>,
+    extinction: f64,
+    albedo: Vector3<f64>,
+    center: Vector3<f64>,
+    phase: String,
+}
+
+impl Volume {
+    //absorption and scattering are coefficients per unit length where the density is 1.
+    pub fn new(boundary: Option<Arc<dyn Hitable>>, density: Arc<dyn Density>, absorption: f64, scattering: f64, tint: Vector3<f64>, phase: String) -> Volume {
+        let extinction = absorption + scattering;
+        let albedo = if extinction > 0.0 { tint*(scattering/extinction) } else { tint };
+        let center = boundary.as_ref().map(|b| *b.get_center()).unwrap_or_else(|| Vector3::new(0.0, 0.0, 0.0));
+        Volume { boundary, density, extinction, albedo, center, phase }
+    }
+
+    //Most steps a ray takes through the medium before it's let through, so thin density
+    //against a high majorant can't hold a thread up.
+    const MAX_STEPS: u32 = 100_000;
+
+    //Where the ray is inside the boundary, cut down to where there's any density at all.
+    fn spans(&self, r: &Ray) -> Vec<(f64, f64)> {
+        let spans = match &self.boundary {
+            Some(b) => b.hit_intervals(r),
+            None => vec![(0.0, f64::MAX)]
+        };
+        match self.density.bounds() {
+            Some((min, max)) => match slab(r, &min, &max) {
+                Some((b0, b1)) => spans.into_iter()
+                    .map(|(t0, t1)| (t0.max(b0), t1.min(b1)))
+                    .filter(|(t0, t1)| t0 < t1)
+                    .collect(),
+                None => vec![]
+            },
+            None => spans
+        }
+    }
+}
+
+//Span of t where the ray is inside the box from min to max.
+fn slab(r: &Ray, min: &Vector3<f64>, max: &Vector3<f64>) -> Option<(f64, f64)> {
+    let mut t0 = f64::MIN;
+    let mut t1 = f64::MAX;
+    for i in 0..3 {
+        let inv = 1.0/r.direction()[i];
+        let mut near = (min[i] - r.origin()[i])*inv;
+        let mut far = (max[i] - r.origin()[i])*inv;
+        if inv < 0.0 {
+            std::mem::swap(&mut near, &mut far);
+        }
+        //A ray running along one of the box's faces gives NaN here, which counts as a miss.
+        if near.is_nan() || far.is_nan() {
+            return None;
+        }
+        t0 = t0.max(near);
+        t1 = t1.min(far);
+    }
+    if t0 < t1 { Some((t0, t1)) } else { None }
+}
+
+impl Hitable for Volume {
+    //Woodcock tracking: take exponential steps against the majorant and accept each one
+    //with probability density/majorant.
+    fn hit(&self, r: &Ray, t_max: f64) -> f64 {
+        let majorant = self.extinction*self.density.max_density();
+        if majorant <= 0.0 {
+            return 0.0;
+        }
+        let len = r.direction().magnitude();
+        let mut steps = 0;
+        for (t0, t1) in self.spans(r) {
+            let mut t = t0.max(0.001);
+            let end = t1.min(t_max);
+            while t < end {
+                steps += 1;
+                if steps > Volume::MAX_STEPS {
+                    return 0.0;
+                }
+                t += -(1.0 - random::gen()).ln()/(majorant*len);
+                if t >= end {
+                    break;
+                }
+                let p = r.point_at_parameter(t);
+                if random::gen()*self.density.max_density() < self.density.density(&p) {
+                    return t;
+                }
+            }
+        }
+        0.0
+    }
+
+    fn get_center(&self) -> &Vector3<f64> {
+        &self.center
+    }
+
+    fn get_radius(&self) -> f64 {
+        self.boundary.as_ref().map(|b| b.get_radius()).unwrap_or(f64::MAX)
+    }
+
+    fn get_color(&self) -> &Vector3<f64> {
+        &self.albedo
+    }
+
+    fn get_material(&self) -> String {
+        self.phase.clone()
+    }
+
+    //Media have no surface, so face the normal back along the ray.
+    fn get_norm_at_p(&self, r: &Ray, _: &Vector3<f64>) -> Vector3<f64> {
+        -r.direction().normalize()
+    }
+
+    fn get_uv_at_p(&self, _: &Ray, _: &Vector3<f64>) -> (f64, f64) {
+        (0.0, 0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid() -> GridDensity {
+        GridDensity {
+            dims: [2, 2, 2],
+            values: vec![1.0; 8],
+            min: Vector3::new(-1.0, -1.0, -1.0),
+            max: Vector3::new(1.0, 1.0, 1.0),
+            peak: 1.0
+        }
+    }
+
+    fn fog(density: Arc<dyn Density>, scattering: f64) -> Volume {
+        Volume::new(None, density, 0.0, scattering, Vector3::new(1.0, 1.0, 1.0), "phase_0".to_string())
+    }
+
+    #[test]
+    fn grid_fog_only_scatters_inside_the_grid() {
+        let volume = fog(Arc::new(grid()), 50.0);
+        random::with_stream(Box::new(random::Seeded::new(5)), || {
+            let through = Ray::new_at(Vector3::new(0.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0), 0.0);
+            for _ in 0..100 {
+                let t = volume.hit(&through, f64::MAX);
+                assert!((4.0..=6.0).contains(&t), "{}", t);
+            }
+            //Used to walk out towards f64::MAX a mean free path at a time.
+            let past = Ray::new_at(Vector3::new(3.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0), 0.0);
+            assert_eq!(volume.hit(&past, f64::MAX), 0.0);
+            let leaving = Ray::new_at(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0), 0.0);
+            for _ in 0..100 {
+                let t = volume.hit(&leaving, f64::MAX);
+                assert!(t == 0.0 || t <= 1.0, "{}", t);
+            }
+        });
+    }
+
+    #[test]
+    fn constant_fog_has_the_right_mean_free_path() {
+        let volume = fog(Arc::new(ConstantDensity {}), 2.0);
+        let r = Ray::new_at(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0), 0.0);
+        let n = 20000;
+        let mean = random::with_stream(Box::new(random::Seeded::new(9)), || {
+            (0..n).map(|_| volume.hit(&r, f64::MAX)).sum::<f64>()/(n as f64)
+        });
+        assert!((mean - 0.5).abs() < 0.02, "{}", mean);
+    }
+
+    #[test]
+    fn thin_density_gives_up_after_max_steps() {
+        //A huge majorant over a density that's almost never there would otherwise take
+        //billions of steps to cross.
+        struct Sparse;
+        impl Density for Sparse {
+            fn density(&self, _: &Vector3<f64>) -> f64 {
+                0.0
+            }
+            fn max_density(&self) -> f64 {
+                1.0
+            }
+        }
+        let volume = fog(Arc::new(Sparse), 1e6);
+        let r = Ray::new_at(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0), 0.0);
+        assert_eq!(volume.hit(&r, f64::MAX), 0.0);
+    }
+
+    #[test]
+    fn grid_interpolates_between_voxels() {
+        let mut g = grid();
+        g.values = vec![0.0, 1.0, 0.0, 1.0, 0.0, 1.0, 0.0, 1.0];
+        assert!((g.density(&Vector3::new(0.0, 0.0, 0.0)) - 0.5).abs() < 1e-9);
+        assert!((g.density(&Vector3::new(0.5, 0.0, 0.0)) - 1.0).abs() < 1e-9);
+        assert_eq!(g.density(&Vector3::new(1.5, 0.0, 0.0)), 0.0);
+    }
+}
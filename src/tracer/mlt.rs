@@ -0,0 +1,303 @@
+use super::camera::*;
+use super::integrator::*;
+use super::random;
+use super::world::MltSettings;
+use super::film::Film;
+use super::Cancel;
+use cgmath::*;
+use rand::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+
+#[derive(Clone, Copy)]
+struct PrimarySample {
+    value: f64,
+    modified: u64,
+    backup: f64,
+    backup_modified: u64,
+}
+
+//The random numbers one path gets traced with. Samples are made the first time the path
+//asks for them and remember the iteration they last changed in, so ones the path hasn't
+//used since a large step can catch up when they're next asked for.
+struct PrimarySamples {
+    rng: XorShiftRng,
+    samples: Vec<PrimarySample>,
+    index: usize,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    settings: MltSettings,
+}
+
+impl PrimarySamples {
+    //Seeded, so a bootstrap path can be traced again to start a chain from it.
+    fn new(seed: u64, settings: &MltSettings) -> PrimarySamples {
+        PrimarySamples {
+            rng: random::rng(seed),
+            samples: vec![],
+            index: 0,
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+            settings: settings.clone(),
+        }
+    }
+
+    fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<f64>() < self.settings.large_step;
+        self.index = 0;
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    fn reject(&mut self) {
+        for x in self.samples.iter_mut() {
+            if x.modified == self.iteration {
+                x.value = x.backup;
+                x.modified = x.backup_modified;
+            }
+        }
+        self.iteration -= 1;
+    }
+
+    fn next(&mut self) -> f64 {
+        let i = self.index;
+        self.index += 1;
+        if i == self.samples.len() {
+            let value = self.rng.gen();
+            self.samples.push(PrimarySample { value, modified: self.iteration, backup: value, backup_modified: self.iteration });
+            return value;
+        }
+
+        let x = &mut self.samples[i];
+        if x.modified < self.last_large_step {
+            x.value = self.rng.gen();
+            x.modified = self.last_large_step;
+        }
+        x.backup = x.value;
+        x.backup_modified = x.modified;
+        if self.large_step {
+            x.value = self.rng.gen();
+        } else {
+            //Kelemen's mutation: an exponentially distributed nudge either way, wrapping around.
+            let (s1, s2) = (self.settings.mutation_min, self.settings.mutation_max);
+            let dv = s2*(-(s2/s1).ln()*self.rng.gen::<f64>()).exp();
+            if self.rng.gen::<f64>() < 0.5 {
+                x.value += dv;
+                if x.value >= 1.0 {
+                    x.value -= 1.0;
+                }
+            } else {
+                x.value -= dv;
+                if x.value < 0.0 {
+                    x.value += 1.0;
+                }
+            }
+        }
+        x.modified = self.iteration;
+        x.value
+    }
+}
+
+struct SampleStream(Rc<RefCell<PrimarySamples>>);
+
+impl random::Stream for SampleStream {
+    fn next(&mut self) -> f64 {
+        self.0.borrow_mut().next()
+    }
+}
+
+//Primary sample space Metropolis light transport (Kelemen et al.) over another integrator.
+//A chain mutates the random numbers a path was traced with, keeping changes in
+//proportion to how bright the new path is, so it lingers on paths that are hard to find.
+//Each job starts a chain of its own from a bootstrap path, makes ns mutations for every
+//pixel of its tile and splats every path it visits, wherever on the image that lands.
+pub struct Mlt {
+    inner: Arc<dyn Integrator>,
+    settings: MltSettings,
+    //Average luminance of a path through a random point on the image.
+    brightness: f64,
+    //Running total of bootstrap luminance, for picking where chains start.
+    bootstrap: Vec<f64>,
+    seed: u64,
+}
+
+impl Mlt {
+    //The inner integrator's splats are dropped, so it should be one that doesn't make any.
+    pub fn new(inner: Arc<dyn Integrator>, cam: &Camera, settings: &MltSettings, seed: u64) -> Mlt {
+        let mut mlt = Mlt { inner, settings: settings.clone(), brightness: 0.0, bootstrap: vec![], seed };
+        let mut total = 0.0;
+        for i in 0..settings.bootstrap.max(1) as u64 {
+            let samples = Rc::new(RefCell::new(PrimarySamples::new(random::hash(&[seed, i]), settings)));
+            let (_, _, col) = mlt.trace(cam, &samples);
+            total += luminance(&col);
+            mlt.bootstrap.push(total);
+        }
+        mlt.brightness = total/(mlt.bootstrap.len() as f64);
+        if mlt.brightness <= 0.0 {
+            println!("Error, no bootstrap path found any light. The image will be black");
+        }
+        mlt
+    }
+
+    //Image position and radiance of the path the samples describe.
+    fn trace(&self, cam: &Camera, samples: &Rc<RefCell<PrimarySamples>>) -> (f64, f64, Vector3<f64>) {
+        random::with_stream(Box::new(SampleStream(samples.clone())), || {
+            let u = random::gen();
+            let v = random::gen();
+            let col = self.inner.render(cam, u, v, &mut |_, _, _| {});
+            if col.x.is_finite() && col.y.is_finite() && col.z.is_finite() {
+                (u, v, col)
+            } else {
+                (u, v, Vector3::zero())
+            }
+        })
+    }
+
+    //Runs one chain of ns mutations for each of the pixels it was given, splatting into
+    //film and reporting the mutations as it goes. Chains with the same number always take
+    //the same path. Gives back false if the render was cancelled before the chain finished.
+    #[allow(clippy::too_many_arguments)]
+    pub fn render(&self, cam: &Camera, chain: u64, pixels: u32, ns: u32, film: &mut Film, cancel: &Cancel, report: &mut dyn FnMut(u64)) -> bool {
+        if self.brightness <= 0.0 {
+            film.add_samples((pixels as u64)*(ns as u64));
+            report((pixels as u64)*(ns as u64));
+            return true;
+        }
+        let brightness = self.brightness;
+        let mut taken = 0;
+        let mut splat = |u: f64, v: f64, col: Vector3<f64>| film.add_splat(u, v, col*brightness);
+
+        //Start from a bootstrap path picked in proportion to its luminance, then give the
+        //chain its own rng so chains starting from the same path drift apart.
+        let mut rng = random::rng(random::hash(&[self.seed, chain]));
+        let total = *self.bootstrap.last().unwrap();
+        let pick = rng.gen::<f64>()*total;
+        let start = self.bootstrap.iter().position(|&sum| sum > pick).unwrap_or(self.bootstrap.len() - 1);
+        let samples = Rc::new(RefCell::new(PrimarySamples::new(random::hash(&[self.seed, start as u64]), &self.settings)));
+        let mut current = self.trace(cam, &samples);
+        samples.borrow_mut().rng = random::rng(rng.gen());
+
+        for _ in 0..pixels {
+            if cancel.stopped() {
+                break;
+            }
+            for _ in 0..ns {
+                samples.borrow_mut().start_iteration();
+                let proposed = self.trace(cam, &samples);
+                let y_current = luminance(&current.2);
+                let y_proposed = luminance(&proposed.2);
+                let accept = if y_current > 0.0 { (y_proposed/y_current).min(1.0) } else { 1.0 };
+
+                //Both paths get splatted, weighted by how likely each one is kept.
+                if y_proposed > 0.0 {
+                    splat(proposed.0, proposed.1, proposed.2*(accept/y_proposed));
+                }
+                if y_current > 0.0 {
+                    splat(current.0, current.1, current.2*((1.0 - accept)/y_current));
+                }
+
+                if rng.gen::<f64>() < accept {
+                    current = proposed;
+                    samples.borrow_mut().accept();
+                } else {
+                    samples.borrow_mut().reject();
+                }
+            }
+            taken += ns as u64;
+            report(ns as u64);
+        }
+        //Splats are shared out over the mutations, so only the ones made count.
+        film.add_samples(taken);
+        taken == (pixels as u64)*(ns as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::world::World;
+
+    fn samples(large_step: f64) -> PrimarySamples {
+        PrimarySamples::new(9, &MltSettings { large_step, ..MltSettings::default() })
+    }
+
+    fn values(samples: &PrimarySamples) -> Vec<f64> {
+        samples.samples.iter().map(|x| x.value).collect()
+    }
+
+    #[test]
+    fn rejecting_puts_the_samples_back() {
+        let mut samples = samples(0.0);
+        samples.start_iteration();
+        let first: Vec<f64> = (0..3).map(|_| samples.next()).collect();
+        samples.accept();
+
+        //Only the first two are used this time, the third has to be left alone.
+        samples.start_iteration();
+        let (a, b) = (samples.next(), samples.next());
+        assert!(a != first[0] && b != first[1]);
+        samples.reject();
+        assert_eq!(values(&samples), first);
+        assert!(samples.samples.iter().all(|x| x.modified == 1));
+        assert_eq!(samples.iteration, 1);
+    }
+
+    #[test]
+    fn samples_catch_up_on_missed_large_steps() {
+        let mut samples = samples(1.0);
+        samples.start_iteration();
+        let first = (samples.next(), samples.next());
+        samples.accept();
+        //A large step that only uses the first sample.
+        samples.start_iteration();
+        samples.next();
+        samples.accept();
+        assert_eq!(samples.last_large_step, 2);
+
+        //The second sample missed that step, so it's drawn afresh before being nudged, and
+        //a rejection goes back to the fresh value rather than the old one.
+        samples.settings.large_step = 0.0;
+        samples.start_iteration();
+        let nudged = samples.next();
+        let caught_up = samples.next();
+        assert!(caught_up != first.1);
+        samples.reject();
+        let (x0, x1) = (samples.samples[0], samples.samples[1]);
+        assert!(x0.value != nudged && x0.modified == 2);
+        assert!(x1.value != first.1 && x1.modified == 2);
+    }
+
+    #[test]
+    fn brightness_agrees_with_the_path_tracer() {
+        let mean = |integrator: &str| {
+            let mut world = World::from_json(r#"{
+                "camera": { "lookfrom": [0.0, 1.0, 6.0], "lookat": [0.0, -1.0, 0.0], "fov": 60.0 },
+                "planes": [{ "origin": [0.0, -1.0, 0.0], "normal": [0.0, 1.0, 0.0], "color": [0.8, 0.8, 0.8], "mat": "flat" }],
+                "spheres": [
+                    { "center": [2.0, 0.0, 0.0], "radius": 1.0, "color": [0.7, 0.5, 0.3], "mat": "flat" },
+                    { "center": [0.0, 4.0, 0.0], "radius": 1.0, "color": [1.0, 1.0, 1.0], "mat": "diffuse_light" }
+                ]
+            }"#, 32, 24).unwrap();
+            let mut settings = world.get_settings();
+            settings.integrator = integrator.to_string();
+            settings.samples = 128;
+            settings.max_depth = 5;
+            settings.seed = 3;
+            settings.threads = 4;
+            world.set_settings(settings);
+            let image = super::super::render(&world).unwrap();
+            image.pixels.iter().map(|p| (p[0] + p[1] + p[2]) as f64).sum::<f64>()/(image.pixels.len() as f64)
+        };
+        let (path, mlt) = (mean("path"), mean("mlt"));
+        assert!(path > 0.0);
+        assert!((mlt - path).abs() < 0.05*path, "mlt {} against path {}", mlt, path);
+    }
+}
@@ -7,155 +7,744 @@ mod camera;
 mod material;
 mod light;
 mod scene;
-mod world;
+pub(crate) mod world;
+mod transform;
+mod csg;
+mod sdf;
+mod heightfield;
+mod medium;
+mod bdpt;
+mod random;
+mod mlt;
+mod integrator;
+mod debug;
+mod whitted;
+mod sampler;
+mod film;
+mod denoise;
+mod aov;
+mod checkpoint;
+mod progress;
+mod stats;
 use cgmath::*;
 use rand::*;
-use crate::tracer::image::GenericImage;
 
 use crate::tracer::scene::*;
 use std::thread;
 use std::sync::mpsc;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+//Running totals for one pixel while its tile renders.
+#[derive(Clone, Copy)]
+struct PixelStats {
+    lum: f64,
+    lum2: f64,
+    n: u32,
+}
+
+impl PixelStats {
+    fn add(&mut self, col: Vector3<f64>) {
+        let lum = integrator::luminance(&col);
+        self.lum += lum;
+        self.lum2 += lum*lum;
+        self.n += 1;
+    }
+
+    //Standard error of the mean luminance relative to the mean. Dark pixels are measured
+    //against a floor so a little noise in the black doesn't keep them going forever.
+    fn error(&self) -> f64 {
+        if self.n < 2 {
+            return f64::MAX;
+        }
+        let n = self.n as f64;
+        let mean = self.lum/n;
+        let variance = (self.lum2/n - mean*mean).max(0.0)*n/(n - 1.0);
+        (variance/n).sqrt()/mean.max(0.01)
+    }
+}
+
+//What render threads send back: samples taken and what the rays did so far on a job, then
+//the finished job itself and whether it got all of its samples.
+enum Message {
+    Progress(usize, u64, stats::Counters),
+    Done(usize, film::Film, Option<aov::Aovs>, bool),
+}
+
+//Stops one render early, set by Ctrl-C, by whoever started it or when the time budget runs
+//out. Threads finish up and whatever has been rendered gets written. Clones share the flag.
+#[derive(Clone, Default)]
+pub struct Cancel {
+    flag: Arc<AtomicBool>,
+}
+
+impl Cancel {
+    pub fn new() -> Cancel {
+        Cancel::default()
+    }
+
+    pub fn stop(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+
+    //Stops it, giving back whether it had been stopped already.
+    fn swap(&self) -> bool {
+        self.flag.swap(true, Ordering::SeqCst)
+    }
+}
+
+//Block of pixels from (x0, y0) up to but not including (x1, y1).
+#[derive(Clone, Copy)]
+pub struct Tile {
+    x0: u32,
+    y0: u32,
+    x1: u32,
+    y1: u32,
+}
+
+const TILE_SIZE: u32 = 16;
+//Most samples a pixel can get when adaptive sampling spends a budget, in samples per pixel.
+const MAX_SHARE: u32 = 4;
+
+impl Tile {
+    //Noise of the kth pixel, taken column by column. A pixel whose samples have all come
+    //out the same, say because it missed every light so far, borrows half its noisiest
+    //neighbour's instead so it isn't stopped as if it were done.
+    fn noise(&self, stats: &[PixelStats], k: usize) -> f64 {
+        let error = stats[k].error();
+        if error > 0.0 {
+            return error;
+        }
+        let rows = (self.y1 - self.y0) as i64;
+        let cols = (self.x1 - self.x0) as i64;
+        let (x, y) = ((k as i64)/rows, (k as i64) % rows);
+        let mut worst: f64 = 0.0;
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx >= 0 && nx < cols && ny >= 0 && ny < rows {
+                    worst = worst.max(stats[(nx*rows + ny) as usize].error());
+                }
+            }
+        }
+        0.5*worst
+    }
+}
+
+//Cuts the image into the same tiles however many threads there are, row by row.
+fn tiles(width: u32, height: u32) -> Vec<Tile> {
+    let mut tiles = vec![];
+    for y0 in (0..height).step_by(TILE_SIZE as usize) {
+        for x0 in (0..width).step_by(TILE_SIZE as usize) {
+            tiles.push(Tile { x0, y0, x1: (x0 + TILE_SIZE).min(width), y1: (y0 + TILE_SIZE).min(height) });
+        }
+    }
+    tiles
+}
+
+//Renders the given samples of every pixel of a tile into film, which should be the
+//tile's own. Adaptive sampling with a threshold treats the length of the range as the most
+//a pixel can have, spending a budget lets a pixel go up to MAX_SHARE times that.
+//Gives back false if the render was cancelled before the tile got all of them.
+#[allow(clippy::too_many_arguments)]
 pub fn render_section(
-    width_seg: Vec<u32>,
-    height: u32,
-    width: u32,
-    scene: Arc<scene::Scene>,
-    cam: Arc<camera::Camera>,
-    ns: u32,
-    timer_start: std::sync::mpsc::Sender<f64>,
-) -> Vec<Vec<u32>> {
-    let mut ret_vec: Vec<Vec<u32>> = Vec::new();
-    let mut rng = rand::thread_rng();
-    for i in width_seg[0]..width_seg[1] {
-        for j in 0..height {
-            let tstart = Instant::now();
-            let mut col = Vector3::new(0.0, 0.0, 0.0);
-            //Sample per pixel.
-            for _ in 0..=ns {
-                let r1: f64 = rng.gen();
-                let r2: f64 = rng.gen();
-                let u = ((i as f64) + r1) / (width as f64);
-                let v = ((j as f64) + r2) / (height as f64);
-                let r = cam.get_ray(u, v);
-                col += scene.render(&r, 0, std::f64::MAX);
+    tile: Tile,
+    film: &mut film::Film,
+    integrator: &Arc<dyn integrator::Integrator>,
+    sampler: &Arc<dyn sampler::Sampler>,
+    cam: &Arc<camera::Camera>,
+    samples: Range<u32>,
+    adaptive: &world::AdaptiveSettings,
+    mut aovs: Option<&mut aov::Aovs>,
+    cancel: &Cancel,
+    report: &mut dyn FnMut(u64),
+) -> bool {
+    let ns = samples.end - samples.start;
+    let (width, height) = (film.image_width() as f64, film.image_height() as f64);
+    let mut take = |stats: &mut PixelStats, i: u32, j: u32, count: u32| {
+        let before = stats.n;
+        for _ in 0..count {
+            if cancel.stopped() {
+                break;
             }
+            random::with_stream(sampler::stream(sampler.clone(), i, j, samples.start + stats.n), || {
+                let x = (i as f64) + random::gen();
+                let y = (j as f64) + random::gen();
+                let mut splat = |u, v, col| film.add_splat(u, v, col);
+                let col = match aovs.as_mut() {
+                    Some(aovs) => {
+                        let (col, lighting) = integrator.render_passes(cam, x/width, y/height, &mut splat);
+                        aovs.add_sample(x, y, lighting.as_ref());
+                        col
+                    },
+                    None => integrator.render(cam, x/width, y/height, &mut splat)
+                };
+                film.add_sample(x, y, col);
+                stats.add(col);
+            });
+        }
+        report((stats.n - before) as u64);
+    };
+
+    let mut coords = vec![];
+    for i in tile.x0..tile.x1 {
+        for j in tile.y0..tile.y1 {
+            coords.push((i, j));
+        }
+    }
+    let empty = PixelStats { lum: 0.0, lum2: 0.0, n: 0 };
+    let mut stats = vec![empty; coords.len()];
+
+    //Sample per pixel. Noise needs two samples to go on, unless there's only one to give.
+    let first = if adaptive.enabled { adaptive.min_samples.max(2).min(ns) } else { ns };
+    for (k, &(i, j)) in coords.iter().enumerate() {
+        take(&mut stats[k], i, j, first);
+    }
+
+    let batch = adaptive.min_samples.max(1);
+    if adaptive.enabled && adaptive.threshold > 0.0 {
+        //Rounds over the tile until every pixel is quiet enough or out of samples.
+        loop {
+            let errors: Vec<f64> = (0..stats.len()).map(|k| tile.noise(&stats, k)).collect();
+            let mut busy = false;
+            for (k, &(i, j)) in coords.iter().enumerate() {
+                if stats[k].n < ns && errors[k] > adaptive.threshold {
+                    let count = batch.min(ns - stats[k].n);
+                    take(&mut stats[k], i, j, count);
+                    busy = true;
+                }
+            }
+            if !busy || cancel.stopped() {
+                break;
+            }
+        }
+    } else if adaptive.enabled {
+        //Whatever the tile's budget has left goes to its noisiest pixel, a batch at a time,
+        //up to MAX_SHARE times its even share so one firefly can't take the whole tile.
+        let budget = (ns as u64)*(coords.len() as u64);
+        let cap = ns.saturating_mul(MAX_SHARE);
+        let mut left = budget.saturating_sub(stats.iter().map(|s| s.n as u64).sum());
+        while left > 0 {
+            let (k, error) = (0..stats.len()).map(|k| if stats[k].n < cap { tile.noise(&stats, k) } else { 0.0 })
+                .enumerate()
+                .fold((0, 0.0), |best, (k, e)| if e > best.1 { (k, e) } else { best });
+            if error <= 0.0 {
+                break;
+            }
+            let count = (batch as u64).min(left).min((cap - stats[k].n) as u64) as u32;
+            take(&mut stats[k], coords[k].0, coords[k].1, count);
+            left -= count as u64;
+        }
+    }
+
+    film.add_samples(stats.iter().map(|s| s.n as u64).sum());
+    !cancel.stopped()
+}
+
+//Where the binary's render writes to as it goes. Renders for the library leave it out and
+//only get the finished image back.
+struct Output<'a> {
+    path: &'a str,
+    resume: Option<&'a str>,
+    //Sums up the world and image size, so a checkpoint isn't picked up by some other render.
+    key: u64,
+}
+
+impl<'a> Output<'a> {
+    fn checkpoint(&self) -> PathBuf {
+        Path::new(self.path).with_extension("checkpoint")
+    }
+}
 
-            col /= (ns) as f64;
-            //col = Vector3::new(col.x.sqrt(), col.y.sqrt(), col.z.sqrt());
-            col.x = col.x.min(1.0);
-            col.y = col.y.min(1.0);
-            col.z = col.z.min(1.0);
-
-            let ir = (255.99*col.x) as u8;
-            let ig = (255.99*col.y) as u8;
-            let ib = (255.99*col.z) as u8;
-            let mut pix = [0;4];
-            pix[0] = ir;
-            pix[1] = ig;
-            pix[2] = ib;
-            pix[3] = 1;
-            ret_vec.push(vec![
-                i, 
-                j as u32,
-                pix[0] as u32,
-                pix[1] as u32,
-                pix[2] as u32,
-                pix[3] as u32,
-                1
-                ]);
-            
-            timer_start.send(tstart.elapsed().as_secs_f64()).unwrap();
-        }
-    }
-    ret_vec
-}
-
-pub fn generate(path: &str, width: u32, height: u32) -> std::io::Result<()> {
+//Float image a render hands back, row by row from the top left.
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[f32; 3]>,
+}
+
+impl Image {
+    pub fn pixel(&self, x: u32, y: u32) -> [f32; 3] {
+        self.pixels[(y*self.width + x) as usize]
+    }
+}
+
+//Renders the world in world_file into an image at path. With resume, it carries on from
+//that checkpoint. Once cancel is stopped, whatever has been rendered gets written.
+pub fn generate(world_file: &str, path: &str, width: u32, height: u32, resume: Option<&str>, cancel: &Cancel) -> std::io::Result<()> {
+    let mut stats = stats::Stats::new();
+    let world = world::World::new(world_file, width, height)?;
+    stats.phase("load");
+    //Checkpoints only fit renders of the same world and settings at the same size, though
+    //they can be picked up with more threads or time.
+    let world_bytes: Vec<u64> = world::image_description(world_file)?.bytes().map(|b| b as u64).collect();
+    let key = random::hash(&[random::hash(&world_bytes), width as u64, height as u64]);
+
+    let output = Output { path, resume, key };
+    let (film, aovs) = render_world(&world, Some(&output), cancel, &mut stats)?;
+    let settings = world.get_settings();
+    save(path, &film, aovs.as_ref(), &settings)?;
+    stats.phase("output");
+    report(&stats, &settings)
+}
+
+//Renders the world and hands back the image, denoised if the settings say so. Nothing is
+//written, apart from the progress stream and statistics when the settings ask for them,
+//and nothing is printed but errors.
+pub fn render(world: &world::World) -> std::io::Result<Image> {
+    render_cancellable(world, &Cancel::new())
+}
+
+//Same as render, stopping early once cancel is stopped from another thread. The image then
+//has every pixel averaging the samples it got.
+pub fn render_cancellable(world: &world::World, cancel: &Cancel) -> std::io::Result<Image> {
+    let mut stats = stats::Stats::new();
+    let (film, _) = render_world(world, None, cancel, &mut stats)?;
+    let settings = world.get_settings();
+    let pixels = if settings.denoise.enabled { denoise::denoise(&film, &settings.denoise) } else { film.pixels() };
+    let image = Image {
+        width: film.image_width(),
+        height: film.image_height(),
+        pixels: pixels.iter().map(|p| [p.x as f32, p.y as f32, p.z as f32]).collect(),
+    };
+    stats.phase("output");
+    if !settings.stats.is_empty() {
+        stats.write(&settings.stats)?;
+    }
+    Ok(image)
+}
+
+fn report(stats: &stats::Stats, settings: &world::Settings) -> std::io::Result<()> {
+    stats.print();
+    if !settings.stats.is_empty() {
+        stats.write(&settings.stats)?;
+    }
+    Ok(())
+}
+
+//Renders the world into a film, along with its passes if it has any. With an output it
+//resumes, writes checkpoints and snapshots there as it goes.
+fn render_world(world: &world::World, output: Option<&Output>, cancel: &Cancel, stats: &mut stats::Stats) -> std::io::Result<(film::Film, Option<aov::Aovs>)> {
     let current_time = Instant::now();
-    let mut img = image::DynamicImage::new_rgb8(width, height);
-    let ns = 5000;
-    let world = world::World::new("worlds/closed_room.json", width, height)?;
+    let (width, height) = (world.width(), world.height());
+    let scene = Arc::new(Scene::new(world.get_hitables(), world.get_materials(), world.get_lights()));
+    let settings = world.get_settings();
+    let ns = settings.samples.max(1);
+    let cam = world.get_camera();
+    //Anything drawn while setting up comes from the seed too, so runs match exactly.
+    let (integrator, mlt) = random::with_stream(Box::new(random::Seeded::new(settings.seed)), || {
+        let integrator = integrator::by_name(scene.clone(), &settings);
+        let mlt = match settings.integrator.as_str() {
+            "mlt" => Some(Arc::new(mlt::Mlt::new(integrator.clone(), &cam, &settings.mlt, settings.seed))),
+            _ => None
+        };
+        (integrator, mlt)
+    });
+    let sampler = sampler::by_name(&settings.sampler, ns, settings.seed);
+    let filter = film::by_name(&settings.filter);
+    let aov_passes = aov::passes(&settings.aovs.passes);
+    if aov_passes.iter().any(|p| p.is_lighting()) && settings.integrator != "path" {
+        println!("Error, only the path integrator splits light into passes. The lighting passes will be black");
+    }
+    let surfaces = if aov_passes.iter().any(|p| p.is_surface()) {
+        Some(Arc::new(aov::SurfaceTracer::new(scene.clone())))
+    } else {
+        None
+    };
+    let features = if settings.denoise.enabled {
+        Some(Arc::new(denoise::FeatureTracer::new(scene.clone(), settings.max_depth)))
+    } else {
+        None
+    };
+    let num_of_threads = settings.threads.max(1);
 
-    let scene = Arc::new(Scene::new(world.get_hitables()));
-    let num_of_threads = 8;
+    //Without progressive rendering the whole image is one pass of every sample.
+    let pass_samples = if settings.progressive.enabled { settings.progressive.pass_samples.clamp(1, ns) } else { ns };
+    let passes = ns.div_ceil(pass_samples);
+    let mut adaptive = settings.adaptive.clone();
+    if adaptive.enabled && passes > 1 {
+        println!("Error, adaptive sampling doesn't work with progressive passes. Turning it off");
+        adaptive.enabled = false;
+    }
 
-    let width_list = 0..width;
-    let width_len  = width_list.len();
-    let width_per_thread = width_len/num_of_threads;
-    let mut width_parallel: Vec<Vec<u32>> = Vec::new();
+    //Threads take the next tile left until there are none, a pass at a time.
+    let tiles = Arc::new(tiles(width, height));
+    let jobs = (passes as usize)*tiles.len();
 
-    for i in 1..=num_of_threads {
-        let entry = ((i-1) as u32)*(width_per_thread as u32);
-        let last_entry = (i as u32)*(width_per_thread as u32);
-        width_parallel.push(vec![entry, last_entry]);
+    let new_films = || {
+        let film = film::Film::new(width, height, filter.clone());
+        let aovs = if aov_passes.is_empty() { None } else { Some(aov::Aovs::new(&aov_passes, width, height, &filter)) };
+        (film, aovs)
+    };
+    let (mut film, mut aovs) = new_films();
+    let mut done = 0;
+    if let Some((resume, key)) = output.and_then(|o| o.resume.map(|r| (r, o.key))) {
+        match checkpoint::load(Path::new(resume), key, &mut film, aovs.as_mut()) {
+            Some(d) => {
+                done = d.min(jobs);
+                println!("Resuming from {}, {}/{} tiles done.", resume, done, jobs);
+            },
+            None => {
+                println!("Starting from the beginning.");
+                let (f, a) = new_films();
+                film = f;
+                aovs = a;
+            }
+        }
     }
+    let checkpoints = if settings.checkpoint.enabled { output } else { None };
+    let next_job = Arc::new(AtomicUsize::new(done));
+
+    //How many samples a job is given, which is what it counts for once it's done.
+    let job_samples = |job: usize| {
+        let (pass, tile) = ((job/tiles.len()) as u32, &tiles[job % tiles.len()]);
+        let pixels = ((tile.x1 - tile.x0)*(tile.y1 - tile.y0)) as u64;
+        pixels*((((pass + 1)*pass_samples).min(ns) - pass*pass_samples) as u64)
+    };
+    //Only the binary's renders print how they're getting on.
+    let console = output.is_some();
+    let mut progress = progress::Progress::new(
+        &settings.progress,
+        (ns as u64)*(width as u64)*(height as u64),
+        (0..done).map(job_samples).sum(),
+        console
+    );
 
-    //Send a render_section() result back to main thread.
+    //Send progress and render_section() results back to main thread.
     let (tx,rx) = mpsc::channel();
 
-    //timer
-    let (timer_send, timer_recv) = mpsc::channel();
+    //Setting up Metropolis traces its rays on this thread.
+    stats.counters.add(&stats::take());
+    stats.phase("build");
 
-    let mut thread_data = Vec::new();
-    let cam = world.get_camera();
-    
-    for width_seg in width_parallel {
-        let scene = scene.clone();
+    let mut workers = Vec::with_capacity(num_of_threads);
+    for _ in 0..num_of_threads {
         let cam = cam.clone();
+        let integrator = integrator.clone();
+        let sampler = sampler.clone();
+        let mlt = mlt.clone();
+        let tiles = tiles.clone();
+        let next_job = next_job.clone();
         let tx = tx.clone();
-        let timer_send = timer_send.clone();
-        thread::spawn(move || {
-            tx.send(render_section(width_seg, height, width, scene, cam, ns, timer_send)).unwrap();
-        });
+        let adaptive = adaptive.clone();
+        let filter = filter.clone();
+        let features = features.clone();
+        let (feature_samples, seed) = (settings.denoise.feature_samples, settings.seed);
+        let aov_passes = aov_passes.clone();
+        let surfaces = surfaces.clone();
+        let surface_samples = settings.aovs.samples;
+        let cancel = cancel.clone();
+        workers.push(thread::spawn(move || {
+            loop {
+                if cancel.stopped() {
+                    break;
+                }
+                let job = next_job.fetch_add(1, Ordering::SeqCst);
+                if job >= jobs {
+                    break;
+                }
+                let (pass, tile) = ((job/tiles.len()) as u32, tiles[job % tiles.len()]);
+                let samples = pass*pass_samples..((pass + 1)*pass_samples).min(ns);
+                let mut section = film::Film::for_tile(&tile, width, height, filter.clone());
+                let mut aovs = if aov_passes.is_empty() { None } else { Some(aov::Aovs::for_tile(&aov_passes, &tile, width, height, &filter)) };
+                //Nobody is listening once the render has given up, so there's nothing to do if
+                //sending fails.
+                let mut report = |n| { let _ = tx.send(Message::Progress(job, n, stats::take())); };
+                //Metropolis chains wander the whole image, so a tile only sets how much work its chain does.
+                let complete = match &mlt {
+                    Some(mlt) => mlt.render(&cam, job as u64, (tile.x1 - tile.x0)*(tile.y1 - tile.y0), samples.end - samples.start, &mut section, &cancel, &mut report),
+                    None => render_section(tile, &mut section, &integrator, &sampler, &cam, samples, &adaptive, aovs.as_mut(), &cancel, &mut report)
+                };
+                //What pixels see doesn't change between passes, so it's only gathered once.
+                if pass == 0 {
+                    stats::aside(|| {
+                        if let Some(features) = &features {
+                            features.gather(&tile, &mut section, &cam, feature_samples, seed);
+                        }
+                        if let (Some(surfaces), Some(aovs)) = (&surfaces, aovs.as_mut()) {
+                            surfaces.gather(&tile, aovs, &cam, surface_samples, seed);
+                        }
+                    });
+                    report(0);
+                }
+                if tx.send(Message::Done(job, section, aovs, complete)).is_err() {
+                    break;
+                }
+            }
+        }));
     }
+    drop(tx);
+    let deadline = if settings.time_limit > 0.0 { Some(current_time + Duration::from_secs_f64(settings.time_limit)) } else { None };
 
-    let mut collect_time = true;
-    for _ in 0..num_of_threads {
-        //Calculate time remaining.
-        if collect_time {
-            //Take thirty values for an average.
-            let mut possible_timer: Vec<f64> = vec![];
-            while possible_timer.len() < 500 {
-                possible_timer.push(timer_recv.recv().unwrap_or(0.0));
+    //Tiles are added up in order whichever thread finishes first, so the sums come out
+    //the same to the last bit.
+    let mut waiting = BTreeMap::new();
+    let mut next_sum = done;
+    let mut last_snapshot = Instant::now();
+    let mut last_checkpoint = Instant::now();
+    //Set once a tile that was cut off partway gets added, after which a checkpoint
+    //couldn't carry on exactly.
+    let mut cut_short = false;
+    let mut failed = None;
+    'merge: loop {
+        if let Some(deadline) = deadline {
+            if Instant::now() >= deadline && !cancel.swap() {
+                progress.message("Out of time, writing out what's done so far.");
             }
-            let average: f64 = possible_timer.iter().sum::<f64>() / (possible_timer.len() as f64);
-            if average != 0.0 {
-                println!("Time per pixel: {:?} sec(s), 
-                    estimated time remaining: {:?} min(s)", 
-                    average,
-
-                    average 
-                    / 60.0 
-                    * ((((width as f64) 
-                    * (height as f64))))
-                    / (num_of_threads as f64)
-                );
+        }
+
+        //Find all threads values, until they've all stopped.
+        let (job, section, section_aovs, complete) = match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(Message::Progress(job, samples, counters)) => {
+                progress.add(job, samples, counters.rays());
+                stats.counters.add(&counters);
+                progress.show();
+                continue;
+            },
+            Ok(Message::Done(job, section, section_aovs, complete)) => (job, section, section_aovs, complete),
+            Err(RecvTimeoutError::Timeout) => {
+                progress.show();
+                continue;
+            },
+            Err(RecvTimeoutError::Disconnected) => break
+        };
+        if complete {
+            progress.finish_job(job, job_samples(job));
+        }
+        waiting.insert(job, (section, section_aovs, complete));
+        while let Some((section, section_aovs, complete)) = waiting.remove(&next_sum) {
+            if !complete && !cut_short {
+                cut_short = true;
+                if let Some(output) = checkpoints {
+                    if let Err(e) = checkpoint::save(&output.checkpoint(), output.key, next_sum, &film, aovs.as_ref()) {
+                        failed = Some(e);
+                        break 'merge;
+                    }
+                    progress.message(&format!("Checkpoint written to {}, {}/{} tiles done.", output.checkpoint().display(), next_sum, jobs));
+                }
+            }
+            film.merge(&section);
+            if let (Some(aovs), Some(section_aovs)) = (aovs.as_mut(), section_aovs.as_ref()) {
+                aovs.merge(section_aovs);
+            }
+            next_sum += 1;
+            if cut_short {
+                continue;
+            }
+
+            //A pass has just finished, write what there is so far if it's time.
+            let pass = next_sum/tiles.len();
+            if let Some(output) = output {
+                if next_sum % tiles.len() == 0 && next_sum < jobs
+                    && last_snapshot.elapsed().as_secs_f64() >= settings.progressive.interval {
+                    progress.message(&format!("Pass {}/{} done, {} samples per pixel. Writing snapshot.", pass, passes, ((pass as u32)*pass_samples).min(ns)));
+                    if let Err(e) = save(output.path, &film, aovs.as_ref(), &settings) {
+                        failed = Some(e);
+                        break 'merge;
+                    }
+                    last_snapshot = Instant::now();
+                }
+            }
+
+            if let Some(output) = checkpoints {
+                if next_sum < jobs && last_checkpoint.elapsed().as_secs_f64() >= settings.checkpoint.interval {
+                    if let Err(e) = checkpoint::save(&output.checkpoint(), output.key, next_sum, &film, aovs.as_ref()) {
+                        failed = Some(e);
+                        break 'merge;
+                    }
+                    progress.message(&format!("Checkpoint written to {}, {}/{} tiles done.", output.checkpoint().display(), next_sum, jobs));
+                    last_checkpoint = Instant::now();
+                }
             }
-            collect_time = false;
         }
+    }
+
+    //Writing failed, so the threads are stopped and waited for rather than left rendering.
+    if let Some(e) = failed {
+        cancel.stop();
+        for worker in workers {
+            let _ = worker.join();
+        }
+        progress.finish(true);
+        return Err(e);
+    }
+
+    stats.phase("render");
+    progress.finish(next_sum < jobs || cut_short);
+
+    if next_sum < jobs || cut_short {
+        //Each pixel is an average of the samples it got, so a stopped render is only
+        //noisier, with tiles nobody got to left black.
+        progress.message(&format!("Stopped early, {}/{} tiles done.", next_sum, jobs));
+        if let (Some(output), false) = (checkpoints, cut_short) {
+            checkpoint::save(&output.checkpoint(), output.key, next_sum, &film, aovs.as_ref())?;
+            progress.message(&format!("Checkpoint written to {}, --resume carries on from it.", output.checkpoint().display()));
+        }
+    } else if let Some(output) = checkpoints.filter(|o| o.checkpoint().exists()) {
+        //A finished render has nothing to resume.
+        std::fs::remove_file(output.checkpoint())?;
+    }
+
+    progress.message("Threads finished, compiling image.");
+
+    progress.message(&format!("Total time taken: {:?} min(s)", current_time.elapsed().as_secs_f64()/60.0));
+
+    Ok((film, aovs))
+}
+
+//Writes the image, and its passes if there are any.
+fn save(path: &str, film: &film::Film, aovs: Option<&aov::Aovs>, settings: &world::Settings) -> std::io::Result<()> {
+    let image = if settings.denoise.enabled {
+        println!("Denoising.");
+        denoise::denoise(film, &settings.denoise)
+    } else {
+        film.pixels()
+    };
+    if let Some(aovs) = aovs {
+        aov::save(path, &image, aovs, settings)?;
+    }
+    film::save(path, film.image_width(), film.image_height(), &image)
+}
 
-        //Find all threads values.
-        thread_data.push(rx.recv().unwrap());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small_room(threads: usize) -> world::World {
+        let mut world = world::World::from_json(include_str!("../../worlds/closed_room.json"), 40, 20).unwrap();
+        let mut settings = world.get_settings();
+        settings.seed = 7;
+        settings.samples = 8;
+        settings.threads = threads;
+        world.set_settings(settings);
+        world
+    }
+
+    #[test]
+    fn thread_count_does_not_change_the_image() {
+        let one = render(&small_room(1)).unwrap();
+        assert!(one.pixels.iter().any(|p| p[0] > 0.0));
+        for threads in [3, 8].iter() {
+            let image = render(&small_room(*threads)).unwrap();
+            assert_eq!((image.width, image.height), (40, 20));
+            assert!(one.pixels == image.pixels, "{} threads gave a different image", threads);
+        }
     }
 
-    println!("Threads finished, compiling image.");
+    //Samples render_section gives the first tile of the small room.
+    fn tile_samples(ns: u32, adaptive: &world::AdaptiveSettings) -> u64 {
+        let world = small_room(1);
+        let settings = world.get_settings();
+        let scene = Arc::new(Scene::new(world.get_hitables(), world.get_materials(), world.get_lights()));
+        let integrator = integrator::by_name(scene, &settings);
+        let sampler = sampler::by_name(&settings.sampler, ns, settings.seed);
+        let tile = tiles(40, 20).remove(0);
+        let mut film = film::Film::for_tile(&tile, 40, 20, film::by_name(&settings.filter));
+        let mut taken = 0;
+        render_section(tile, &mut film, &integrator, &sampler, &world.get_camera(), 0..ns, adaptive, None, &Cancel::new(), &mut |n| taken += n);
+        taken
+    }
+
+    #[test]
+    fn adaptive_sampling_keeps_to_the_sample_count() {
+        let pixels = (TILE_SIZE*TILE_SIZE) as u64;
+        let budget = world::AdaptiveSettings { enabled: true, min_samples: 4, threshold: 0.0 };
+        assert_eq!(tile_samples(1, &budget), pixels);
+        assert_eq!(tile_samples(8, &budget), 8*pixels);
+        let threshold = world::AdaptiveSettings { enabled: true, min_samples: 4, threshold: 1e-9 };
+        assert_eq!(tile_samples(1, &threshold), pixels);
+        assert_eq!(tile_samples(8, &threshold), 8*pixels);
+    }
 
-    for first in &*thread_data {
-        for pix in first {
-            let mut pixels = [0u8;4];
-            pixels[0] = (pix[2] as u8).into();
-            pixels[1] = (pix[3] as u8).into();
-            pixels[2] = (pix[4] as u8).into();
-            pixels[3] = (pix[5] as u8).into();
-            img.put_pixel(pix[0], pix[1], image::Rgba(pixels));
+    #[test]
+    fn a_stopped_render_only_stops_itself() {
+        let cancel = Cancel::new();
+        cancel.stop();
+        for integrator in ["path", "mlt"].iter() {
+            let mut world = small_room(2);
+            let mut settings = world.get_settings();
+            settings.integrator = integrator.to_string();
+            world.set_settings(settings);
+            let image = render_cancellable(&world, &cancel).unwrap();
+            assert!(image.pixels.iter().all(|p| *p == [0.0; 3]), "{}", integrator);
         }
+        assert!(render(&small_room(2)).unwrap().pixels.iter().any(|p| p[0] > 0.0));
     }
 
-    println!("Total time taken: {:?} min(s)", current_time.elapsed().as_secs_f64()/60.0);
+    #[test]
+    fn resuming_gives_the_uninterrupted_image() {
+        let mut world = world::World::from_json(include_str!("../../worlds/closed_room.json"), 96, 48).unwrap();
+        let mut settings = small_room(1).get_settings();
+        settings.samples = 16;
+        settings.checkpoint.enabled = true;
+        settings.checkpoint.interval = 1000.0;
+        world.set_settings(settings);
+        let (whole, _) = render_world(&world, None, &Cancel::new(), &mut stats::Stats::new()).unwrap();
 
-    img.save(path)
-}
\ No newline at end of file
+        //Wherever the stop lands, carrying on has to add exactly what's missing.
+        let path = std::env::temp_dir().join(format!("rustytracer_resume_{}.png", std::process::id()));
+        let output = Output { path: path.to_str().unwrap(), resume: None, key: 5 };
+        let cancel = Cancel::new();
+        let stopper = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(500));
+            stopper.stop();
+        });
+        render_world(&world, Some(&output), &cancel, &mut stats::Stats::new()).unwrap();
+        let checkpoint = output.checkpoint();
+        assert!(checkpoint.exists(), "the render finished before it could be stopped");
+        let output = Output { path: output.path, resume: checkpoint.to_str(), key: 5 };
+        let (resumed, _) = render_world(&world, Some(&output), &Cancel::new(), &mut stats::Stats::new()).unwrap();
+        assert!(whole.pixels() == resumed.pixels());
+        assert!(!checkpoint.exists());
+    }
+
+    #[test]
+    fn a_failed_write_stops_the_render() {
+        let mut world = small_room(2);
+        let mut settings = world.get_settings();
+        settings.checkpoint.enabled = true;
+        settings.checkpoint.interval = 0.0;
+        world.set_settings(settings);
+        let path = std::env::temp_dir().join("rustytracer_missing").join("image.png");
+        let output = Output { path: path.to_str().unwrap(), resume: None, key: 5 };
+        let cancel = Cancel::new();
+        assert!(render_world(&world, Some(&output), &cancel, &mut stats::Stats::new()).is_err());
+        assert!(cancel.stopped());
+    }
+
+    #[test]
+    fn worlds_built_in_code_match_world_files() {
+        let json = r#"{
+            "camera": { "lookfrom": [0.0, 0.0, 0.0], "lookat": [0.0, 0.0, -1.0], "fov": 90.0 },
+            "planes": [{ "origin": [0.0, -1.0, 0.0], "normal": [0.0, 1.0, 0.0], "color": [0.8, 0.8, 0.8], "mat": "flat" }],
+            "spheres": [
+                { "center": [0.0, 0.0, -3.0], "radius": 1.0, "color": [1.0, 1.0, 1.0], "mat": "glass" },
+                { "center": [0.0, 5.0, -3.0], "radius": 2.0, "color": [1.0, 1.0, 1.0], "mat": "diffuse_light" }
+            ],
+            "boxes": [{ "min": [1.0, -1.0, -4.0], "max": [2.0, 0.0, -3.0], "color": [0.5, 0.2, 0.2], "mat": "metal" }]
+        }"#;
+        let mut from_file = world::World::from_json(json, 32, 16).unwrap();
+        let mut in_code = world::World::empty(32, 16);
+        in_code.add_plane([0.0, -1.0, 0.0], [0.0, 1.0, 0.0], [0.8, 0.8, 0.8], "flat");
+        in_code.add_sphere([0.0, 0.0, -3.0], 1.0, [1.0, 1.0, 1.0], "glass");
+        in_code.add_sphere([0.0, 5.0, -3.0], 2.0, [1.0, 1.0, 1.0], "diffuse_light");
+        in_code.add_box([1.0, -1.0, -4.0], [2.0, 0.0, -3.0], [0.5, 0.2, 0.2], "metal");
+        let settings = small_room(2).get_settings();
+        from_file.set_settings(settings.clone());
+        in_code.set_settings(settings);
+        let image = render(&in_code).unwrap();
+        assert!(image.pixels.iter().any(|p| p[0] > 0.0));
+        assert!(render(&from_file).unwrap().pixels == image.pixels);
+    }
+}
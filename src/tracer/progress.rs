@@ -0,0 +1,214 @@
+use super::world::ProgressSettings;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{IsTerminal, Write};
+use std::time::Instant;
+
+//Keeps track of how far a render has got from what the threads report as they go, and
+//shows it. Jobs still running count for the samples they've taken, finished ones for
+//every sample they were given, since adaptive sampling can leave some untaken.
+pub struct Progress {
+    start: Instant,
+    //Samples the whole render is made of, and how many of those were done before this run.
+    total: u64,
+    resumed: u64,
+    finished: u64,
+    running: HashMap<usize, u64>,
+    //Actually taken and traced during this run, for the rates.
+    samples: u64,
+    rays: u64,
+    interval: f64,
+    log_interval: f64,
+    last_line: Instant,
+    last_event: Instant,
+    //Whether to print at all. A terminal gets one line redrawn in place, anything else a
+    //line now and then.
+    console: bool,
+    live: bool,
+    stream: Option<Box<dyn Write>>,
+}
+
+impl Progress {
+    pub fn new(settings: &ProgressSettings, total: u64, resumed: u64, console: bool) -> Progress {
+        let stream: Option<Box<dyn Write>> = match settings.stream.as_str() {
+            "" => None,
+            "stderr" => Some(Box::new(std::io::stderr())),
+            path => match File::create(path) {
+                Ok(file) => Some(Box::new(file)),
+                Err(e) => {
+                    println!("Error, couldn't open progress stream {}: {}", path, e);
+                    None
+                }
+            }
+        };
+        Progress {
+            start: Instant::now(),
+            total: total.max(1),
+            resumed,
+            finished: resumed,
+            running: HashMap::new(),
+            samples: 0,
+            rays: 0,
+            interval: settings.interval,
+            log_interval: settings.log_interval,
+            last_line: Instant::now(),
+            last_event: Instant::now(),
+            console,
+            live: std::io::stdout().is_terminal(),
+            stream,
+        }
+    }
+
+    //A job took some more samples, tracing rays for them.
+    pub fn add(&mut self, job: usize, samples: u64, rays: u64) {
+        *self.running.entry(job).or_insert(0) += samples;
+        self.samples += samples;
+        self.rays += rays;
+    }
+
+    //A job is done and counts for all the samples it was given.
+    pub fn finish_job(&mut self, job: usize, samples: u64) {
+        self.running.remove(&job);
+        self.finished += samples;
+    }
+
+    fn fraction(&self) -> f64 {
+        let running: u64 = self.running.values().sum();
+        ((self.finished + running) as f64/(self.total as f64)).min(1.0)
+    }
+
+    //Seconds left going by how fast this run has got through the render so far.
+    fn remaining(&self, elapsed: f64) -> Option<f64> {
+        let fraction = self.fraction();
+        let gained = fraction - (self.resumed as f64)/(self.total as f64);
+        if gained <= 0.0 || elapsed <= 0.0 {
+            return None;
+        }
+        Some((1.0 - fraction)*elapsed/gained)
+    }
+
+    //Shows the progress and sends it down the stream, each if it's been long enough since
+    //last time.
+    pub fn show(&mut self) {
+        let line_wait = if self.live { self.interval } else { self.log_interval };
+        let line_due = self.console && self.last_line.elapsed().as_secs_f64() >= line_wait;
+        let event_due = self.stream.is_some() && self.last_event.elapsed().as_secs_f64() >= self.interval;
+        if !line_due && !event_due {
+            return;
+        }
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let remaining = self.remaining(elapsed);
+        if line_due {
+            self.last_line = Instant::now();
+            let line = format!(
+                "{:5.1}% | {} rays/s | {} samples/s | elapsed {} | remaining {}",
+                self.fraction()*100.0,
+                rate(self.rays as f64/elapsed),
+                rate(self.samples as f64/elapsed),
+                clock(elapsed),
+                remaining.map(clock).unwrap_or_else(|| "?".to_string())
+            );
+            if self.live {
+                print!("\r{}\x1b[K", line);
+                std::io::stdout().flush().ok();
+            } else {
+                println!("{}", line);
+            }
+        }
+        if event_due {
+            self.last_event = Instant::now();
+            self.event("progress", json!({ "remaining": remaining }));
+        }
+    }
+
+    //Prints a line without it getting tangled up in the live one.
+    pub fn message(&mut self, text: &str) {
+        if !self.console {
+            return;
+        }
+        if self.live {
+            print!("\r\x1b[K");
+        }
+        println!("{}", text);
+    }
+
+    //Last update, once the threads have all stopped.
+    pub fn finish(&mut self, stopped_early: bool) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let line = format!(
+            "{:.1}% done, {} rays and {} samples in {}, {} rays/s.",
+            self.fraction()*100.0,
+            self.rays,
+            self.samples,
+            clock(elapsed),
+            rate(self.rays as f64/elapsed)
+        );
+        self.message(&line);
+        self.event("finished", json!({ "stopped_early": stopped_early }));
+    }
+
+    //Writes one JSON line to the stream with the common fields plus extra.
+    fn event(&mut self, kind: &str, extra: serde_json::Value) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let mut line = json!({
+            "event": kind,
+            "fraction": self.fraction(),
+            "samples": self.samples,
+            "rays": self.rays,
+            "samples_per_sec": self.samples as f64/elapsed.max(1e-9),
+            "rays_per_sec": self.rays as f64/elapsed.max(1e-9),
+            "elapsed": elapsed,
+        });
+        if let (Some(line), Some(extra)) = (line.as_object_mut(), extra.as_object()) {
+            line.extend(extra.clone());
+        }
+        if let Some(stream) = self.stream.as_mut() {
+            writeln!(stream, "{}", line).and_then(|_| stream.flush()).ok();
+        }
+    }
+}
+
+//1234567.0 as "1.23M".
+fn rate(x: f64) -> String {
+    if x >= 1e9 {
+        format!("{:.2}G", x/1e9)
+    } else if x >= 1e6 {
+        format!("{:.2}M", x/1e6)
+    } else if x >= 1e3 {
+        format!("{:.2}k", x/1e3)
+    } else {
+        format!("{:.0}", x)
+    }
+}
+
+//Seconds as hours:minutes:seconds.
+fn clock(seconds: f64) -> String {
+    let s = seconds.max(0.0).round() as u64;
+    format!("{}:{:02}:{:02}", s/3600, (s/60) % 60, s % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_and_clocks_read_naturally() {
+        assert_eq!(rate(950.0), "950");
+        assert_eq!(rate(1_234_567.0), "1.23M");
+        assert_eq!(rate(2.5e9), "2.50G");
+        assert_eq!(clock(3725.4), "1:02:05");
+        assert_eq!(clock(-1.0), "0:00:00");
+    }
+
+    #[test]
+    fn remaining_time_only_counts_this_run() {
+        //A quarter was done before resuming, and this run has done another quarter in 10s.
+        let mut progress = Progress::new(&ProgressSettings::default(), 400, 100, false);
+        progress.add(0, 60, 0);
+        progress.finish_job(1, 40);
+        assert!((progress.fraction() - 0.5).abs() < 1e-12);
+        assert!((progress.remaining(10.0).unwrap() - 20.0).abs() < 1e-9);
+        assert_eq!(Progress::new(&ProgressSettings::default(), 400, 100, false).remaining(10.0), None);
+    }
+}
@@ -0,0 +1,62 @@
+use rand::*;
+use std::cell::RefCell;
+
+//Where the numbers tracing draws come from. Integrators can hand the current thread a
+//stream of their own, otherwise it's the thread's rng.
+pub trait Stream {
+    //Uniform number in [0, 1).
+    fn next(&mut self) -> f64;
+}
+
+thread_local! {
+    static STREAM: RefCell<Option<Box<dyn Stream>>> = RefCell::new(None);
+}
+
+pub fn gen() -> f64 {
+    STREAM.with(|s| match s.borrow_mut().as_mut() {
+        Some(stream) => stream.next(),
+        None => rand::thread_rng().gen()
+    })
+}
+
+//Runs f with every gen() on this thread reading from stream, then goes back to whatever
+//stream was there before.
+pub fn with_stream<R>(stream: Box<dyn Stream>, f: impl FnOnce() -> R) -> R {
+    let previous = STREAM.with(|s| s.borrow_mut().replace(stream));
+    let result = f();
+    STREAM.with(|s| *s.borrow_mut() = previous);
+    result
+}
+
+//Plain rng stream started from a seed, for work that isn't tied to a pixel sample.
+pub struct Seeded(XorShiftRng);
+
+impl Seeded {
+    pub fn new(seed: u64) -> Seeded {
+        Seeded(rng(seed))
+    }
+}
+
+impl Stream for Seeded {
+    fn next(&mut self) -> f64 {
+        self.0.gen()
+    }
+}
+
+//Xorshift's first outputs follow its seed closely, so the seed gets hashed first.
+pub fn rng(seed: u64) -> XorShiftRng {
+    let z = hash(&[seed]);
+    XorShiftRng::from_seed([z as u32, (z >> 32) as u32, 0x243f_6a88, 0x85a3_08d3])
+}
+
+//Splitmix64 over the values, so nearby inputs give unrelated outputs.
+pub fn hash(values: &[u64]) -> u64 {
+    let mut h = 0u64;
+    for v in values {
+        let mut z = (h ^ v).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        h = z ^ (z >> 31);
+    }
+    h
+}
@@ -3,12 +3,18 @@ use cgmath::*;
 
 pub struct Ray {
     a: Vector3<f64>,
-    b: Vector3<f64>
+    b: Vector3<f64>,
+    time: f64
 }
 
 impl Ray {
     pub fn new_from(a: Vector3<f64>, b: Vector3<f64>) -> Ray {
-        Ray { a: a, b: b }
+        Ray { a, b, time: 0.0 }
+    }
+
+    //Ray that exists at a specific instant during the camera's shutter interval.
+    pub fn new_at(a: Vector3<f64>, b: Vector3<f64>, time: f64) -> Ray {
+        Ray { a, b, time }
     }
 
     pub fn origin(&self) -> &Vector3<f64> {
@@ -19,7 +25,11 @@ impl Ray {
         &self.b
     }
 
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
     pub fn point_at_parameter(&self, t: f64) -> Vector3<f64> {
         self.a + t*self.b
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,360 @@
+use super::random;
+use super::random::hash;
+use rand::*;
+use std::sync::Arc;
+
+//Hands out the numbers each sample of a pixel is traced with. Every call to random::gen()
+//during a sample reads the next dimension, so the camera gets the first few and the
+//materials, lights and media the rest. The same pixel, sample and dimension always give
+//the same number, whichever thread asks.
+pub trait Sampler: Send + Sync {
+    //Uniform number in [0, 1) for one dimension of a sample.
+    fn get(&self, x: u32, y: u32, index: u32, dim: u32) -> f64;
+}
+
+//Walks through the dimensions of one sample.
+struct SampleStream {
+    sampler: Arc<dyn Sampler>,
+    x: u32,
+    y: u32,
+    index: u32,
+    dim: u32,
+}
+
+impl random::Stream for SampleStream {
+    fn next(&mut self) -> f64 {
+        let value = self.sampler.get(self.x, self.y, self.index, self.dim);
+        self.dim += 1;
+        value
+    }
+}
+
+//Stream for sample index of pixel (x, y), to hand to random::with_stream.
+pub fn stream(sampler: Arc<dyn Sampler>, x: u32, y: u32, index: u32) -> Box<dyn random::Stream> {
+    Box::new(SampleStream { sampler, x, y, index, dim: 0 })
+}
+
+//Builds the sampler the settings name. Samples is how many each pixel gets, which the
+//stratified and multi-jittered samplers lay out their patterns for.
+pub fn by_name(name: &str, samples: u32, seed: u64) -> Arc<dyn Sampler> {
+    match name {
+        "independent" => Arc::new(Independent { seed }),
+        "stratified" => Arc::new(Stratified { seed, samples: samples.max(1) }),
+        "halton" => Arc::new(Halton { seed }),
+        "sobol" => Arc::new(Sobol { seed }),
+        "pmj" => Arc::new(Pmj::new(samples, seed)),
+        _ => {
+            println!("Error, sampler '{}' not found. Using sobol", name);
+            Arc::new(Sobol { seed })
+        }
+    }
+}
+
+//Plain random numbers, the baseline the others improve on.
+pub struct Independent {
+    seed: u64,
+}
+
+impl Sampler for Independent {
+    fn get(&self, x: u32, y: u32, index: u32, dim: u32) -> f64 {
+        to_unit(hash(&[self.seed, x as u64, y as u64, index as u64, dim as u64]))
+    }
+}
+
+//Splits every dimension into one stratum per sample and gives each sample its own
+//stratum, shuffled differently per dimension (a Latin hypercube), so any count works.
+pub struct Stratified {
+    seed: u64,
+    samples: u32,
+}
+
+impl Sampler for Stratified {
+    fn get(&self, x: u32, y: u32, index: u32, dim: u32) -> f64 {
+        let h = hash(&[self.seed, x as u64, y as u64, dim as u64]);
+        let stratum = permute(index % self.samples, self.samples, h as u32);
+        let jitter = to_unit(hash(&[h, index as u64]));
+        ((stratum as f64) + jitter)/(self.samples as f64)
+    }
+}
+
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131
+];
+
+//Radical inverse of the sample index in a different prime base per dimension, shifted by
+//a random offset per pixel and dimension. Dimensions past the prime table are random,
+//since Halton's high bases correlate badly.
+pub struct Halton {
+    seed: u64,
+}
+
+impl Sampler for Halton {
+    fn get(&self, x: u32, y: u32, index: u32, dim: u32) -> f64 {
+        let h = hash(&[self.seed, x as u64, y as u64, dim as u64]);
+        if dim as usize >= PRIMES.len() {
+            return to_unit(hash(&[h, index as u64]));
+        }
+        let value = radical_inverse(index, PRIMES[dim as usize]) + to_unit(h);
+        if value >= 1.0 { value - 1.0 } else { value }
+    }
+}
+
+fn radical_inverse(mut i: u32, base: u32) -> f64 {
+    let inv = 1.0/(base as f64);
+    let mut f = inv;
+    let mut value = 0.0;
+    while i > 0 {
+        value += ((i % base) as f64)*f;
+        i /= base;
+        f *= inv;
+    }
+    value
+}
+
+//The first two Sobol dimensions, Owen scrambled with Burley's hash and padded out: each
+//pair of dimensions shuffles the sample order and scrambles the points its own way.
+pub struct Sobol {
+    seed: u64,
+}
+
+impl Sampler for Sobol {
+    fn get(&self, x: u32, y: u32, index: u32, dim: u32) -> f64 {
+        let h = hash(&[self.seed, x as u64, y as u64, (dim/2) as u64]);
+        let i = owen_scramble(index, h as u32);
+        let bits = if dim.is_multiple_of(2) { i.reverse_bits() } else { sobol_second(i) };
+        let scrambled = owen_scramble(bits, ((h >> 32) as u32) ^ (dim % 2));
+        (scrambled as f64)/4_294_967_296.0
+    }
+}
+
+fn sobol_second(mut i: u32) -> u32 {
+    let mut v = 1u32 << 31;
+    let mut bits = 0;
+    while i != 0 {
+        if i & 1 != 0 {
+            bits ^= v;
+        }
+        i >>= 1;
+        v ^= v >> 1;
+    }
+    bits
+}
+
+//Nested uniform scramble of the bits of x, most significant first.
+fn owen_scramble(x: u32, seed: u32) -> u32 {
+    let mut x = x.reverse_bits();
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x.reverse_bits()
+}
+
+const PMJ_SETS: u64 = 16;
+
+//Progressive multi-jittered points (Christensen et al.), made up front. Pairs of
+//dimensions read a table picked per pixel, shifted by a random offset. Any prefix of a
+//table is well stratified, so stopping at any sample count is fine.
+pub struct Pmj {
+    seed: u64,
+    tables: Vec<Vec<(f64, f64)>>,
+}
+
+impl Pmj {
+    pub fn new(samples: u32, seed: u64) -> Pmj {
+        let mut size = 1;
+        while size < samples.max(1) as usize {
+            size *= 4;
+        }
+        let tables = (0..PMJ_SETS).map(|set| pmj_table(size, hash(&[seed, set]))).collect();
+        Pmj { seed, tables }
+    }
+}
+
+impl Sampler for Pmj {
+    fn get(&self, x: u32, y: u32, index: u32, dim: u32) -> f64 {
+        let size = self.tables[0].len();
+        let h = hash(&[self.seed, x as u64, y as u64, (dim/2) as u64]);
+        let table = &self.tables[(h.wrapping_add((index as usize/size) as u64) % PMJ_SETS) as usize];
+        let point = table[index as usize % size];
+        let value = if dim.is_multiple_of(2) { point.0 } else { point.1 };
+        let value = value + to_unit(hash(&[h, dim as u64 % 2]));
+        if value >= 1.0 { value - 1.0 } else { value }
+    }
+}
+
+//Builds size points, size being a power of 4, by repeatedly filling in the subquadrants
+//the existing points leave empty while keeping every point in its own row and column.
+fn pmj_table(size: usize, seed: u64) -> Vec<(f64, f64)> {
+    let mut rng = random::rng(seed);
+    let mut points = vec![(rng.gen::<f64>(), rng.gen::<f64>())];
+    while points.len() < size {
+        let n = points.len();
+        let cells = (n as f64).sqrt().round() as usize;
+
+        //Diagonally opposite subquadrant to each existing point.
+        let (mut xs, mut ys) = pmj_occupied(&points, 2*n);
+        for k in 0..n {
+            let (i, j, xhalf, yhalf) = pmj_cell(points[k], cells);
+            let p = pmj_point(&mut rng, (i, j), (1 - xhalf, 1 - yhalf), cells, &mut xs, &mut ys);
+            points.push(p);
+        }
+
+        //The two subquadrants left, in random order.
+        let (mut xs, mut ys) = pmj_occupied(&points, 4*n);
+        let mut second = vec![];
+        for k in 0..n {
+            let (i, j, xhalf, yhalf) = pmj_cell(points[k], cells);
+            let (xhalf, yhalf) = if rng.gen::<f64>() < 0.5 { (1 - xhalf, yhalf) } else { (xhalf, 1 - yhalf) };
+            points.push(pmj_point(&mut rng, (i, j), (xhalf, yhalf), cells, &mut xs, &mut ys));
+            second.push(pmj_point(&mut rng, (i, j), (1 - xhalf, 1 - yhalf), cells, &mut xs, &mut ys));
+        }
+        points.append(&mut second);
+    }
+    points
+}
+
+//Cell of a cells by cells grid the point is in, and which half of the cell on each axis.
+fn pmj_cell(p: (f64, f64), cells: usize) -> (usize, usize, usize, usize) {
+    let (x, y) = (p.0*(cells as f64), p.1*(cells as f64));
+    let (i, j) = (x.floor(), y.floor());
+    (i as usize, j as usize, ((x - i)*2.0) as usize, ((y - j)*2.0) as usize)
+}
+
+//Which of strata rows and columns the points already take up.
+fn pmj_occupied(points: &[(f64, f64)], strata: usize) -> (Vec<bool>, Vec<bool>) {
+    let mut xs = vec![false; strata];
+    let mut ys = vec![false; strata];
+    for p in points {
+        xs[((p.0*(strata as f64)) as usize).min(strata - 1)] = true;
+        ys[((p.1*(strata as f64)) as usize).min(strata - 1)] = true;
+    }
+    (xs, ys)
+}
+
+//Random point in the given half of the cell on each axis, retried until its row and
+//column are free. Gives up after a while rather than looping forever on a bad layout.
+fn pmj_point(rng: &mut XorShiftRng, cell: (usize, usize), half: (usize, usize), cells: usize, xs: &mut [bool], ys: &mut [bool]) -> (f64, f64) {
+    let pick = |rng: &mut XorShiftRng, c: usize, h: usize, taken: &mut [bool]| {
+        let strata = taken.len();
+        let mut v = 0.0;
+        for _ in 0..1000 {
+            v = ((c as f64) + 0.5*((h as f64) + rng.gen::<f64>()))/(cells as f64);
+            if !taken[((v*(strata as f64)) as usize).min(strata - 1)] {
+                break;
+            }
+        }
+        taken[((v*(strata as f64)) as usize).min(strata - 1)] = true;
+        v
+    };
+    let x = pick(rng, cell.0, half.0, xs);
+    let y = pick(rng, cell.1, half.1, ys);
+    (x, y)
+}
+
+//Kensler's hashed permutation: where i lands in a shuffle of 0..l picked by p.
+fn permute(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    i.wrapping_add(p) % l
+}
+
+fn to_unit(h: u64) -> f64 {
+    ((h >> 11) as f64)/9_007_199_254_740_992.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Whether every box of 1/cols by 1/rows holds exactly one of the points.
+    fn one_per_box(points: &[(f64, f64)], cols: usize, rows: usize) -> bool {
+        let mut counts = vec![0; cols*rows];
+        for p in points {
+            let i = ((p.0*(cols as f64)) as usize).min(cols - 1);
+            let j = ((p.1*(rows as f64)) as usize).min(rows - 1);
+            counts[j*cols + i] += 1;
+        }
+        counts.iter().all(|&c| c == 1)
+    }
+
+    //Whether 16 points are a (0, 4, 2)-net: one in every box of area 1/16.
+    fn is_net(points: &[(f64, f64)]) -> bool {
+        [(1, 16), (2, 8), (4, 4), (8, 2), (16, 1)].iter().all(|&(c, r)| one_per_box(points, c, r))
+    }
+
+    #[test]
+    fn stratified_gives_every_sample_its_own_stratum() {
+        let sampler = by_name("stratified", 16, 7);
+        for dim in 0..6 {
+            let points: Vec<(f64, f64)> = (0..16).map(|i| (sampler.get(3, 5, i, dim), 0.5)).collect();
+            assert!(one_per_box(&points, 16, 1));
+        }
+    }
+
+    #[test]
+    fn sobol_pixels_are_nets() {
+        let sampler = by_name("sobol", 16, 7);
+        for (x, y) in [(0, 0), (3, 5), (100, 40)].iter() {
+            for pair in 0..3 {
+                let points: Vec<(f64, f64)> = (0..16).map(|i| (sampler.get(*x, *y, i, 2*pair), sampler.get(*x, *y, i, 2*pair + 1))).collect();
+                assert!(is_net(&points));
+            }
+        }
+    }
+
+    #[test]
+    fn pmj_tables_are_stratified_at_every_power_of_four() {
+        for seed in 0..4 {
+            let table = pmj_table(64, seed);
+            assert_eq!(table.len(), 64);
+            assert!(one_per_box(&table[..4], 2, 2));
+            assert!(one_per_box(&table[..16], 4, 4));
+            assert!(one_per_box(&table[..16], 16, 1) && one_per_box(&table[..16], 1, 16));
+            assert!(one_per_box(&table, 8, 8));
+            assert!(one_per_box(&table, 64, 1) && one_per_box(&table, 1, 64));
+        }
+    }
+
+    #[test]
+    fn samplers_stay_in_the_unit_interval() {
+        for name in ["independent", "stratified", "halton", "sobol", "pmj"].iter() {
+            let sampler = by_name(name, 8, 1);
+            for i in 0..32 {
+                for dim in 0..40 {
+                    let v = sampler.get(1, 2, i, dim);
+                    assert!((0.0..1.0).contains(&v), "{} gave {}", name, v);
+                }
+            }
+        }
+    }
+}
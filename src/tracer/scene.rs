@@ -1,36 +1,85 @@
 use super::geometry::*;
-use super::light::*;
 use super::ray::*;
 use super::material::*;
+use super::light::Light;
 use cgmath::*;
+use super::stats;
 use std::sync::Arc;
-extern crate rand;
-
-use rand::*;
 
 pub struct Scene {
     renderables: Vec<Arc<dyn Hitable>>,
     materials: MaterialsFactory,
+    point_lights: Vec<Arc<Light>>,
 }
 
 impl Scene {
-    pub fn new(render_list: Vec<Arc<dyn Hitable>>) -> Scene {
-        Scene { renderables: render_list, materials: MaterialsFactory::new() }
+    pub fn new(render_list: Vec<Arc<dyn Hitable>>, materials: MaterialsFactory, point_lights: Vec<Arc<Light>>) -> Scene {
+        Scene { renderables: render_list, materials, point_lights }
+    }
+
+    //Each hitable is only asked once, since media sample a new distance every time.
+    pub fn get_closest_intersection(&self, ray: &Ray, t_max: f64) -> Option<(f64, &Arc<dyn Hitable>)> {
+        self.get_closest_intersection_among(ray, t_max, &[])
+    }
+
+    //Only asks the hitables whose entry in keep is true, or all of them when keep is empty.
+    pub fn get_closest_intersection_among(&self, ray: &Ray, t_max: f64, keep: &[bool]) -> Option<(f64, &Arc<dyn Hitable>)> {
+        let (t, closest, tests) = self.closest(ray, t_max, keep);
+        stats::count(|c| {
+            c.traced += 1;
+            c.tests += tests;
+        });
+        closest.map(|hitable| (t, hitable))
+    }
+
+    //Whether anything in keep, or anything at all when it's empty, is in the ray's way
+    //before t_max.
+    pub fn blocked(&self, ray: &Ray, t_max: f64, keep: &[bool]) -> bool {
+        let (_, closest, tests) = self.closest(ray, t_max, keep);
+        stats::count(|c| {
+            c.shadow += 1;
+            c.tests += tests;
+        });
+        closest.is_some()
     }
 
-    pub fn get_closest_intersection(&self, ray: &Ray) -> f64 {
-        let mut t = std::f64::MAX;
-        for hitable in &*self.renderables {
-            let t2 = hitable.hit(ray, std::f64::MAX);
+    fn closest(&self, ray: &Ray, t_max: f64, keep: &[bool]) -> (f64, Option<&Arc<dyn Hitable>>, u64) {
+        let mut t = t_max;
+        let mut closest = None;
+        let mut tests = 0;
+        for (i, hitable) in self.renderables.iter().enumerate() {
+            if !keep.is_empty() && !keep[i] {
+                continue;
+            }
+            tests += 1;
+            let t2 = hitable.hit(ray, t);
             if t > t2 && t2 != 0.0 {
                 t = t2;
+                closest = Some(hitable);
             }
         }
+        (t, closest, tests)
+    }
 
-        if t == std::f64::MAX {
-            t = -1.0;
-        }
-        t        
+    pub fn get_hitables(&self) -> &[Arc<dyn Hitable>] {
+        &self.renderables
+    }
+
+    pub fn get_materials(&self) -> &MaterialsFactory {
+        &self.materials
+    }
+
+    //Lights the world file lists, which only the Whitted preview uses.
+    pub fn get_point_lights(&self) -> &[Arc<Light>] {
+        &self.point_lights
+    }
+
+    //Emitters whose surface can be sampled, for integrators that aim rays at lights.
+    pub fn get_lights(&self) -> Vec<Arc<dyn Hitable>> {
+        self.renderables.iter()
+            .filter(|h| h.area() > 0.0 && self.materials.get_material_by_key(h.get_material()).emitted() != Vector3::new(0.0, 0.0, 0.0))
+            .cloned()
+            .collect()
     }
 
     //fn _shadow_march(&self, p: &Vector3<f64>, n: &Vector3<f64>) -> f64 {
@@ -53,32 +102,8 @@ impl Scene {
     //    attenuation
     //}
 
-    pub fn render(&self, ray: &Ray, depth: i64, t_max: f64) -> Vector3<f64> {
-        //Prevent infinite loops.
-        if depth > 3 {
-            let rng = rand::thread_rng().gen::<f64>();
-            if rng > 0.3 {
-                return Vector3::new(0.0, 0.0, 0.0);
-            }
-        }
-
-        let t = self.get_closest_intersection(ray);
-        for hitable in &*self.renderables {
-            let t2 = hitable.hit(ray, t_max);
-            if t != t2 {
-                continue;
-            }
-
-            let p = ray.point_at_parameter(t2);
-            let n = hitable.get_norm_at_p(&p);
-            let emitted = self.materials.get_material_by_key(hitable.get_material()).emitted();
-
-            let (new_ray, scattered) = self.materials.get_material_by_key(hitable.get_material()).scatter(ray, &n, &p);
-
-            let col = self.render(&new_ray, depth+1, t_max);
-            return emitted + Vector3::new(hitable.get_color().x * col.x, hitable.get_color().y * col.y, hitable.get_color().z * col.z);
-        }
-        return Vector3::new(0.0,0.0,0.0);
+    //Position of the hitable in the render list, which stays the same between runs.
+    pub fn index_of(&self, hitable: &Arc<dyn Hitable>) -> usize {
+        self.renderables.iter().position(|h| Arc::ptr_eq(h, hitable)).unwrap_or(0)
     }
-
 }
\ No newline at end of file
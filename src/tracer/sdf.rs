@@ -0,0 +1,448 @@
+use super::geometry::*;
+use super::ray::*;
+use cgmath::*;
+
+//Signed distance field tree. Distances are negative inside the surface.
+pub enum Sdf {
+    Sphere(f64),
+    Box(Vector3<f64>),
+    RoundBox(Vector3<f64>, f64),
+    Torus(f64, f64),
+    Cylinder(f64, f64),
+    Capsule(f64, f64),
+    Plane(Vector3<f64>, f64),
+    Union(Box<Sdf>, Box<Sdf>),
+    Intersection(Box<Sdf>, Box<Sdf>),
+    Subtraction(Box<Sdf>, Box<Sdf>),
+    SmoothUnion(Box<Sdf>, Box<Sdf>, f64),
+    SmoothIntersection(Box<Sdf>, Box<Sdf>, f64),
+    SmoothSubtraction(Box<Sdf>, Box<Sdf>, f64),
+    Translate(Box<Sdf>, Vector3<f64>),
+    Scale(Box<Sdf>, f64),
+    //Holds the inverse rotation so points can be taken straight into the child's space.
+    Rotate(Box<Sdf>, Matrix3<f64>),
+    //Repeats space with the given period on each axis, zero leaves that axis alone.
+    Repeat(Box<Sdf>, Vector3<f64>),
+    Round(Box<Sdf>, f64),
+    Mandelbulb(f64, u32),
+    Menger(u32),
+}
+
+impl Sdf {
+    pub fn distance(&self, p: Vector3<f64>) -> f64 {
+        match self {
+            Sdf::Sphere(r) => p.magnitude() - r,
+            Sdf::Box(half) => box_distance(p, *half),
+            Sdf::RoundBox(half, r) => box_distance(p, *half) - r,
+            Sdf::Torus(major, minor) => {
+                let q = Vector2::new(Vector2::new(p.x, p.z).magnitude() - major, p.y);
+                q.magnitude() - minor
+            },
+            Sdf::Cylinder(r, half_height) => {
+                let d = Vector2::new(Vector2::new(p.x, p.z).magnitude() - r, p.y.abs() - half_height);
+                d.x.max(d.y).min(0.0) + Vector2::new(d.x.max(0.0), d.y.max(0.0)).magnitude()
+            },
+            Sdf::Capsule(r, half_height) => {
+                let y = p.y.clamp(-half_height, *half_height);
+                Vector3::new(p.x, p.y - y, p.z).magnitude() - r
+            },
+            Sdf::Plane(n, offset) => p.dot(*n) + offset,
+            Sdf::Union(a, b) => a.distance(p).min(b.distance(p)),
+            Sdf::Intersection(a, b) => a.distance(p).max(b.distance(p)),
+            Sdf::Subtraction(a, b) => a.distance(p).max(-b.distance(p)),
+            Sdf::SmoothUnion(a, b, k) => smooth_min(a.distance(p), b.distance(p), *k),
+            Sdf::SmoothIntersection(a, b, k) => -smooth_min(-a.distance(p), -b.distance(p), *k),
+            Sdf::SmoothSubtraction(a, b, k) => -smooth_min(-a.distance(p), b.distance(p), *k),
+            Sdf::Translate(a, offset) => a.distance(p - offset),
+            Sdf::Scale(a, s) => a.distance(p/ *s) * s,
+            Sdf::Rotate(a, inverse) => a.distance(inverse * p),
+            Sdf::Repeat(a, period) => {
+                let mut q = p;
+                for i in 0..3 {
+                    if period[i] > 0.0 {
+                        q[i] = (p[i] + 0.5*period[i]).rem_euclid(period[i]) - 0.5*period[i];
+                    }
+                }
+                a.distance(q)
+            },
+            Sdf::Round(a, r) => a.distance(p) - r,
+            Sdf::Mandelbulb(power, iterations) => mandelbulb(p, *power, *iterations),
+            Sdf::Menger(iterations) => menger(p, *iterations),
+        }
+    }
+
+    //Parses expressions such as "smooth_union(sphere(1), translate(box(1, 0.5, 1), 0, -1, 0), 0.3)".
+    pub fn parse(text: &str) -> Result<Sdf, String> {
+        let tokens = tokenize(text)?;
+        let mut pos = 0;
+        let expr = parse_expr(&tokens, &mut pos)?;
+        if pos != tokens.len() {
+            return Err(format!("unexpected '{}' after expression", tokens[pos]));
+        }
+        match expr {
+            Expr::Shape(sdf) => Ok(sdf),
+            Expr::Number(_) => Err("expression is a number, not a shape".to_string())
+        }
+    }
+}
+
+fn box_distance(p: Vector3<f64>, half: Vector3<f64>) -> f64 {
+    let q = Vector3::new(p.x.abs() - half.x, p.y.abs() - half.y, p.z.abs() - half.z);
+    let outside = Vector3::new(q.x.max(0.0), q.y.max(0.0), q.z.max(0.0)).magnitude();
+    outside + q.x.max(q.y.max(q.z)).min(0.0)
+}
+
+//Polynomial smooth minimum, k is roughly the width of the blend.
+fn smooth_min(a: f64, b: f64, k: f64) -> f64 {
+    if k <= 0.0 {
+        return a.min(b);
+    }
+    let h = (0.5 + 0.5*(b - a)/k).clamp(0.0, 1.0);
+    b*(1.0 - h) + a*h - k*h*(1.0 - h)
+}
+
+//Distance estimate for the power-n Mandelbulb, from the running derivative of z^n + c.
+fn mandelbulb(p: Vector3<f64>, power: f64, iterations: u32) -> f64 {
+    let mut z = p;
+    let mut dr = 1.0;
+    let mut r = 0.0;
+    for _ in 0..iterations {
+        r = z.magnitude();
+        if r > 2.0 {
+            break;
+        }
+        let theta = (z.z/r).acos()*power;
+        let phi = z.y.atan2(z.x)*power;
+        dr = r.powf(power - 1.0)*power*dr + 1.0;
+        let zr = r.powf(power);
+        z = zr*Vector3::new(theta.sin()*phi.cos(), phi.sin()*theta.sin(), theta.cos()) + p;
+    }
+    if r == 0.0 {
+        return 0.0;
+    }
+    0.5*r.ln()*r/dr
+}
+
+//Menger sponge filling the cube from -1 to 1, carved by folding space into each sub-cube.
+fn menger(p: Vector3<f64>, iterations: u32) -> f64 {
+    let mut d = box_distance(p, Vector3::new(1.0, 1.0, 1.0));
+    let mut s = 1.0;
+    for _ in 0..iterations {
+        let a = Vector3::new(
+            (p.x*s).rem_euclid(2.0) - 1.0,
+            (p.y*s).rem_euclid(2.0) - 1.0,
+            (p.z*s).rem_euclid(2.0) - 1.0
+        );
+        s *= 3.0;
+        let r = Vector3::new(1.0 - 3.0*a.x.abs(), 1.0 - 3.0*a.y.abs(), 1.0 - 3.0*a.z.abs()).map(f64::abs);
+        let da = r.x.max(r.y);
+        let db = r.y.max(r.z);
+        let dc = r.z.max(r.x);
+        let c = (da.min(db.min(dc)) - 1.0)/s;
+        d = d.max(c);
+    }
+    d
+}
+
+enum Expr {
+    Number(f64),
+    Shape(Sdf),
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    let chars: Vec<char> = text.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '(' || c == ')' || c == ',' {
+            tokens.push(c.to_string());
+            i += 1;
+        } else if c.is_alphanumeric() || c == '_' || c == '.' || c == '-' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.' || chars[i] == '-') {
+                i += 1;
+            }
+            tokens.push(chars[start..i].iter().collect());
+        } else {
+            return Err(format!("unexpected character '{}'", c));
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_expr(tokens: &[String], pos: &mut usize) -> Result<Expr, String> {
+    let token = tokens.get(*pos).ok_or("unexpected end of expression")?.clone();
+    *pos += 1;
+    if let Ok(n) = token.parse::<f64>() {
+        return Ok(Expr::Number(n));
+    }
+
+    if tokens.get(*pos).map(|t| t.as_str()) != Some("(") {
+        return Err(format!("expected '(' after '{}'", token));
+    }
+    *pos += 1;
+    let mut args = vec![];
+    if tokens.get(*pos).map(|t| t.as_str()) == Some(")") {
+        *pos += 1;
+    } else {
+        loop {
+            args.push(parse_expr(tokens, pos)?);
+            match tokens.get(*pos).map(|t| t.as_str()) {
+                Some(",") => *pos += 1,
+                Some(")") => { *pos += 1; break; },
+                _ => return Err(format!("expected ',' or ')' in arguments of '{}'", token))
+            }
+        }
+    }
+    Ok(Expr::Shape(build_call(&token, args)?))
+}
+
+//Splits arguments into the leading shapes and the trailing numbers.
+fn split_args(name: &str, args: Vec<Expr>, shapes: usize, numbers: usize) -> Result<(Vec<Sdf>, Vec<f64>), String> {
+    let mut sdfs = vec![];
+    let mut nums = vec![];
+    for arg in args {
+        match arg {
+            Expr::Shape(s) if nums.is_empty() => sdfs.push(s),
+            Expr::Number(n) => nums.push(n),
+            Expr::Shape(_) => return Err(format!("'{}' expects shapes before numbers", name))
+        }
+    }
+    if sdfs.len() != shapes || nums.len() != numbers {
+        return Err(format!("'{}' takes {} shape(s) and {} number(s)", name, shapes, numbers));
+    }
+    Ok((sdfs, nums))
+}
+
+fn build_call(name: &str, args: Vec<Expr>) -> Result<Sdf, String> {
+    let (shapes, numbers) = match name {
+        "sphere" | "menger" => (0, 1),
+        "torus" | "cylinder" | "capsule" | "mandelbulb" => (0, 2),
+        "box" => (0, 3),
+        "round_box" | "plane" => (0, 4),
+        "union" | "intersect" | "subtract" => (2, 0),
+        "smooth_union" | "smooth_intersect" | "smooth_subtract" => (2, 1),
+        "translate" | "repeat" => (1, 3),
+        "scale" | "round" => (1, 1),
+        "rotate" => (1, 4),
+        _ => return Err(format!("unknown function '{}'", name))
+    };
+    let (mut s, n) = split_args(name, args, shapes, numbers)?;
+    let b = s.pop().map(Box::new);
+    let a = s.pop().map(Box::new);
+
+    Ok(match name {
+        "sphere" => Sdf::Sphere(n[0]),
+        "box" => Sdf::Box(Vector3::new(n[0], n[1], n[2])),
+        "round_box" => Sdf::RoundBox(Vector3::new(n[0], n[1], n[2]), n[3]),
+        "torus" => Sdf::Torus(n[0], n[1]),
+        "cylinder" => Sdf::Cylinder(n[0], n[1]),
+        "capsule" => Sdf::Capsule(n[0], n[1]),
+        "plane" => Sdf::Plane(Vector3::new(n[0], n[1], n[2]).normalize(), n[3]),
+        "mandelbulb" => Sdf::Mandelbulb(n[0], n[1] as u32),
+        "menger" => Sdf::Menger(n[0] as u32),
+        "union" => Sdf::Union(a.unwrap(), b.unwrap()),
+        "intersect" => Sdf::Intersection(a.unwrap(), b.unwrap()),
+        "subtract" => Sdf::Subtraction(a.unwrap(), b.unwrap()),
+        "smooth_union" => Sdf::SmoothUnion(a.unwrap(), b.unwrap(), n[0]),
+        "smooth_intersect" => Sdf::SmoothIntersection(a.unwrap(), b.unwrap(), n[0]),
+        "smooth_subtract" => Sdf::SmoothSubtraction(a.unwrap(), b.unwrap(), n[0]),
+        "translate" => Sdf::Translate(b.unwrap(), Vector3::new(n[0], n[1], n[2])),
+        "repeat" => Sdf::Repeat(b.unwrap(), Vector3::new(n[0], n[1], n[2])),
+        "scale" => Sdf::Scale(b.unwrap(), n[0]),
+        "round" => Sdf::Round(b.unwrap(), n[0]),
+        _ => {
+            let rotation = Matrix3::from_axis_angle(Vector3::new(n[0], n[1], n[2]).normalize(), Deg(n[3]));
+            Sdf::Rotate(b.unwrap(), rotation.transpose())
+        }
+    })
+}
+
+//Hitable that sphere traces a distance field, optionally limited to a bounding sphere.
+pub struct SdfObject {
+    sdf: Sdf,
+    center: Vector3<f64>,
+    bound: f64,
+    color: Vector3<f64>,
+    mat: String,
+}
+
+impl SdfObject {
+    const MAX_STEPS: u32 = 512;
+    const EPSILON: f64 = 1e-4;
+    const MAX_DISTANCE: f64 = 1e3;
+
+    //A bound of zero means the field is unbounded, e.g. when it repeats forever.
+    pub fn new(sdf: Sdf, center: Vector3<f64>, bound: f64, col: Vector3<f64>, m: String) -> SdfObject {
+        SdfObject { sdf, center, bound, color: col, mat: m }
+    }
+
+    fn distance(&self, p: Vector3<f64>) -> f64 {
+        self.sdf.distance(p - self.center)
+    }
+
+    //Range of t worth marching through, clipped by the bounding sphere.
+    fn march_range(&self, r: &Ray, t_max: f64) -> Option<(f64, f64)> {
+        let len = r.direction().magnitude();
+        if self.bound <= 0.0 {
+            return Some((0.001, t_max.min(SdfObject::MAX_DISTANCE/len)));
+        }
+        let oc = r.origin() - self.center;
+        let a = r.direction().dot(*r.direction());
+        let b = oc.dot(*r.direction());
+        let c = oc.dot(oc) - self.bound*self.bound;
+        let discriminant = b*b - a*c;
+        if discriminant < 0.0 {
+            return None;
+        }
+        let t0 = ((-b - discriminant.sqrt())/a).max(0.001);
+        let t1 = ((-b + discriminant.sqrt())/a).min(t_max);
+        if t1 < t0 { None } else { Some((t0, t1)) }
+    }
+}
+
+impl Hitable for SdfObject {
+    //Steps along the ray by the distance to the nearest surface. Rays that start on
+    //the surface (after a bounce) have to move off it before a hit counts.
+    fn hit(&self, r: &Ray, t_max: f64) -> f64 {
+        let (mut t, t_end) = match self.march_range(r, t_max) {
+            Some(range) => range,
+            None => return 0.0
+        };
+        let len = r.direction().magnitude();
+        let mut escaped = self.distance(r.point_at_parameter(t)).abs() > 4.0*SdfObject::EPSILON;
+        for _ in 0..SdfObject::MAX_STEPS {
+            let d = self.distance(r.point_at_parameter(t)).abs();
+            if escaped && d < SdfObject::EPSILON {
+                return t;
+            }
+            if d > 4.0*SdfObject::EPSILON {
+                escaped = true;
+            }
+            t += d.max(SdfObject::EPSILON)/len;
+            if t > t_end {
+                break;
+            }
+        }
+        0.0
+    }
+
+    fn get_center(&self) -> &Vector3<f64> {
+        &self.center
+    }
+
+    fn get_radius(&self) -> f64 {
+        self.bound
+    }
+
+    fn get_color(&self) -> &Vector3<f64> {
+        &self.color
+    }
+
+    fn get_material(&self) -> String {
+        self.mat.clone()
+    }
+
+    //Central differences of the field.
+    fn get_norm_at_p(&self, _: &Ray, p: &Vector3<f64>) -> Vector3<f64> {
+        let h = 1e-5;
+        let dx = Vector3::new(h, 0.0, 0.0);
+        let dy = Vector3::new(0.0, h, 0.0);
+        let dz = Vector3::new(0.0, 0.0, h);
+        let n = Vector3::new(
+            self.distance(p + dx) - self.distance(p - dx),
+            self.distance(p + dy) - self.distance(p - dy),
+            self.distance(p + dz) - self.distance(p - dz)
+        );
+        if n.magnitude2() == 0.0 {
+            return Vector3::new(0.0, 1.0, 0.0);
+        }
+        n.normalize()
+    }
+
+    //Spherical mapping around the object's center.
+    fn get_uv_at_p(&self, _: &Ray, p: &Vector3<f64>) -> (f64, f64) {
+        let d = p - self.center;
+        if d.magnitude2() == 0.0 {
+            return (0.0, 0.0);
+        }
+        let d = d.normalize();
+        let phi = (-d.z).atan2(d.x) + std::f64::consts::PI;
+        (phi/(2.0*std::f64::consts::PI), (-d.y).clamp(-1.0, 1.0).acos()/std::f64::consts::PI)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f64, y: f64, z: f64) -> Vector3<f64> {
+        Vector3::new(x, y, z)
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn parses_primitives() {
+        let sphere = Sdf::parse("sphere(2)").unwrap();
+        assert!(close(sphere.distance(at(3.0, 0.0, 0.0)), 1.0));
+        assert!(close(sphere.distance(at(0.0, 0.0, 0.0)), -2.0));
+        let b = Sdf::parse("box(1, 0.5, 1)").unwrap();
+        assert!(close(b.distance(at(0.0, 1.5, 0.0)), 1.0));
+        let torus = Sdf::parse(" torus( 2 , 0.5 ) ").unwrap();
+        assert!(close(torus.distance(at(2.0, 0.0, 0.0)), -0.5));
+    }
+
+    #[test]
+    fn parses_nested_operations() {
+        let sdf = Sdf::parse("smooth_union(sphere(1), translate(box(1, 0.5, 1), 0, -1, 0), 0.3)").unwrap();
+        assert!(sdf.distance(at(0.0, 0.0, 0.0)) < 0.0);
+        assert!(sdf.distance(at(0.0, -1.4, 0.0)) < 0.0);
+        assert!(sdf.distance(at(0.0, 3.0, 0.0)) > 0.0);
+
+        let hollow = Sdf::parse("subtract(sphere(2), sphere(1))").unwrap();
+        assert!(close(hollow.distance(at(0.0, 0.0, 0.0)), 1.0));
+        assert!(close(hollow.distance(at(1.5, 0.0, 0.0)), -0.5));
+
+        let moved = Sdf::parse("translate(scale(sphere(1), 2), 5, 0, 0)").unwrap();
+        assert!(close(moved.distance(at(5.0, 0.0, 0.0)), -2.0));
+        assert!(close(moved.distance(at(8.0, 0.0, 0.0)), 1.0));
+    }
+
+    #[test]
+    fn rotate_turns_the_child() {
+        //A long box along x turned a quarter turn about z lies along y.
+        let sdf = Sdf::parse("rotate(box(2, 0.5, 0.5), 0, 0, 1, 90)").unwrap();
+        assert!(sdf.distance(at(0.0, 1.5, 0.0)) < 0.0);
+        assert!(sdf.distance(at(1.5, 0.0, 0.0)) > 0.0);
+    }
+
+    #[test]
+    fn repeat_tiles_space() {
+        let sdf = Sdf::parse("repeat(sphere(0.5), 4, 0, 0)").unwrap();
+        assert!(close(sdf.distance(at(8.0, 0.0, 0.0)), -0.5));
+        assert!(close(sdf.distance(at(8.0, 1.0, 0.0)), 0.5));
+    }
+
+    #[test]
+    fn rejects_bad_expressions() {
+        for text in ["", "sphere", "sphere(1", "sphere(1))", "blob(1)", "sphere(1, 2)", "union(sphere(1))",
+                     "translate(1, 2, 3, sphere(1))", "4", "sphere(1) $"] {
+            assert!(Sdf::parse(text).is_err(), "{} should not parse", text);
+        }
+    }
+
+    #[test]
+    fn sphere_tracing_finds_the_surface() {
+        let object = SdfObject::new(Sdf::parse("sphere(1)").unwrap(), at(0.0, 0.0, 0.0), 1.5, at(1.0, 1.0, 1.0), "flat".to_string());
+        let r = Ray::new_at(at(0.0, 0.0, 5.0), at(0.0, 0.0, -1.0), 0.0);
+        assert!((object.hit(&r, f64::MAX) - 4.0).abs() < 1e-3);
+        let miss = Ray::new_at(at(2.0, 0.0, 5.0), at(0.0, 0.0, -1.0), 0.0);
+        assert_eq!(object.hit(&miss, f64::MAX), 0.0);
+    }
+}
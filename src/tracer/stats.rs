@@ -0,0 +1,151 @@
+use serde_json::json;
+use std::cell::Cell;
+use std::time::Instant;
+
+//Counts of what the rays did. Every thread keeps its own so tracing never waits on a lock,
+//and sends them back to be added up along with its progress.
+#[derive(Clone, Copy, Default)]
+pub struct Counters {
+    //Rays leaving the camera.
+    pub camera: u64,
+    //Rays looking for the closest hit, camera rays included.
+    pub traced: u64,
+    //Rays only asking whether anything is in the way.
+    pub shadow: u64,
+    //Hitables asked whether a ray hits them. There's no BVH, so it's every hitable a ray
+    //could hit and this is what finding the hit costs.
+    pub tests: u64,
+    //Rays the denoiser and passes trace to see what pixels show, kept out of the counts
+    //above since they don't go into the image.
+    pub auxiliary: u64,
+}
+
+impl Counters {
+    pub fn rays(&self) -> u64 {
+        self.traced + self.shadow
+    }
+
+    pub fn add(&mut self, other: &Counters) {
+        self.camera += other.camera;
+        self.traced += other.traced;
+        self.shadow += other.shadow;
+        self.tests += other.tests;
+        self.auxiliary += other.auxiliary;
+    }
+}
+
+thread_local! {
+    static COUNTERS: Cell<Counters> = Cell::new(Counters::default());
+}
+
+pub fn count(f: impl FnOnce(&mut Counters)) {
+    COUNTERS.with(|c| {
+        let mut counters = c.get();
+        f(&mut counters);
+        c.set(counters);
+    });
+}
+
+//What this thread has counted since it last asked.
+pub fn take() -> Counters {
+    COUNTERS.with(|c| c.replace(Counters::default()))
+}
+
+//Runs f and counts every ray it follows as auxiliary instead.
+pub fn aside<T>(f: impl FnOnce() -> T) -> T {
+    let before = take();
+    let value = f();
+    let during = take();
+    COUNTERS.with(|c| c.set(Counters { auxiliary: before.auxiliary + during.traced + during.shadow + during.auxiliary, ..before }));
+    value
+}
+
+//Everything a render reports once it's done.
+pub struct Stats {
+    pub counters: Counters,
+    //Phases in the order they ran, with how long each took in seconds.
+    phases: Vec<(&'static str, f64)>,
+    phase_start: Instant,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats { counters: Counters::default(), phases: vec![], phase_start: Instant::now() }
+    }
+
+    //Ends the phase that's running, which started when the last one ended.
+    pub fn phase(&mut self, name: &'static str) {
+        self.phases.push((name, self.phase_start.elapsed().as_secs_f64()));
+        self.phase_start = Instant::now();
+    }
+
+    //Rays followed per ray leaving the camera, so one for a path that hit nothing or a
+    //light straight away. Bidirectional light paths count towards it too.
+    fn path_length(&self) -> f64 {
+        self.counters.traced as f64/(self.counters.camera.max(1) as f64)
+    }
+
+    fn tests_per_ray(&self) -> f64 {
+        self.counters.tests as f64/(self.counters.rays().max(1) as f64)
+    }
+
+    pub fn print(&self) {
+        let c = &self.counters;
+        println!("Rays: {} camera, {} secondary, {} shadow, {} for the denoiser and passes.", c.camera, c.traced.saturating_sub(c.camera), c.shadow, c.auxiliary);
+        println!("Intersection tests: {} in all, {:.2} per ray.", c.tests, self.tests_per_ray());
+        println!("Average path length: {:.2} rays.", self.path_length());
+        let phases: Vec<String> = self.phases.iter().map(|(name, t)| format!("{} {:.3}s", name, t)).collect();
+        println!("Time: {}.", phases.join(", "));
+        match peak_memory() {
+            Some(bytes) => println!("Peak memory: {:.1} MiB.", bytes as f64/(1024.0*1024.0)),
+            None => println!("Peak memory: unknown.")
+        }
+    }
+
+    pub fn write(&self, path: &str) -> std::io::Result<()> {
+        let c = &self.counters;
+        let phases: serde_json::Map<String, serde_json::Value> = self.phases.iter()
+            .map(|(name, t)| (name.to_string(), json!(t)))
+            .collect();
+        let stats = json!({
+            "rays": {
+                "camera": c.camera,
+                "secondary": c.traced.saturating_sub(c.camera),
+                "shadow": c.shadow,
+                "auxiliary": c.auxiliary,
+            },
+            "intersection_tests": c.tests,
+            "tests_per_ray": self.tests_per_ray(),
+            "path_length": self.path_length(),
+            "seconds": phases,
+            "peak_memory": peak_memory(),
+        });
+        std::fs::write(path, format!("{:#}\n", stats))
+    }
+}
+
+//Most memory the process has had resident, in bytes. Only Linux says.
+fn peak_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb*1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rays_counted_aside_stay_out_of_the_image_counts() {
+        take();
+        count(|c| { c.camera += 1; c.traced += 3; c.tests += 10; });
+        let value = aside(|| {
+            count(|c| { c.camera += 2; c.traced += 2; c.shadow += 1; c.tests += 7; });
+            5
+        });
+        assert_eq!(value, 5);
+        let c = take();
+        assert_eq!((c.camera, c.traced, c.shadow, c.tests, c.auxiliary), (1, 3, 0, 10, 3));
+    }
+}
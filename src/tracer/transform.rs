@@ -0,0 +1,479 @@
+use super::geometry::*;
+use super::ray::*;
+use cgmath::*;
+use std::cell::Cell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+//The matrices that take a hitable from its own space into the world.
+#[derive(Clone, Copy)]
+struct Affine {
+    matrix: Matrix4<f64>,
+    inverse: Matrix4<f64>,
+    normal_matrix: Matrix4<f64>,
+}
+
+impl Affine {
+    fn new(matrix: Matrix4<f64>) -> Option<Affine> {
+        let inverse = matrix.invert()?;
+        Some(Affine { matrix, inverse, normal_matrix: inverse.transpose() })
+    }
+
+    //The direction isn't renormalized, so t along the local ray is t along the world ray.
+    fn local_ray(&self, r: &Ray) -> Ray {
+        Ray::new_at(
+            (self.inverse * r.origin().extend(1.0)).truncate(),
+            (self.inverse * r.direction().extend(0.0)).truncate(),
+            r.time()
+        )
+    }
+
+    fn local_point(&self, p: &Vector3<f64>) -> Vector3<f64> {
+        (self.inverse * p.extend(1.0)).truncate()
+    }
+
+    fn world_normal(&self, n: Vector3<f64>) -> Vector3<f64> {
+        let n = (self.normal_matrix * n.extend(0.0)).truncate();
+        n/n.magnitude()
+    }
+
+    fn hit(&self, inner: &dyn Hitable, r: &Ray, t_max: f64) -> f64 {
+        inner.hit(&self.local_ray(r), t_max)
+    }
+
+    fn get_norm_at_p(&self, inner: &dyn Hitable, r: &Ray, p: &Vector3<f64>) -> Vector3<f64> {
+        self.world_normal(inner.get_norm_at_p(&self.local_ray(r), &self.local_point(p)))
+    }
+
+    fn get_uv_at_p(&self, inner: &dyn Hitable, r: &Ray, p: &Vector3<f64>) -> (f64, f64) {
+        inner.get_uv_at_p(&self.local_ray(r), &self.local_point(p))
+    }
+
+    fn hit_intervals(&self, inner: &dyn Hitable, r: &Ray) -> Vec<(f64, f64)> {
+        inner.hit_intervals(&self.local_ray(r))
+    }
+
+    //Exact for rotations, translations and even scales. Uneven scales stretch some parts of
+    //the surface more than others, so this is only the average growth.
+    fn area(&self, inner: &dyn Hitable) -> f64 {
+        let linear = Matrix3::from_cols(self.matrix.x.truncate(), self.matrix.y.truncate(), self.matrix.z.truncate());
+        inner.area()*linear.determinant().abs().powf(2.0/3.0)
+    }
+
+    fn sample_surface(&self, inner: &dyn Hitable, time: f64) -> Option<(Vector3<f64>, Vector3<f64>)> {
+        let (p, n) = inner.sample_surface(time)?;
+        Some(((self.matrix * p.extend(1.0)).truncate(), self.world_normal(n)))
+    }
+}
+
+//Places a hitable in the world through an affine matrix. Instancing is just several
+//transforms sharing the same Arc'd hitable, so each copy only costs two matrices.
+pub struct Transform {
+    inner: Arc<dyn Hitable>,
+    affine: Affine,
+    center: Vector3<f64>,
+}
+
+impl Transform {
+    pub fn new(inner: Arc<dyn Hitable>, matrix: Matrix4<f64>) -> Transform {
+        let affine = Affine::new(matrix).unwrap_or_else(|| {
+            println!("Error, transform matrix is not invertible. Using identity");
+            Affine::new(Matrix4::identity()).unwrap()
+        });
+        let center = (affine.matrix * inner.get_center().extend(1.0)).truncate();
+        Transform { inner, affine, center }
+    }
+
+    pub fn translate(v: Vector3<f64>) -> Matrix4<f64> {
+        Matrix4::from_translation(v)
+    }
+
+    pub fn rotate(axis: Vector3<f64>, degrees: f64) -> Matrix4<f64> {
+        Matrix4::from_axis_angle(axis.normalize(), Deg(degrees))
+    }
+
+    pub fn scale(v: Vector3<f64>) -> Matrix4<f64> {
+        Matrix4::from_nonuniform_scale(v.x, v.y, v.z)
+    }
+
+    //Takes the matrix in row-major order, the way it reads when written out by hand.
+    pub fn from_rows(m: &[f64]) -> Matrix4<f64> {
+        Matrix4::new(
+            m[0], m[4], m[8],  m[12],
+            m[1], m[5], m[9],  m[13],
+            m[2], m[6], m[10], m[14],
+            m[3], m[7], m[11], m[15]
+        )
+    }
+}
+
+impl Hitable for Transform {
+    fn hit(&self, r: &Ray, t_max: f64) -> f64 {
+        self.affine.hit(&*self.inner, r, t_max)
+    }
+
+    fn get_center(&self) -> &Vector3<f64> {
+        &self.center
+    }
+
+    fn get_radius(&self) -> f64 {
+        self.inner.get_radius()
+    }
+
+    fn get_color(&self) -> &Vector3<f64> {
+        self.inner.get_color()
+    }
+
+    fn get_material(&self) -> String {
+        self.inner.get_material()
+    }
+
+    fn get_norm_at_p(&self, r: &Ray, p: &Vector3<f64>) -> Vector3<f64> {
+        self.affine.get_norm_at_p(&*self.inner, r, p)
+    }
+
+    fn get_uv_at_p(&self, r: &Ray, p: &Vector3<f64>) -> (f64, f64) {
+        self.affine.get_uv_at_p(&*self.inner, r, p)
+    }
+
+    fn hit_intervals(&self, r: &Ray) -> Vec<(f64, f64)> {
+        self.affine.hit_intervals(&*self.inner, r)
+    }
+
+    fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    fn area(&self) -> f64 {
+        self.affine.area(&*self.inner)
+    }
+
+    fn sample_surface(&self, time: f64) -> Option<(Vector3<f64>, Vector3<f64>)> {
+        self.affine.sample_surface(&*self.inner, time)
+    }
+}
+
+//One step of a transform, kept as its numbers so two of them can be blended.
+#[derive(Clone)]
+pub enum Step {
+    Translate(Vector3<f64>),
+    Rotate(Vector3<f64>, f64),
+    Scale(Vector3<f64>),
+    Matrix(Matrix4<f64>),
+}
+
+impl Step {
+    fn matrix(&self) -> Matrix4<f64> {
+        match self {
+            Step::Translate(v) => Transform::translate(*v),
+            Step::Rotate(axis, degrees) => Transform::rotate(*axis, *degrees),
+            Step::Scale(v) => Transform::scale(*v),
+            Step::Matrix(m) => *m
+        }
+    }
+}
+
+//Matrix for steps applied in the order they're listed.
+pub fn compose(steps: &[Step]) -> Matrix4<f64> {
+    steps.iter().fold(Matrix4::identity(), |matrix, step| step.matrix() * matrix)
+}
+
+//Splits an affine matrix into translation, rotation and stretch (m = T*R*S), which blend
+//without passing through anything flat. None for flips and matrices that aren't affine.
+fn decompose(m: &Matrix4<f64>) -> Option<(Vector3<f64>, Quaternion<f64>, Matrix3<f64>)> {
+    if m.x.w != 0.0 || m.y.w != 0.0 || m.z.w != 0.0 || m.w.w != 1.0 {
+        return None;
+    }
+    let linear = Matrix3::from_cols(m.x.truncate(), m.y.truncate(), m.z.truncate());
+    if linear.determinant() <= 0.0 {
+        return None;
+    }
+    //Averaging with the inverse transpose converges on the nearest rotation.
+    let mut rotation = linear;
+    for _ in 0..100 {
+        let next = (rotation + rotation.invert()?.transpose())*0.5;
+        let change = (next - rotation).x.magnitude() + (next - rotation).y.magnitude() + (next - rotation).z.magnitude();
+        rotation = next;
+        if change < 1e-12 {
+            break;
+        }
+    }
+    let stretch = rotation.invert()? * linear;
+    Some((m.w.truncate(), Quaternion::from(rotation).normalize(), stretch))
+}
+
+//A start and end step checked and taken apart once, so blending them for a ray is cheap
+//and never comes out singular between the two.
+enum Blend {
+    Translate(Vector3<f64>, Vector3<f64>),
+    //Unit axes that don't point opposite ways, so the blended axis is never zero.
+    Rotate(Vector3<f64>, Vector3<f64>, f64, f64),
+    //Every component keeps its sign, so none of them passes through zero.
+    Scale(Vector3<f64>, Vector3<f64>),
+    Matrix((Vector3<f64>, Quaternion<f64>, Matrix3<f64>), (Vector3<f64>, Quaternion<f64>, Matrix3<f64>)),
+}
+
+impl Blend {
+    //None if they're different kinds of step or can't be blended without going singular.
+    fn new(start: &Step, end: &Step) -> Option<Blend> {
+        match (start, end) {
+            (Step::Translate(a), Step::Translate(b)) => Some(Blend::Translate(*a, *b)),
+            (Step::Rotate(a, d0), Step::Rotate(b, d1)) => {
+                if a.magnitude() == 0.0 || b.magnitude() == 0.0 || (a.normalize() + b.normalize()).magnitude() < 1e-9 {
+                    return None;
+                }
+                Some(Blend::Rotate(a.normalize(), b.normalize(), *d0, *d1))
+            },
+            (Step::Scale(a), Step::Scale(b)) => {
+                if a.x*b.x <= 0.0 || a.y*b.y <= 0.0 || a.z*b.z <= 0.0 {
+                    return None;
+                }
+                Some(Blend::Scale(*a, *b))
+            },
+            (Step::Matrix(a), Step::Matrix(b)) => {
+                let (a, mut b) = (decompose(a)?, decompose(b)?);
+                //Takes the short way round.
+                if a.1.dot(b.1) < 0.0 {
+                    b.1 = -b.1;
+                }
+                Some(Blend::Matrix(a, b))
+            },
+            _ => None
+        }
+    }
+
+    //The matrix s of the way from start to end.
+    fn matrix(&self, s: f64) -> Matrix4<f64> {
+        match self {
+            Blend::Translate(a, b) => Transform::translate(a.lerp(*b, s)),
+            Blend::Rotate(a, b, d0, d1) => Transform::rotate(a.lerp(*b, s), d0 + (d1 - d0)*s),
+            Blend::Scale(a, b) => Transform::scale(a.lerp(*b, s)),
+            Blend::Matrix((t0, r0, s0), (t1, r1, s1)) => {
+                Matrix4::from_translation(t0.lerp(*t1, s))
+                    * Matrix4::from(Matrix3::from(r0.slerp(*r1, s)))
+                    * Matrix4::from(s0 + (s1 - s0)*s)
+            }
+        }
+    }
+}
+
+static NEXT_ANIMATED: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    //The last matrices worked out on this thread, keyed by object and ray time. A ray
+    //asks for the hit, normal and uv one after the other, so they're usually the same.
+    static LAST_FRAME: Cell<Option<(usize, u64, Affine)>> = const { Cell::new(None) };
+}
+
+//Transform whose steps blend from start at time 0 to end at time 1, carrying on at the
+//same rate outside that, so rotating and growing objects blur over the shutter.
+pub struct Animated {
+    inner: Arc<dyn Hitable>,
+    id: usize,
+    blends: Vec<Blend>,
+    //Where it is at either end. Carrying on past them can go singular, and then the
+    //nearer end is used instead.
+    start: Affine,
+    end: Affine,
+    //Where the object is at time 0.
+    center: Vector3<f64>,
+}
+
+impl Animated {
+    //None unless both lists have the same kinds of step in the same order, and every pair
+    //can be blended without the object going flat partway.
+    pub fn new(inner: Arc<dyn Hitable>, start: Vec<Step>, end: Vec<Step>) -> Option<Animated> {
+        if start.len() != end.len() {
+            return None;
+        }
+        let blends = start.iter().zip(end.iter()).map(|(a, b)| Blend::new(a, b)).collect::<Option<Vec<Blend>>>()?;
+        let matrix = |s: f64| blends.iter().fold(Matrix4::identity(), |matrix, blend| blend.matrix(s) * matrix);
+        let (start, end) = (Affine::new(matrix(0.0))?, Affine::new(matrix(1.0))?);
+        let center = (start.matrix * inner.get_center().extend(1.0)).truncate();
+        let id = NEXT_ANIMATED.fetch_add(1, Ordering::Relaxed);
+        Some(Animated { inner, id, blends, start, end, center })
+    }
+
+    fn at(&self, time: f64) -> Affine {
+        LAST_FRAME.with(|last| {
+            if let Some((id, bits, affine)) = last.get() {
+                if id == self.id && bits == time.to_bits() {
+                    return affine;
+                }
+            }
+            let matrix = self.blends.iter().fold(Matrix4::identity(), |matrix, blend| blend.matrix(time) * matrix);
+            let affine = Affine::new(matrix).unwrap_or(if time < 0.5 { self.start } else { self.end });
+            last.set(Some((self.id, time.to_bits(), affine)));
+            affine
+        })
+    }
+}
+
+impl Hitable for Animated {
+    fn hit(&self, r: &Ray, t_max: f64) -> f64 {
+        self.at(r.time()).hit(&*self.inner, r, t_max)
+    }
+
+    fn get_center(&self) -> &Vector3<f64> {
+        &self.center
+    }
+
+    fn get_radius(&self) -> f64 {
+        self.inner.get_radius()
+    }
+
+    fn get_color(&self) -> &Vector3<f64> {
+        self.inner.get_color()
+    }
+
+    fn get_material(&self) -> String {
+        self.inner.get_material()
+    }
+
+    fn get_norm_at_p(&self, r: &Ray, p: &Vector3<f64>) -> Vector3<f64> {
+        self.at(r.time()).get_norm_at_p(&*self.inner, r, p)
+    }
+
+    fn get_uv_at_p(&self, r: &Ray, p: &Vector3<f64>) -> (f64, f64) {
+        self.at(r.time()).get_uv_at_p(&*self.inner, r, p)
+    }
+
+    fn hit_intervals(&self, r: &Ray) -> Vec<(f64, f64)> {
+        self.at(r.time()).hit_intervals(&*self.inner, r)
+    }
+
+    fn is_closed(&self) -> bool {
+        self.inner.is_closed()
+    }
+
+    //Area has no time, so objects that grow are sampled as big as they are at time 0.
+    fn area(&self) -> f64 {
+        self.start.area(&*self.inner)
+    }
+
+    fn sample_surface(&self, time: f64) -> Option<(Vector3<f64>, Vector3<f64>)> {
+        self.at(time).sample_surface(&*self.inner, time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::random;
+
+    fn unit_sphere() -> Arc<dyn Hitable> {
+        Arc::new(Sphere::new(Vector3::new(1.0, 1.0, 1.0), 1.0, Vector3::new(0.0, 0.0, 0.0), "flat".to_string()))
+    }
+
+    fn toward_origin(x: f64, time: f64) -> Ray {
+        Ray::new_at(Vector3::new(x, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0), time)
+    }
+
+    #[test]
+    fn animated_translation_blends_over_time() {
+        let start = vec![Step::Translate(Vector3::new(0.0, 0.0, 0.0))];
+        let end = vec![Step::Translate(Vector3::new(2.0, 0.0, 0.0))];
+        let moving = Animated::new(unit_sphere(), start, end).unwrap();
+        assert!((moving.hit(&toward_origin(0.0, 0.0), f64::MAX) - 4.0).abs() < 1e-9);
+        assert!((moving.hit(&toward_origin(1.0, 0.5), f64::MAX) - 4.0).abs() < 1e-9);
+        assert!((moving.hit(&toward_origin(2.0, 1.0), f64::MAX) - 4.0).abs() < 1e-9);
+        assert_eq!(moving.hit(&toward_origin(0.0, 1.0), f64::MAX), 0.0);
+    }
+
+    #[test]
+    fn animated_scale_grows_the_object() {
+        let start = vec![Step::Scale(Vector3::new(1.0, 1.0, 1.0))];
+        let end = vec![Step::Scale(Vector3::new(2.0, 2.0, 2.0))];
+        let growing = Animated::new(unit_sphere(), start, end).unwrap();
+        assert!((growing.hit(&toward_origin(0.0, 0.0), f64::MAX) - 4.0).abs() < 1e-9);
+        assert!((growing.hit(&toward_origin(0.0, 1.0), f64::MAX) - 3.0).abs() < 1e-9);
+        let n = growing.get_norm_at_p(&toward_origin(0.0, 1.0), &Vector3::new(0.0, 0.0, 2.0));
+        assert!((n - Vector3::new(0.0, 0.0, 1.0)).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn animated_rotation_turns_about_the_axis() {
+        //An off-center sphere swung a quarter turn about y ends up on the +x axis.
+        let start = vec![Step::Translate(Vector3::new(0.0, 0.0, -3.0)), Step::Rotate(Vector3::new(0.0, 1.0, 0.0), 0.0)];
+        let end = vec![Step::Translate(Vector3::new(0.0, 0.0, -3.0)), Step::Rotate(Vector3::new(0.0, 1.0, 0.0), -90.0)];
+        let swinging = Animated::new(unit_sphere(), start, end).unwrap();
+        assert!((swinging.hit(&toward_origin(0.0, 0.0), f64::MAX) - 7.0).abs() < 1e-9);
+        let r = Ray::new_at(Vector3::new(3.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0), 1.0);
+        assert!((swinging.hit(&r, f64::MAX) - 4.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn animated_needs_matching_steps() {
+        let start = vec![Step::Translate(Vector3::new(0.0, 0.0, 0.0))];
+        let end = vec![Step::Scale(Vector3::new(2.0, 2.0, 2.0))];
+        assert!(Animated::new(unit_sphere(), start, end).is_none());
+        assert!(Animated::new(unit_sphere(), vec![], vec![Step::Scale(Vector3::new(2.0, 2.0, 2.0))]).is_none());
+    }
+
+    #[test]
+    fn animated_refuses_steps_that_go_flat() {
+        let single = |a: Step, b: Step| Animated::new(unit_sphere(), vec![a], vec![b]).is_none();
+        assert!(single(Step::Scale(Vector3::new(1.0, 1.0, 1.0)), Step::Scale(Vector3::new(1.0, -1.0, 1.0))));
+        assert!(single(Step::Scale(Vector3::new(1.0, 1.0, 1.0)), Step::Scale(Vector3::new(1.0, 0.0, 1.0))));
+        assert!(single(Step::Rotate(Vector3::new(0.0, 1.0, 0.0), 10.0), Step::Rotate(Vector3::new(0.0, -2.0, 0.0), 10.0)));
+        assert!(single(Step::Matrix(Matrix4::identity()), Step::Matrix(Transform::scale(Vector3::new(1.0, 1.0, 0.0)))));
+        assert!(single(Step::Matrix(Matrix4::identity()), Step::Matrix(Transform::scale(Vector3::new(-1.0, 1.0, 1.0)))));
+        //A half turn only goes through flat matrices when they're blended entry by entry.
+        let turned = Transform::rotate(Vector3::new(0.0, 1.0, 0.0), 180.0) * Transform::scale(Vector3::new(2.0, 1.0, 1.0));
+        assert!(!single(Step::Matrix(Matrix4::identity()), Step::Matrix(turned)));
+    }
+
+    #[test]
+    fn matrices_come_apart_and_back() {
+        let m = Transform::translate(Vector3::new(1.0, -2.0, 3.0))
+            * Transform::rotate(Vector3::new(1.0, 2.0, 0.5), 70.0)
+            * Transform::scale(Vector3::new(2.0, 0.5, 1.5));
+        let (t, r, s) = decompose(&m).unwrap();
+        let back = Matrix4::from_translation(t) * Matrix4::from(Matrix3::from(r)) * Matrix4::from(s);
+        for c in 0..4 {
+            assert!((back[c] - m[c]).magnitude() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn blended_matrices_turn_rather_than_shrink() {
+        //Blending the entries would squash the sphere halfway through the turn.
+        let end = Transform::rotate(Vector3::new(0.0, 1.0, 0.0), 90.0) * Transform::scale(Vector3::new(2.0, 2.0, 2.0));
+        let turning = Animated::new(unit_sphere(), vec![Step::Matrix(Matrix4::identity())], vec![Step::Matrix(end)]).unwrap();
+        assert!((turning.hit(&toward_origin(0.0, 0.5), f64::MAX) - 3.5).abs() < 1e-9);
+        let r = Ray::new_at(Vector3::new(1.4, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0), 0.5);
+        assert!(turning.hit(&r, f64::MAX) > 0.0);
+    }
+
+    #[test]
+    fn animated_objects_keep_their_own_matrices() {
+        let moving = |x: f64| {
+            Animated::new(unit_sphere(), vec![Step::Translate(Vector3::zero())], vec![Step::Translate(Vector3::new(x, 0.0, 0.0))]).unwrap()
+        };
+        let (left, right) = (moving(-2.0), moving(2.0));
+        for _ in 0..2 {
+            assert!((left.hit(&toward_origin(-1.0, 0.5), f64::MAX) - 4.0).abs() < 1e-9);
+            assert!((right.hit(&toward_origin(1.0, 0.5), f64::MAX) - 4.0).abs() < 1e-9);
+            assert_eq!(right.hit(&toward_origin(-1.0, 0.5), f64::MAX), 0.0);
+        }
+    }
+
+    #[test]
+    fn transformed_emitters_are_sampled_in_place() {
+        let matrix = Transform::translate(Vector3::new(0.0, 3.0, 0.0)) * Transform::scale(Vector3::new(2.0, 2.0, 2.0));
+        let placed = Transform::new(unit_sphere(), matrix);
+        assert!((placed.area() - 16.0*std::f64::consts::PI).abs() < 1e-9);
+        let start = vec![Step::Translate(Vector3::new(0.0, 0.0, 0.0))];
+        let end = vec![Step::Translate(Vector3::new(2.0, 0.0, 0.0))];
+        let moving = Animated::new(unit_sphere(), start, end).unwrap();
+        random::with_stream(Box::new(random::Seeded::new(9)), || {
+            for _ in 0..100 {
+                let (p, n) = placed.sample_surface(0.0).unwrap();
+                assert!(((p - Vector3::new(0.0, 3.0, 0.0)).magnitude() - 2.0).abs() < 1e-9);
+                assert!((n - (p - Vector3::new(0.0, 3.0, 0.0))/2.0).magnitude() < 1e-9);
+                let (p, _) = moving.sample_surface(0.5).unwrap();
+                assert!(((p - Vector3::new(1.0, 0.0, 0.0)).magnitude() - 1.0).abs() < 1e-9);
+            }
+        });
+    }
+}
@@ -0,0 +1,171 @@
+use super::camera::*;
+use super::integrator::*;
+use super::ray::*;
+use super::scene::*;
+use super::world::WhittedSettings;
+use cgmath::*;
+use std::sync::Arc;
+
+//Classic recursive ray tracing for quick previews. Surfaces are lit straight from point
+//lights with hard shadows and a Blinn-Phong highlight, mirrors and glass are followed
+//exactly and media are left out, so a few samples per pixel are enough.
+pub struct Whitted {
+    scene: Arc<Scene>,
+    //Position of each light and its color times intensity.
+    lights: Vec<(Vector3<f64>, Vector3<f64>)>,
+    //Hitables rays can stop at, which leaves out media.
+    surfaces: Vec<bool>,
+    //Hitables that cast shadows, which also leaves out emitters so stand-in lights can shine.
+    blockers: Vec<bool>,
+    max_depth: u32,
+    settings: WhittedSettings,
+}
+
+impl Whitted {
+    pub fn new(scene: Arc<Scene>, max_depth: u32, settings: &WhittedSettings) -> Whitted {
+        let materials = scene.get_materials();
+        let surfaces: Vec<bool> = scene.get_hitables().iter()
+            .map(|h| !materials.get_material_by_key(h.get_material()).is_volume())
+            .collect();
+        let blockers = scene.get_hitables().iter().zip(surfaces.iter())
+            .map(|(h, &surface)| surface && materials.get_material_by_key(h.get_material()).emitted() == Vector3::zero())
+            .collect();
+
+        let mut lights: Vec<(Vector3<f64>, Vector3<f64>)> = scene.get_point_lights().iter()
+            .map(|l| (*l.origin(), *l.color()*l.intensity()))
+            .collect();
+        //Worlds made for the path tracer are lit by emitting objects instead, so put a point
+        //light in the middle of each. Its intensity matches the path tracer for a sphere.
+        if lights.is_empty() {
+            for emitter in scene.get_lights() {
+                let points: Vec<Vector3<f64>> = (0..64).filter_map(|_| emitter.sample_surface(0.0)).map(|(p, _)| p).collect();
+                if points.is_empty() {
+                    continue;
+                }
+                let center = points.iter().fold(Vector3::zero(), |sum, p| sum + p)/(points.len() as f64);
+                let emitted = materials.get_material_by_key(emitter.get_material()).emitted();
+                lights.push((center, emitted*emitter.area()/(4.0*std::f64::consts::PI)));
+            }
+        }
+        if lights.is_empty() {
+            println!("Error, the world has no lights. Whitted will only show ambient light");
+        }
+
+        Whitted { scene, lights, surfaces, blockers, max_depth, settings: settings.clone() }
+    }
+
+    fn trace(&self, ray: &Ray, depth: u32) -> Vector3<f64> {
+        let (t, hitable) = match self.scene.get_closest_intersection_among(ray, f64::MAX, &self.surfaces) {
+            Some(hit) => hit,
+            None => return Vector3::zero()
+        };
+        let material = self.scene.get_materials().get_material_by_key(hitable.get_material());
+        let emitted = material.emitted();
+        if emitted != Vector3::zero() {
+            return emitted;
+        }
+
+        let p = ray.point_at_parameter(t);
+        let n = hitable.get_norm_at_p(ray, &p);
+        let color = *hitable.get_color();
+        let rays = material.specular_rays(ray, &n, &p);
+        if !rays.is_empty() {
+            if depth >= self.max_depth {
+                return Vector3::zero();
+            }
+            let col = rays.iter().fold(Vector3::zero(), |col, (r, weight)| col + self.trace(r, depth + 1)*(*weight));
+            return color.mul_element_wise(col);
+        }
+
+        let (diffuse, highlight) = self.shade(ray, &p, &n);
+        color.mul_element_wise(diffuse) + highlight
+    }
+
+    //Diffuse and highlight light reaching p from every light it can see, plus ambient.
+    fn shade(&self, ray: &Ray, p: &Vector3<f64>, n: &Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+        let view = -ray.direction().normalize();
+        let n = if n.dot(view) < 0.0 { -*n } else { *n };
+        let ambient = self.settings.ambient;
+        let mut diffuse = Vector3::new(ambient, ambient, ambient);
+        let mut highlight = Vector3::zero();
+        for (origin, power) in &self.lights {
+            let to_light = origin - p;
+            let dist = to_light.magnitude();
+            let l = to_light/dist;
+            let cos = n.dot(l);
+            if cos <= 0.0 {
+                continue;
+            }
+            let shadow = Ray::new_at(*p, l, ray.time());
+            if self.scene.blocked(&shadow, dist, &self.blockers) {
+                continue;
+            }
+
+            let h = (l + view).normalize();
+            let falloff = 1.0/(dist*dist);
+            diffuse += power*cos*falloff;
+            highlight += power*self.settings.specular*n.dot(h).max(0.0).powf(self.settings.shininess)*falloff;
+        }
+        (diffuse, highlight)
+    }
+}
+
+impl Integrator for Whitted {
+    fn render(&self, cam: &Camera, u: f64, v: f64, _: &mut dyn FnMut(f64, f64, Vector3<f64>)) -> Vector3<f64> {
+        self.trace(&cam.get_ray(u, v), 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::random;
+    use super::super::world::World;
+
+    fn whitted(world: &World) -> Whitted {
+        let scene = Arc::new(Scene::new(world.get_hitables(), world.get_materials(), world.get_lights()));
+        let settings = WhittedSettings { ambient: 0.1, specular: 0.0, shininess: 32.0 };
+        random::with_stream(Box::new(random::Seeded::new(2)), || Whitted::new(scene, 4, &settings))
+    }
+
+    #[test]
+    fn emitters_get_a_point_light_as_bright_as_they_are() {
+        let mut world = World::empty(16, 16);
+        world.set_camera([0.0, 1.0, 3.0], [0.0, 0.0, 0.0], 60.0, [0.0, 0.0]);
+        world.add_plane([0.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.5, 0.5, 0.5], "flat");
+        world.add_sphere([0.0, 6.0, 0.0], 0.25, [1.0, 1.0, 1.0], "diffuse_light");
+        let whitted = whitted(&world);
+        assert_eq!(whitted.lights.len(), 1);
+        let (origin, power) = whitted.lights[0];
+        assert!((origin - Vector3::new(0.0, 6.0, 0.0)).magnitude() < 0.1);
+
+        //Far from a small sphere, the path tracer's floor takes radiance times pi r^2/d^2
+        //times albedo/pi, which is what a point light of radiance times r^2 gives.
+        let p = Vector3::new(0.0, 0.0, 0.0);
+        let ray = Ray::new_at(Vector3::new(0.0, 1.0, 3.0), p - Vector3::new(0.0, 1.0, 3.0), 0.0);
+        let (diffuse, _) = whitted.shade(&ray, &p, &Vector3::new(0.0, 1.0, 0.0));
+        let emitted = world.get_materials().get_material_by_key("diffuse_light".to_string()).emitted();
+        let expected = emitted*(0.25*0.25)/36.0;
+        assert!((power - emitted*0.25*0.25).magnitude() < 1e-9);
+        assert!((diffuse - Vector3::new(0.1, 0.1, 0.1) - expected).magnitude() < 0.05*expected.magnitude());
+    }
+
+    #[test]
+    fn blocked_lights_leave_only_ambient() {
+        let shade = |blocked: bool| {
+            let mut world = World::empty(16, 16);
+            world.add_plane([0.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.5, 0.5, 0.5], "flat");
+            world.add_point_light([0.0, 5.0, 0.0], 10.0, [1.0, 1.0, 1.0]);
+            if blocked {
+                world.add_box([-1.0, 2.0, -1.0], [1.0, 3.0, 1.0], [0.5, 0.5, 0.5], "flat");
+            }
+            let p = Vector3::new(0.0, 0.0, 0.0);
+            let ray = Ray::new_at(Vector3::new(0.0, 1.0, 3.0), p - Vector3::new(0.0, 1.0, 3.0), 0.0);
+            whitted(&world).shade(&ray, &p, &Vector3::new(0.0, 1.0, 0.0))
+        };
+        let (diffuse, highlight) = shade(true);
+        assert_eq!(diffuse, Vector3::new(0.1, 0.1, 0.1));
+        assert_eq!(highlight, Vector3::zero());
+        assert!(shade(false).0.x > 0.1);
+    }
+}
@@ -1,9 +1,16 @@
 use std::sync::Arc;
 use std::fs::File;
 use std::io::BufReader;
+use std::collections::HashMap as Map;
 use super::geometry;
+use super::transform;
+use super::csg;
+use super::sdf;
+use super::heightfield;
+use super::medium;
+use super::material;
+use super::light;
 use cgmath::Vector3;
-use crate::tracer::cgmath::InnerSpace;
 use super::camera;
 
 use serde::Deserialize;
@@ -11,92 +18,1163 @@ use serde_json::*;
 
 #[derive(Deserialize, Debug)]
 pub struct Camera {
-    lookfrom: Vec<f64>,
-    lookat:   Vec<f64>,
-    fov: f64
+    lookfrom: [f64; 3],
+    lookat:   [f64; 3],
+    fov: f64,
+    //Shutter open and close times, a non-zero interval enables motion blur.
+    #[serde(default)]
+    shutter: Option<[f64; 2]>
+}
+
+//How the image gets rendered. Anything left out takes its default.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct Settings {
+    //One of "path", "bdpt", "mlt" or "whitted", or a debug view: "normals", "depth", "albedo", "uv",
+    //"object_id", "ao" or "bounces".
+    pub integrator: String,
+    //Samples per pixel, or mutations per pixel for Metropolis.
+    pub samples: u32,
+    //Where each sample's numbers come from: "independent", "stratified", "halton", "sobol"
+    //or "pmj".
+    pub sampler: String,
+    //Starting point for every random number, the same seed gives the same image.
+    pub seed: u64,
+    //Threads to render with. The image doesn't depend on it.
+    pub threads: usize,
+    //Seconds the render can take before it stops and writes what it has, zero for no limit.
+    pub time_limit: f64,
+    //Bounces before Russian roulette can end a path.
+    pub min_depth: u32,
+    //Most bounces a path can have, how deep Whitted follows mirrors and glass, and the top
+    //of the bounce heatmap.
+    pub max_depth: u32,
+    pub adaptive: AdaptiveSettings,
+    pub filter: FilterSettings,
+    pub denoise: DenoiseSettings,
+    pub aovs: AovSettings,
+    pub progressive: ProgressiveSettings,
+    pub checkpoint: CheckpointSettings,
+    pub progress: ProgressSettings,
+    //File to write ray counts, timings and peak memory to as JSON once the render is done,
+    //empty for none. They're always printed.
+    pub stats: String,
+    pub mlt: MltSettings,
+    pub whitted: WhittedSettings,
+    //Distance where the depth view fades to black.
+    pub depth_range: f64,
+    //How far ambient occlusion looks for something blocking the sky.
+    pub ao_distance: f64
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            integrator: "path".to_string(),
+            samples: 5000,
+            sampler: "sobol".to_string(),
+            seed: 0,
+            threads: 8,
+            time_limit: 0.0,
+            min_depth: 3,
+            max_depth: 8,
+            adaptive: AdaptiveSettings::default(),
+            filter: FilterSettings::default(),
+            denoise: DenoiseSettings::default(),
+            aovs: AovSettings::default(),
+            progressive: ProgressiveSettings::default(),
+            checkpoint: CheckpointSettings::default(),
+            progress: ProgressSettings::default(),
+            stats: String::new(),
+            mlt: MltSettings::default(),
+            whitted: WhittedSettings::default(),
+            depth_range: 20.0,
+            ao_distance: 1.0
+        }
+    }
+}
+
+//Spreads samples by how noisy each pixel still is instead of giving every pixel the same
+//number. With a threshold, pixels stop once their noise drops below it and samples is the
+//most any pixel gets. Without one, each tile spends samples per pixel on average, always
+//on its noisiest pixel next, but no pixel gets more than four times samples. Metropolis
+//ignores it.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct AdaptiveSettings {
+    pub enabled: bool,
+    //Samples every pixel gets before its noise is trusted, and how many it gets at a time after.
+    pub min_samples: u32,
+    //Standard error relative to the pixel's brightness where it counts as done, zero for none.
+    pub threshold: f64
+}
+
+impl Default for AdaptiveSettings {
+    fn default() -> AdaptiveSettings {
+        AdaptiveSettings { enabled: false, min_samples: 32, threshold: 0.0 }
+    }
+}
+
+//How samples are spread over the pixels around them.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct FilterSettings {
+    //One of "box", "tent", "gaussian", "mitchell" or "lanczos".
+    pub name: String,
+    //Reach in pixels, zero for the filter's usual one.
+    pub radius: f64
+}
+
+impl Default for FilterSettings {
+    fn default() -> FilterSettings {
+        FilterSettings { name: "box".to_string(), radius: 0.0 }
+    }
+}
+
+//Edge-avoiding a-trous filter run over the finished image, so a low sample render can
+//stand in for a long one. It's guided by the albedo, normal and depth of what each pixel
+//sees, and each sigma is how big a difference in one of them has to be before pixels
+//stop being blurred together.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct DenoiseSettings {
+    pub enabled: bool,
+    //Passes of the filter, each reaching twice as far as the last.
+    pub iterations: u32,
+    //Samples per pixel taken of the albedo, normal and depth.
+    pub feature_samples: u32,
+    pub sigma_color: f64,
+    pub sigma_albedo: f64,
+    pub sigma_normal: f64,
+    //Relative to the depth of the nearer pixel.
+    pub sigma_depth: f64
+}
+
+impl Default for DenoiseSettings {
+    fn default() -> DenoiseSettings {
+        DenoiseSettings {
+            enabled: false,
+            iterations: 5,
+            feature_samples: 8,
+            sigma_color: 0.6,
+            sigma_albedo: 0.1,
+            sigma_normal: 0.3,
+            sigma_depth: 0.1
+        }
+    }
+}
+
+//Extra passes written next to the image for compositing.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct AovSettings {
+    //Any of "albedo", "normal", "depth", "position", "object_id", "material_id",
+    //"direct_diffuse", "indirect_diffuse", "specular", "emission" or "samples". The
+    //lighting passes add up to the image and only the path integrator fills them in.
+    pub passes: Vec<String>,
+    //"exr" for one multi-layer EXR holding the image and every pass, or "png" for an
+    //image per pass.
+    pub format: String,
+    //Looks per pixel the albedo, normal, depth and position are averaged over.
+    pub samples: u32
+}
+
+impl Default for AovSettings {
+    fn default() -> AovSettings {
+        AovSettings { passes: vec![], format: "exr".to_string(), samples: 8 }
+    }
+}
+
+//Renders the image in passes of a few samples per pixel each instead of all at once, and
+//writes what there is so far after a pass, so a render can be stopped once it looks good
+//enough. Adaptive sampling needs every sample of a tile at once, so it's turned off.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct ProgressiveSettings {
+    pub enabled: bool,
+    //Samples per pixel each pass adds.
+    pub pass_samples: u32,
+    //Seconds to wait between snapshots, zero for one after every pass.
+    pub interval: f64
+}
+
+impl Default for ProgressiveSettings {
+    fn default() -> ProgressiveSettings {
+        ProgressiveSettings { enabled: false, pass_samples: 16, interval: 0.0 }
+    }
+}
+
+//Saves the render so far next to the image every so often, so it can be picked up again
+//with --resume after the machine goes down. It's deleted once the render finishes.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct CheckpointSettings {
+    pub enabled: bool,
+    //Seconds between checkpoints.
+    pub interval: f64
+}
+
+impl Default for CheckpointSettings {
+    fn default() -> CheckpointSettings {
+        CheckpointSettings { enabled: false, interval: 600.0 }
+    }
+}
+
+//How progress gets shown while rendering. A terminal gets a line that's redrawn in place,
+//anything else, like a log file, gets a line every so often.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct ProgressSettings {
+    //Seconds between redraws of the live line and between stream updates.
+    pub interval: f64,
+    //Seconds between lines when the output isn't a terminal.
+    pub log_interval: f64,
+    //Where to write progress as JSON lines for other programs: a file, "stderr", or empty
+    //for nowhere.
+    pub stream: String
+}
+
+impl Default for ProgressSettings {
+    fn default() -> ProgressSettings {
+        ProgressSettings { interval: 0.5, log_interval: 60.0, stream: String::new() }
+    }
+}
+
+//Tuning for the Metropolis integrator.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct MltSettings {
+    //Paths traced up front to find the image's brightness and start the chains from.
+    pub bootstrap: u32,
+    //Chance a mutation throws the path away for a brand new one.
+    pub large_step: f64,
+    //Smallest and largest nudge a small mutation gives each primary sample.
+    pub mutation_min: f64,
+    pub mutation_max: f64
+}
+
+impl Default for MltSettings {
+    fn default() -> MltSettings {
+        MltSettings { bootstrap: 100000, large_step: 0.3, mutation_min: 1.0/1024.0, mutation_max: 1.0/64.0 }
+    }
+}
+
+//Point light, only seen by the Whitted preview.
+#[derive(Deserialize, Debug)]
+pub struct Light {
+    origin: [f64; 3],
+    intensity: f64,
+    #[serde(default = "white")]
+    color: [f64; 3]
+}
+
+//Shading for the Whitted preview.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct WhittedSettings {
+    //Light every surface gets even in shadow, so the preview isn't black where lights can't reach.
+    pub ambient: f64,
+    //Strength and tightness of the Blinn-Phong highlight on non-mirror surfaces.
+    pub specular: f64,
+    pub shininess: f64
+}
+
+impl Default for WhittedSettings {
+    fn default() -> WhittedSettings {
+        WhittedSettings { ambient: 0.1, specular: 0.3, shininess: 32.0 }
+    }
+}
+
+//Motion applied to any object over the shutter interval, after its transform. Translate
+//is a velocity per unit of time. Start and end are transforms at time 0 and time 1 with
+//the same steps in the same order, and each step's numbers blend from one to the other.
+#[derive(Deserialize, Debug)]
+pub struct Motion {
+    #[serde(default)]
+    translate: Option<[f64; 3]>,
+    #[serde(default)]
+    start: Vec<TransformOp>,
+    #[serde(default)]
+    end: Vec<TransformOp>
+}
+
+//Transform steps, applied to the object in the order they're listed.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TransformOp {
+    Translate([f64; 3]),
+    Rotate { axis: [f64; 3], angle: f64 },
+    Scale([f64; 3]),
+    //Row-major 4x4 matrix.
+    Matrix([f64; 16])
+}
+
+//Fields every object accepts for naming and placing it.
+#[derive(Deserialize, Debug, Default)]
+pub struct Placement {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    transform: Vec<TransformOp>,
+    #[serde(default)]
+    motion: Option<Motion>,
+    //Fills the inside of the object with a participating medium.
+    #[serde(default)]
+    medium: Option<Medium>,
+    #[serde(default)]
+    subsurface: Option<Subsurface>
+}
+
+//Interior of an object using the "subsurface" material, walked by the volume code.
+#[derive(Deserialize, Debug)]
+pub struct Subsurface {
+    //Average distance light travels inside before scattering.
+    mean_free_path: f64,
+    //Fraction of light surviving each scattering event, per channel.
+    #[serde(default = "white")]
+    albedo: [f64; 3],
+    #[serde(default)]
+    g: f64
+}
+
+impl Default for Subsurface {
+    fn default() -> Subsurface {
+        Subsurface { mean_free_path: 0.1, albedo: [0.8, 0.8, 0.8], g: 0.0 }
+    }
+}
+
+//Participating medium. Absorption and scattering are coefficients per unit length,
+//scaled by the density where one is given.
+#[derive(Deserialize, Debug)]
+pub struct Medium {
+    #[serde(default)]
+    absorption: f64,
+    #[serde(default)]
+    scattering: f64,
+    #[serde(default = "white")]
+    color: [f64; 3],
+    //Henyey-Greenstein asymmetry, 0 scatters evenly in all directions.
+    #[serde(default)]
+    g: f64,
+    #[serde(default)]
+    density: Option<DensityField>
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DensityField {
+    Noise {
+        scale: f64,
+        #[serde(default = "default_octaves")]
+        octaves: u32
+    },
+    //Voxel file spread over the box from min to max.
+    Grid {
+        file: String,
+        min: [f64; 3],
+        max: [f64; 3]
+    }
+}
+
+fn white() -> [f64; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+fn default_octaves() -> u32 {
+    4
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Plane {
-    origin: Vec<f64>,
-    normal: Vec<f64>,
-    color:  Vec<f64>,
-    mat: String
+    origin: [f64; 3],
+    normal: [f64; 3],
+    color:  [f64; 3],
+    mat: String,
+    #[serde(flatten)]
+    placement: Placement
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Sphere {
-    color:  Vec<f64>,
+    color:  [f64; 3],
+    radius: f64,
+    center: [f64; 3],
+    //Where the center has moved to by time 1.0.
+    #[serde(default)]
+    center1: Option<[f64; 3]>,
+    mat: String,
+    #[serde(flatten)]
+    placement: Placement
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AaBox {
+    min: [f64; 3],
+    max: [f64; 3],
+    color: [f64; 3],
+    mat: String,
+    #[serde(flatten)]
+    placement: Placement
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Cylinder {
+    base: [f64; 3],
+    //Runs from base to base + axis.
+    axis: [f64; 3],
     radius: f64,
-    center: Vec<f64>,
-    mat: String
+    color: [f64; 3],
+    mat: String,
+    #[serde(flatten)]
+    placement: Placement
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Cone {
+    base: [f64; 3],
+    axis: [f64; 3],
+    radius: f64,
+    //Radius at the tip, zero for a pointed cone.
+    #[serde(default)]
+    top_radius: f64,
+    color: [f64; 3],
+    mat: String,
+    #[serde(flatten)]
+    placement: Placement
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Disk {
+    center: [f64; 3],
+    normal: [f64; 3],
+    radius: f64,
+    color: [f64; 3],
+    mat: String,
+    #[serde(flatten)]
+    placement: Placement
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Rectangle {
+    corner: [f64; 3],
+    edge_u: [f64; 3],
+    edge_v: [f64; 3],
+    color: [f64; 3],
+    mat: String,
+    #[serde(flatten)]
+    placement: Placement
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Torus {
+    center: [f64; 3],
+    axis: [f64; 3],
+    major_radius: f64,
+    minor_radius: f64,
+    color: [f64; 3],
+    mat: String,
+    #[serde(flatten)]
+    placement: Placement
+}
+
+//Terrain from a grayscale image, spanning origin to origin + size.
+#[derive(Deserialize, Debug)]
+pub struct Heightfield {
+    image: String,
+    origin: [f64; 3],
+    size: [f64; 3],
+    color: [f64; 3],
+    mat: String,
+    #[serde(flatten)]
+    placement: Placement
+}
+
+//Implicit surface written in the expression language from sdf::Sdf::parse.
+#[derive(Deserialize, Debug)]
+pub struct SdfShape {
+    expr: String,
+    #[serde(default)]
+    center: Option<[f64; 3]>,
+    //Radius of the bounding sphere around center, leave out for unbounded fields.
+    #[serde(default)]
+    bound: f64,
+    color: [f64; 3],
+    mat: String,
+    #[serde(flatten)]
+    placement: Placement
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CsgOp {
+    Union,
+    Intersection,
+    Difference
+}
+
+//Any solid that can appear inside a CSG tree.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Shape {
+    Plane(Plane),
+    Sphere(Sphere),
+    Box(AaBox),
+    Cylinder(Cylinder),
+    Cone(Cone),
+    Torus(Torus),
+    Csg(Box<Csg>)
+}
+
+//Combines two solids. The result takes its color and material from the left shape.
+#[derive(Deserialize, Debug)]
+pub struct Csg {
+    op: CsgOp,
+    left: Shape,
+    right: Shape,
+    #[serde(flatten)]
+    placement: Placement
+}
+
+//A copy of a named prototype placed somewhere else in the world.
+#[derive(Deserialize, Debug)]
+pub struct Instance {
+    object: String,
+    #[serde(default)]
+    transform: Vec<TransformOp>,
+    #[serde(default)]
+    motion: Option<Motion>
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct Objects {
+    #[serde(default)]
+    planes: Vec<Plane>,
+    #[serde(default)]
+    spheres: Vec<Sphere>,
+    #[serde(default)]
+    boxes: Vec<AaBox>,
+    #[serde(default)]
+    cylinders: Vec<Cylinder>,
+    #[serde(default)]
+    cones: Vec<Cone>,
+    #[serde(default)]
+    disks: Vec<Disk>,
+    #[serde(default)]
+    rectangles: Vec<Rectangle>,
+    #[serde(default)]
+    tori: Vec<Torus>,
+    #[serde(default)]
+    csg: Vec<Csg>,
+    #[serde(default)]
+    sdfs: Vec<SdfShape>,
+    #[serde(default)]
+    heightfields: Vec<Heightfield>,
 }
 
 #[derive(Deserialize, Debug)]
 struct WorldJSON {
     pub camera: Camera,
-    pub planes: Vec<Plane>,
-    pub spheres: Vec<Sphere>,
+    #[serde(default)]
+    pub settings: Settings,
+    #[serde(flatten)]
+    pub objects: Objects,
+    //Objects that are only rendered through instances.
+    #[serde(default)]
+    pub prototypes: Objects,
+    #[serde(default)]
+    pub instances: Vec<Instance>,
+    //Medium filling the whole world.
+    #[serde(default)]
+    pub fog: Option<Medium>,
+    #[serde(default)]
+    pub lights: Vec<Light>,
 }
 
 pub struct World {
+    width: u32,
+    height: u32,
     camera:   Arc<camera::Camera>,
-    hitables: Vec<Arc<dyn geometry::Hitable>>
+    hitables: Vec<Arc<dyn geometry::Hitable>>,
+    materials: material::MaterialsFactory,
+    lights: Vec<Arc<light::Light>>,
+    settings: Settings
 }
 
+//A built object along with its name and the medium filling it, if any.
+type Built = (Option<String>, Arc<dyn geometry::Hitable>, Option<Medium>);
+
 impl World {
-    pub fn new(filename: &'static str, width: u32, height: u32) -> Result<World> {
-        let file = File::open(filename).unwrap();
+    pub fn new(filename: &str, width: u32, height: u32) -> Result<World> {
+        let file = File::open(filename).map_err(|e| {
+            println!("Error opening world file {}: {}", filename, e);
+            Error::io(e)
+        })?;
         let reader = BufReader::new(file);
 
-        let json: WorldJSON = match serde_json::from_reader(reader) {
-            Ok(res) => res,
+        match serde_json::from_reader(reader) {
+            Ok(json) => Ok(World::build(json, width, height)),
             Err(e) => {
                 println!("Error parsing file: {}", e);
-                return Err(e);
+                Err(e)
+            }
+        }
+    }
+
+    //Same as new, from the text of a world file.
+    pub fn from_json(text: &str, width: u32, height: u32) -> Result<World> {
+        Ok(World::build(serde_json::from_str(text)?, width, height))
+    }
+
+    //Empty world with the camera at the origin looking down -z, for adding objects to.
+    pub fn empty(width: u32, height: u32) -> World {
+        let camera = Camera { lookfrom: [0.0, 0.0, 0.0], lookat: [0.0, 0.0, -1.0], fov: 90.0, shutter: None };
+        World::build(WorldJSON {
+            camera,
+            settings: Settings::default(),
+            objects: Objects::default(),
+            prototypes: Objects::default(),
+            instances: vec![],
+            fog: None,
+            lights: vec![]
+        }, width, height)
+    }
+
+    fn build(json: WorldJSON, width: u32, height: u32) -> World {
+        let camera = World::camera(&json.camera, width, height);
+        let mut world = World {
+            width,
+            height,
+            camera: Arc::new(camera),
+            hitables: vec![],
+            materials: material::MaterialsFactory::new(),
+            lights: vec![],
+            settings: json.settings
+        };
+
+        for (_, hitable, medium) in World::build_objects(json.objects) {
+            world.add(hitable, medium);
+        }
+
+        if let Some(fog) = json.fog {
+            if let Some(volume) = fog.build(None, &mut world.materials) {
+                world.hitables.push(volume);
+            }
+        }
+
+        let mut prototypes: Map<String, Arc<dyn geometry::Hitable>> = Map::new();
+        for (name, hitable, medium) in World::build_objects(json.prototypes) {
+            if medium.is_some() {
+                println!("Error, media aren't supported on prototypes. Ignoring it");
+            }
+            match name {
+                Some(name) => { prototypes.insert(name, hitable); },
+                None => println!("Error, prototype objects need a name. Skipping it")
             }
+        }
+
+        for instance in json.instances {
+            match prototypes.get(&instance.object) {
+                Some(prototype) => {
+                    let hitable = World::apply_transform(prototype.clone(), &instance.transform);
+                    world.hitables.push(World::apply_motion(hitable, &instance.motion));
+                },
+                None => println!("Error, prototype '{}' not found. Skipping instance", instance.object)
+            }
+        }
+
+        world.lights = json.lights.iter()
+            .map(|l| Arc::new(light::Light::new(vec3(&l.origin), l.intensity, vec3(&l.color))))
+            .collect();
+        world
+    }
+
+    //Adds an object along with the medium filling it, if it has one.
+    fn add(&mut self, hitable: Arc<dyn geometry::Hitable>, medium: Option<Medium>) {
+        let medium = match medium {
+            None if hitable.get_material() == "subsurface" => Some(Subsurface::default().medium()),
+            _ => medium
         };
+        if let Some(medium) = medium {
+            if let Some(volume) = medium.build(Some(hitable.clone()), &mut self.materials) {
+                self.hitables.push(volume);
+            }
+        }
+        //A material of "none" leaves just the medium, without a surface around it.
+        if hitable.get_material() != "none" {
+            self.hitables.push(hitable);
+        }
+    }
 
-        let json_cam = json.camera;
-        let lookfrom = Vector3::new(json_cam.lookfrom[0], json_cam.lookfrom[1], json_cam.lookfrom[2]);
-        let lookat = Vector3::new(json_cam.lookat[0], json_cam.lookat[1], json_cam.lookat[2]);
+    //Objects can also be added in code, with the same materials a world file names, like
+    //"flat", "metal", "glass" or "diffuse_light" for one that gives off light.
+    pub fn add_sphere(&mut self, center: [f64; 3], radius: f64, color: [f64; 3], mat: &str) {
+        self.add(Arc::new(geometry::Sphere::new(color.into(), radius, center.into(), mat.to_string())), None);
+    }
+
+    pub fn add_plane(&mut self, origin: [f64; 3], normal: [f64; 3], color: [f64; 3], mat: &str) {
+        self.add(Arc::new(geometry::Plane::new(origin.into(), normal.into(), color.into(), mat.to_string())), None);
+    }
+
+    pub fn add_box(&mut self, min: [f64; 3], max: [f64; 3], color: [f64; 3], mat: &str) {
+        self.add(Arc::new(geometry::AaBox::new(min.into(), max.into(), color.into(), mat.to_string())), None);
+    }
+
+    //Parallelogram spanned by edge_u and edge_v from corner, facing along edge_u x edge_v.
+    pub fn add_rectangle(&mut self, corner: [f64; 3], edge_u: [f64; 3], edge_v: [f64; 3], color: [f64; 3], mat: &str) {
+        self.add(Arc::new(geometry::Rectangle::new(corner.into(), edge_u.into(), edge_v.into(), color.into(), mat.to_string())), None);
+    }
 
-        let camera = camera::Camera::new(
-            lookfrom,
-            lookat,
+    //Point light, which only the Whitted preview sees.
+    pub fn add_point_light(&mut self, origin: [f64; 3], intensity: f64, color: [f64; 3]) {
+        self.lights.push(Arc::new(light::Light::new(origin.into(), intensity, color.into())));
+    }
+
+    fn camera(json_cam: &Camera, width: u32, height: u32) -> camera::Camera {
+        let shutter = json_cam.shutter.unwrap_or([0.0, 0.0]);
+        camera::Camera::new(
+            vec3(&json_cam.lookfrom),
+            vec3(&json_cam.lookat),
             Vector3::new(0.0, 1.0, 0.0),
             json_cam.fov,
-            (width/height).into()
-        );
+            (width as f64)/(height.max(1) as f64),
+            shutter[0],
+            shutter[1]
+        )
+    }
 
+    //Points the camera from lookfrom at lookat, with fov degrees from the top of the image
+    //to the bottom and the shutter open between the two times.
+    pub fn set_camera(&mut self, lookfrom: [f64; 3], lookat: [f64; 3], fov: f64, shutter: [f64; 2]) {
+        let json_cam = Camera { lookfrom, lookat, fov, shutter: Some(shutter) };
+        self.camera = Arc::new(World::camera(&json_cam, self.width, self.height));
+    }
 
-        let mut hitables: Vec<Arc<dyn geometry::Hitable>> = vec![];
-        for plane in json.planes {
-            hitables.push(Arc::new(geometry::Plane::new(
-                Vector3::new(plane.origin[0], plane.origin[1], plane.origin[2]),
-                Vector3::new(plane.normal[0], plane.normal[1], plane.normal[2]),
-                Vector3::new(plane.color[0], plane.color[1], plane.color[2]),
-                plane.mat.clone()
-            )));
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn build_objects(objects: Objects) -> Vec<Built> {
+        let mut built: Vec<Built> = vec![];
+        for plane in objects.planes {
+            built.push(World::place(plane.build(), plane.placement));
+        }
+        for sphere in objects.spheres {
+            built.push(World::place(sphere.build(), sphere.placement));
         }
+        for b in objects.boxes {
+            built.push(World::place(b.build(), b.placement));
+        }
+        for cylinder in objects.cylinders {
+            built.push(World::place(cylinder.build(), cylinder.placement));
+        }
+        for cone in objects.cones {
+            built.push(World::place(cone.build(), cone.placement));
+        }
+        for disk in objects.disks {
+            built.push(World::place(disk.build(), disk.placement));
+        }
+        for rect in objects.rectangles {
+            built.push(World::place(rect.build(), rect.placement));
+        }
+        for torus in objects.tori {
+            built.push(World::place(torus.build(), torus.placement));
+        }
+        for csg in objects.csg {
+            built.push(World::place(csg.build(), csg.placement));
+        }
+        for shape in objects.sdfs {
+            match shape.build() {
+                Some(hitable) => built.push(World::place(hitable, shape.placement)),
+                None => continue
+            }
+        }
+        for field in objects.heightfields {
+            match field.build() {
+                Some(hitable) => built.push(World::place(hitable, field.placement)),
+                None => continue
+            }
+        }
+        built
+    }
 
-        for sphere in json.spheres {
-            hitables.push(Arc::new(geometry::Sphere::new(
-                Vector3::new(sphere.color[0], sphere.color[1], sphere.color[2]),
-                sphere.radius,
-                Vector3::new(sphere.center[0], sphere.center[1], sphere.center[2]),
-                sphere.mat.clone()
-            )));
+    fn place(hitable: Arc<dyn geometry::Hitable>, placement: Placement) -> Built {
+        let hitable = World::apply_transform(hitable, &placement.transform);
+        let subsurface = placement.subsurface;
+        let medium = placement.medium.or_else(|| subsurface.map(|s| s.medium()));
+        (placement.name, World::apply_motion(hitable, &placement.motion), medium)
+    }
+
+    fn apply_transform(hitable: Arc<dyn geometry::Hitable>, ops: &[TransformOp]) -> Arc<dyn geometry::Hitable> {
+        if ops.is_empty() {
+            return hitable;
         }
+        Arc::new(transform::Transform::new(hitable, transform::compose(&World::steps(ops))))
+    }
 
-        Ok(World { camera: Arc::new(camera), hitables: hitables})
+    fn steps(ops: &[TransformOp]) -> Vec<transform::Step> {
+        ops.iter().map(|op| match op {
+            TransformOp::Translate(v) => transform::Step::Translate(vec3(v)),
+            TransformOp::Rotate { axis, angle } => transform::Step::Rotate(vec3(axis), *angle),
+            TransformOp::Scale(v) => transform::Step::Scale(vec3(v)),
+            TransformOp::Matrix(m) => transform::Step::Matrix(transform::Transform::from_rows(m))
+        }).collect()
     }
 
-    pub fn get_hitables(&self) -> Vec<Arc<dyn geometry::Hitable>> {
+    fn apply_motion(hitable: Arc<dyn geometry::Hitable>, motion: &Option<Motion>) -> Arc<dyn geometry::Hitable> {
+        let motion = match motion {
+            Some(m) => m,
+            None => return hitable
+        };
+        let mut hitable = hitable;
+        if !motion.start.is_empty() || !motion.end.is_empty() {
+            match transform::Animated::new(hitable.clone(), World::steps(&motion.start), World::steps(&motion.end)) {
+                Some(animated) => hitable = Arc::new(animated),
+                None => println!("Error, motion start and end need the same steps in the same order, blending without flattening the object. Ignoring them")
+            }
+        }
+        match &motion.translate {
+            Some(v) => Arc::new(geometry::Moving::new(hitable, vec3(v))),
+            None => hitable
+        }
+    }
+
+    pub(crate) fn get_hitables(&self) -> Vec<Arc<dyn geometry::Hitable>> {
         self.hitables.clone()
     }
 
-    pub fn get_camera(&self) -> Arc<camera::Camera> {
+    pub(crate) fn get_camera(&self) -> Arc<camera::Camera> {
         self.camera.clone()
     }
-}
\ No newline at end of file
+
+    pub(crate) fn get_materials(&self) -> material::MaterialsFactory {
+        self.materials.clone()
+    }
+
+    pub(crate) fn get_lights(&self) -> Vec<Arc<light::Light>> {
+        self.lights.clone()
+    }
+
+    pub fn get_settings(&self) -> Settings {
+        self.settings.clone()
+    }
+
+    pub fn set_settings(&mut self, settings: Settings) {
+        self.settings = settings;
+    }
+}
+
+//The world file without the settings that don't change the image, written out the same
+//way every time, for telling whether a checkpoint belongs to a render. Heightfield images
+//and density grids it reads are added by size and modification time, so editing one of
+//them counts as a different world too.
+pub fn image_description(filename: &str) -> Result<String> {
+    let file = File::open(filename).map_err(Error::io)?;
+    let mut json: Value = serde_json::from_reader(BufReader::new(file))?;
+    if let Some(settings) = json.get_mut("settings").and_then(|s| s.as_object_mut()) {
+        settings.remove("threads");
+        settings.remove("time_limit");
+        settings.remove("checkpoint");
+        settings.remove("progress");
+        settings.remove("stats");
+        if let Some(progressive) = settings.get_mut("progressive").and_then(|p| p.as_object_mut()) {
+            progressive.remove("interval");
+        }
+    }
+    let mut description = json.to_string();
+    let mut files = vec![];
+    referenced_files(&json, &mut files);
+    for path in files {
+        let stamp = std::fs::metadata(&path).ok().map(|m| {
+            let modified = m.modified().ok().and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok()).unwrap_or_default();
+            format!("{} {}", m.len(), modified.as_nanos())
+        });
+        description.push_str(&format!("\n{} {}", path, stamp.unwrap_or_else(|| "missing".to_string())));
+    }
+    Ok(description)
+}
+
+//Paths of the heightfield images and density grids anywhere in the world, in order.
+fn referenced_files(json: &Value, files: &mut Vec<String>) {
+    match json {
+        Value::Object(map) => {
+            for (key, value) in map {
+                match (key.as_str(), value) {
+                    ("image", Value::String(path)) | ("file", Value::String(path)) => files.push(path.clone()),
+                    _ => referenced_files(value, files)
+                }
+            }
+        },
+        Value::Array(values) => values.iter().for_each(|v| referenced_files(v, files)),
+        _ => {}
+    }
+}
+
+impl Plane {
+    fn build(&self) -> Arc<dyn geometry::Hitable> {
+        Arc::new(geometry::Plane::new(vec3(&self.origin), vec3(&self.normal), vec3(&self.color), self.mat.clone()))
+    }
+}
+
+impl Sphere {
+    fn build(&self) -> Arc<dyn geometry::Hitable> {
+        match &self.center1 {
+            Some(c1) => Arc::new(geometry::Sphere::new_moving(
+                vec3(&self.color),
+                self.radius,
+                vec3(&self.center),
+                vec3(c1),
+                self.mat.clone()
+            )),
+            None => Arc::new(geometry::Sphere::new(vec3(&self.color), self.radius, vec3(&self.center), self.mat.clone()))
+        }
+    }
+}
+
+impl AaBox {
+    fn build(&self) -> Arc<dyn geometry::Hitable> {
+        Arc::new(geometry::AaBox::new(vec3(&self.min), vec3(&self.max), vec3(&self.color), self.mat.clone()))
+    }
+}
+
+impl Cylinder {
+    fn build(&self) -> Arc<dyn geometry::Hitable> {
+        Arc::new(geometry::Cylinder::new(
+            vec3(&self.base),
+            vec3(&self.axis),
+            self.radius,
+            vec3(&self.color),
+            self.mat.clone()
+        ))
+    }
+}
+
+impl Cone {
+    fn build(&self) -> Arc<dyn geometry::Hitable> {
+        Arc::new(geometry::Cone::new(
+            vec3(&self.base),
+            vec3(&self.axis),
+            self.radius,
+            self.top_radius,
+            vec3(&self.color),
+            self.mat.clone()
+        ))
+    }
+}
+
+impl Disk {
+    fn build(&self) -> Arc<dyn geometry::Hitable> {
+        Arc::new(geometry::Disk::new(
+            vec3(&self.center),
+            vec3(&self.normal),
+            self.radius,
+            vec3(&self.color),
+            self.mat.clone()
+        ))
+    }
+}
+
+impl Rectangle {
+    fn build(&self) -> Arc<dyn geometry::Hitable> {
+        Arc::new(geometry::Rectangle::new(
+            vec3(&self.corner),
+            vec3(&self.edge_u),
+            vec3(&self.edge_v),
+            vec3(&self.color),
+            self.mat.clone()
+        ))
+    }
+}
+
+impl Torus {
+    fn build(&self) -> Arc<dyn geometry::Hitable> {
+        Arc::new(geometry::Torus::new(
+            vec3(&self.center),
+            vec3(&self.axis),
+            self.major_radius,
+            self.minor_radius,
+            vec3(&self.color),
+            self.mat.clone()
+        ))
+    }
+}
+
+impl Subsurface {
+    //Scattering only, absorption comes from the albedo at each event.
+    fn medium(&self) -> Medium {
+        Medium {
+            absorption: 0.0,
+            scattering: 1.0/self.mean_free_path.max(1e-6),
+            color: self.albedo,
+            g: self.g,
+            density: None
+        }
+    }
+}
+
+impl Medium {
+    fn build(&self, boundary: Option<Arc<dyn geometry::Hitable>>, materials: &mut material::MaterialsFactory) -> Option<Arc<dyn geometry::Hitable>> {
+        let density: Arc<dyn medium::Density> = match &self.density {
+            None => Arc::new(medium::ConstantDensity {}),
+            Some(DensityField::Noise { scale, octaves }) => Arc::new(medium::NoiseDensity::new(*scale, *octaves)),
+            Some(DensityField::Grid { file, min, max }) => match medium::GridDensity::load(file, vec3(min), vec3(max)) {
+                Ok(grid) => Arc::new(grid),
+                Err(e) => {
+                    println!("Error loading density grid '{}': {}. Skipping medium", file, e);
+                    return None;
+                }
+            }
+        };
+
+        //A medium fills whatever a ray is inside of, so shapes without an inside can't hold one.
+        if boundary.as_ref().is_some_and(|b| !b.is_closed()) {
+            println!("Error, media need a closed shape like a sphere, box, cylinder, cone, torus or CSG to fill. Skipping medium");
+            return None;
+        }
+
+        let phase = format!("phase_{}", self.g);
+        materials.insert(phase.clone(), Arc::new(material::HenyeyGreenstein::new(self.g)));
+        Some(Arc::new(medium::Volume::new(boundary, density, self.absorption, self.scattering, vec3(&self.color), phase)))
+    }
+}
+
+impl Heightfield {
+    fn build(&self) -> Option<Arc<dyn geometry::Hitable>> {
+        match heightfield::Heightfield::from_image(&self.image, vec3(&self.origin), vec3(&self.size), vec3(&self.color), self.mat.clone()) {
+            Ok(field) => Some(Arc::new(field)),
+            Err(e) => {
+                println!("Error loading heightfield '{}': {}. Skipping it", self.image, e);
+                None
+            }
+        }
+    }
+}
+
+impl SdfShape {
+    fn build(&self) -> Option<Arc<dyn geometry::Hitable>> {
+        match sdf::Sdf::parse(&self.expr) {
+            Ok(field) => {
+                let center = self.center.as_ref().map(vec3).unwrap_or_else(|| Vector3::new(0.0, 0.0, 0.0));
+                Some(Arc::new(sdf::SdfObject::new(field, center, self.bound, vec3(&self.color), self.mat.clone())))
+            },
+            Err(e) => {
+                println!("Error parsing sdf '{}': {}. Skipping it", self.expr, e);
+                None
+            }
+        }
+    }
+}
+
+impl Shape {
+    //Children are built with their own transforms, their names are ignored.
+    fn build(&self) -> Arc<dyn geometry::Hitable> {
+        let (hitable, placement) = match self {
+            Shape::Plane(o) => (o.build(), &o.placement),
+            Shape::Sphere(o) => (o.build(), &o.placement),
+            Shape::Box(o) => (o.build(), &o.placement),
+            Shape::Cylinder(o) => (o.build(), &o.placement),
+            Shape::Cone(o) => (o.build(), &o.placement),
+            Shape::Torus(o) => (o.build(), &o.placement),
+            Shape::Csg(o) => (o.build(), &o.placement)
+        };
+        let hitable = World::apply_transform(hitable, &placement.transform);
+        World::apply_motion(hitable, &placement.motion)
+    }
+}
+
+impl Csg {
+    fn build(&self) -> Arc<dyn geometry::Hitable> {
+        let op = match self.op {
+            CsgOp::Union => csg::CsgOp::Union,
+            CsgOp::Intersection => csg::CsgOp::Intersection,
+            CsgOp::Difference => csg::CsgOp::Difference
+        };
+        Arc::new(csg::Csg::new(op, self.left.build(), self.right.build()))
+    }
+}
+
+fn vec3(v: &[f64; 3]) -> Vector3<f64> {
+    Vector3::from(*v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn media_fill_csg_shapes_away_from_their_first_child() {
+        //The lens is nowhere near the left sphere's center.
+        let lens = |op: &str| format!(r#"{{
+            "camera": {{ "lookfrom": [0.0, 0.0, 8.0], "lookat": [0.0, 0.0, 0.0], "fov": 40.0 }},
+            "csg": [{{
+                "op": "{}",
+                "left": {{ "sphere": {{ "color": [1.0, 1.0, 1.0], "radius": 2.0, "center": [0.0, 0.0, -1.6], "mat": "glass" }} }},
+                "right": {{ "sphere": {{ "color": [1.0, 1.0, 1.0], "radius": 2.0, "center": [0.0, 0.0, 1.6], "mat": "glass" }} }},
+                "medium": {{ "scattering": 1.0 }}
+            }}]
+        }}"#, op);
+        assert_eq!(World::from_json(&lens("intersection"), 8, 8).unwrap().get_hitables().len(), 2);
+        assert_eq!(World::from_json(&lens("difference"), 8, 8).unwrap().get_hitables().len(), 2);
+    }
+
+    #[test]
+    fn media_need_a_closed_shape() {
+        let world = World::from_json(r#"{
+            "camera": { "lookfrom": [0.0, 0.0, 8.0], "lookat": [0.0, 0.0, 0.0], "fov": 40.0 },
+            "disks": [{ "center": [0.0, 0.0, 0.0], "normal": [0.0, 0.0, 1.0], "radius": 1.0, "color": [1.0, 1.0, 1.0], "mat": "flat", "medium": { "scattering": 1.0 } }]
+        }"#, 8, 8).unwrap();
+        assert_eq!(world.get_hitables().len(), 1);
+    }
+
+    #[test]
+    fn descriptions_change_with_the_files_a_world_reads() {
+        let dir = std::env::temp_dir();
+        let (world, grid) = (dir.join(format!("rustytracer_world_{}.json", std::process::id())), dir.join(format!("rustytracer_grid_{}.txt", std::process::id())));
+        std::fs::write(&world, format!(r#"{{
+            "camera": {{ "lookfrom": [0.0, 0.0, 8.0], "lookat": [0.0, 0.0, 0.0], "fov": 40.0 }},
+            "spheres": [{{ "center": [0.0, 0.0, 0.0], "radius": 1.0, "color": [1.0, 1.0, 1.0], "mat": "none",
+                "medium": {{ "density": {{ "grid": {{ "file": "{}", "min": [-1.0, -1.0, -1.0], "max": [1.0, 1.0, 1.0] }} }} }} }}]
+        }}"#, grid.display())).unwrap();
+        std::fs::write(&grid, "1 1 1 0.5").unwrap();
+        let before = image_description(world.to_str().unwrap()).unwrap();
+        assert!(before.contains(grid.to_str().unwrap()));
+        std::fs::write(&grid, "1 1 1 0.75").unwrap();
+        let after = image_description(world.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&world).unwrap();
+        std::fs::remove_file(&grid).unwrap();
+        assert!(before != after);
+    }
+
+    #[test]
+    fn short_vectors_are_refused() {
+        let json = r#"{
+            "camera": { "lookfrom": [0.0, 0.0, 8.0], "lookat": [0.0, 0.0, 0.0], "fov": 40.0 },
+            "spheres": [{ "center": [0.0, 0.0], "radius": 1.0, "color": [1.0, 1.0, 1.0], "mat": "flat" }]
+        }"#;
+        assert!(World::from_json(json, 8, 8).is_err());
+        let json = json.replace("[0.0, 0.0]", "[0.0, 0.0, 0.0]").replace(r#""fov": 40.0"#, r#""fov": 40.0, "shutter": [0.0]"#);
+        assert!(World::from_json(&json, 8, 8).is_err());
+    }
+
+    #[test]
+    fn the_example_worlds_load() {
+        for entry in std::fs::read_dir("worlds").unwrap() {
+            let path = entry.unwrap().path();
+            let json = std::fs::read_to_string(&path).unwrap();
+            assert!(World::from_json(&json, 8, 8).is_ok(), "{}", path.display());
+        }
+    }
+}